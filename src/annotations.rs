@@ -0,0 +1,750 @@
+use crate::error::{self, Error};
+use cgmath::{InnerSpace, Matrix, Matrix3, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::projection::{CroppedArea, CubeFace, Equirectangular, Face, Projection};
+use crate::selection::Selection;
+use crate::{view_name, CubeView, EquLayout, DOWN, UP};
+
+/// Bounding box detected on a face or tile image, in pixels of that image
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Detection {
+    /// Face or tile file name as written by `split` (e.g. `front_p4_3.jpg`)
+    pub file: String,
+    /// `[x_min, y_min, x_max, y_max]`
+    pub bbox: [f32; 4],
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+}
+
+/// Detection mapped onto the equirectangular panorama.
+///
+/// Shapes crossing the 0/360 degree seam are unwrapped: x coordinates continue past the image
+/// width and `wraps` is set, take x modulo width to get pixel columns.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct ProjectedDetection {
+    pub file: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    pub polygon: Vec<[f32; 2]>,
+    /// Axis aligned `[x_min, y_min, x_max, y_max]`, `x_max` may exceed the width when `wraps`
+    pub bbox: [f32; 4],
+    pub wraps: bool,
+    /// Files of all detections merged into this one (by `--merge`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<String>,
+}
+
+/// Annotation on the equirectangular panorama in image pixels, either a box or a polygon.
+/// Shapes may continue past the image width to cross the seam (as `project-annotations` writes).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct PanoramaAnnotation {
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<[f32; 4]>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub polygon: Vec<[f32; 2]>,
+}
+
+/// Fragment of a panorama annotation visible on a face or tile, in pixels of that image
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct ViewAnnotation {
+    pub file: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    pub polygon: Vec<[f32; 2]>,
+    /// `[x_min, y_min, x_max, y_max]` of the polygon
+    pub bbox: [f32; 4],
+    /// Fraction of the whole annotation (by solid angle) visible in this fragment
+    pub visible: f32,
+}
+
+/// Face or tile image within the cubemap
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct View {
+    pub face: Face,
+    /// Upper left corner on the face
+    pub offset: Vector2<u32>,
+    /// Width and height of the image
+    pub size: u32,
+}
+
+/// How `split` laid out faces and tiles
+#[derive(Clone, Copy, Debug)]
+pub struct CubeLayout {
+    pub face_size: u32,
+    pub patch_size: u32,
+}
+
+impl CubeLayout {
+    /// Recognizes `front.jpg` or `front_p4_3.jpg` style names, tiles must be cut the way this
+    /// layout cuts faces
+    pub fn locate(&self, file: &str) -> error::Result<View> {
+        let stem = Path::new(file).file_stem().unwrap_or_default();
+        let (face, tile) = CubeView::parse_name(&stem.to_string_lossy())
+            .ok_or_else(|| Error::UnknownView(file.to_string()))?;
+        let (pieces, i) = match tile {
+            Some(tile) => tile,
+            None => {
+                return Ok(View {
+                    face,
+                    offset: Vector2::new(0, 0),
+                    size: self.face_size,
+                })
+            }
+        };
+        let expected = self.face_size.checked_div(self.patch_size).unwrap_or(0);
+        if pieces != expected {
+            return Err(Error::Layout(format!(
+                "{} is cut into {} tiles per row, expected {} ({}px tiles of {}px faces)",
+                file, pieces, expected, self.patch_size, self.face_size
+            )));
+        }
+        if i >= pieces * pieces {
+            return Err(Error::Layout(format!(
+                "{} has tile index beyond {}",
+                file,
+                pieces * pieces - 1
+            )));
+        }
+        // patches go column by column
+        Ok(View {
+            face,
+            offset: Vector2::new(i / pieces, i % pieces) * self.patch_size,
+            size: self.patch_size,
+        })
+    }
+
+    /// Dimensions of a face or tile image
+    pub fn image_size(&self, file: &str) -> Option<Vector2<u32>> {
+        let size = self.locate(file).ok()?.size;
+        Some(Vector2::new(size, size))
+    }
+}
+
+/// Points along the box edges (clockwise from upper left), `edge_points` per edge
+pub(crate) fn box_outline(bbox: &[f32; 4], edge_points: u32) -> Vec<Vector2<f32>> {
+    let [x0, y0, x1, y1] = *bbox;
+    let corners = [
+        Vector2::new(x0, y0),
+        Vector2::new(x1, y0),
+        Vector2::new(x1, y1),
+        Vector2::new(x0, y1),
+    ];
+    let n = edge_points.max(1);
+    (0..4)
+        .flat_map(|edge| {
+            let (a, b) = (corners[edge], corners[(edge + 1) % 4]);
+            (0..n).map(move |i| a + (b - a) * (i as f32 / n as f32))
+        })
+        .collect()
+}
+
+fn outline_ranges(
+    outline: &[Vector2<f32>],
+) -> (std::ops::RangeInclusive<f32>, std::ops::RangeInclusive<f32>) {
+    let min_max = |values: Vec<f32>| {
+        values.iter().cloned().fold(f32::INFINITY, f32::min)
+            ..=values.iter().cloned().fold(f32::NEG_INFINITY, f32::max)
+    };
+    (
+        min_max(outline.iter().map(|p| p.x).collect()),
+        min_max(outline.iter().map(|p| p.y).collect()),
+    )
+}
+
+/// Maps outline on a face to the equirectangular image laid out as `equ`, handling the seam
+/// and the poles
+pub(crate) fn face_outline_to_equ(
+    face: Face,
+    face_size: u32,
+    outline: &[Vector2<f32>],
+    equ: &EquLayout,
+) -> Option<(Vec<[f32; 2]>, [f32; 4], bool)> {
+    let cube_face = CubeFace {
+        face,
+        size: face_size,
+    };
+    let rays = outline
+        .iter()
+        .map(|pixel| cube_face.pixel_to_ray(pixel))
+        .collect::<Option<Vec<_>>>()?;
+    let (x_range, y_range) = outline_ranges(outline);
+    let pole = [UP, DOWN].into_iter().find(|pole| {
+        cube_face
+            .ray_to_pixel(&world_ray(equ, pole))
+            .is_some_and(|p| x_range.contains(&p.x) && y_range.contains(&p.y))
+    });
+    rays_to_equ(&rays, pole, equ)
+}
+
+/// World ray pointing where image `ray` of the panorama points
+pub(crate) fn world_ray(equ: &EquLayout, ray: &Vector3<f32>) -> Vector3<f32> {
+    equ.orientation.map_or(*ray, |o| o.transpose() * ray)
+}
+
+/// Maps outline given by world rays to the equirectangular image laid out as `equ`, `pole` is
+/// the image pole (`UP` or `DOWN`) enclosed by the outline. Pixels are those of the (possibly
+/// partial) image, outlines may reach beyond its edges.
+pub(crate) fn rays_to_equ(
+    rays: &[Vector3<f32>],
+    pole: Option<Vector3<f32>>,
+    equ: &EquLayout,
+) -> Option<(Vec<[f32; 2]>, [f32; 4], bool)> {
+    let area = &equ.area;
+    let full = Equirectangular {
+        area: CroppedArea::full(Vector2::new(area.full_width, area.full_height)),
+    };
+    let origin = Vector2::new(area.left as f32, area.top as f32);
+    let width = area.full_width as f32;
+    let height = area.full_height as f32;
+    let mut points: Vec<Vector2<f32>> = vec![];
+    for ray in rays {
+        let ray = equ.orientation.map_or(*ray, |o| o * ray);
+        let mut p = full.ray_to_pixel(&ray)? - origin;
+        if let Some(previous) = points.last() {
+            p.x += ((previous.x - p.x) / width).round() * width;
+        }
+        points.push(p);
+    }
+    let first = *points.first()?;
+    let last = *points.last()?;
+    let ys = points.iter().map(|p| p.y);
+    let (y_min, y_max) = (
+        ys.clone().fold(f32::INFINITY, f32::min),
+        ys.fold(f32::NEG_INFINITY, f32::max),
+    );
+    if let Some(pole) = pole {
+        // box around a pole covers every longitude, close the polygon along the pole
+        let pole_y = if pole.z > 0.0 { 0.0 } else { height } - origin.y;
+        let mut polygon = points.iter().map(|p| [p.x, p.y]).collect::<Vec<_>>();
+        polygon.push([last.x, pole_y]);
+        polygon.push([first.x, pole_y]);
+        return Some((
+            polygon,
+            [0.0, y_min.min(pole_y), area.width as f32, y_max.max(pole_y)],
+            false,
+        ));
+    }
+    let x_min = points.iter().map(|p| p.x).fold(f32::INFINITY, f32::min);
+    let shift = (x_min / width).floor() * width;
+    let polygon = points
+        .iter()
+        .map(|p| [p.x - shift, p.y])
+        .collect::<Vec<_>>();
+    let x_max = polygon
+        .iter()
+        .map(|p| p[0])
+        .fold(f32::NEG_INFINITY, f32::max);
+    Some((polygon, [x_min - shift, y_min, x_max, y_max], x_max > width))
+}
+
+/// Solid angle of a spherical polygon given by its vertex rays (fan of
+/// Van Oosterom-Strackee triangles, polygon must fit in a hemisphere)
+pub(crate) fn solid_angle(rays: &[Vector3<f32>]) -> f32 {
+    if rays.len() < 3 {
+        return 0.0;
+    }
+    let a = rays[0].normalize();
+    rays[1..]
+        .windows(2)
+        .map(|w| {
+            let (b, c) = (w[0].normalize(), w[1].normalize());
+            let numerator = a.dot(b.cross(c));
+            let denominator = 1.0 + a.dot(b) + b.dot(c) + c.dot(a);
+            2.0 * numerator.atan2(denominator)
+        })
+        .sum::<f32>()
+        .abs()
+}
+
+/// Sutherland-Hodgman clipping of a closed polygon against half-space `inside(p) >= 0`,
+/// `inside` must be linear so that the crossing can be interpolated
+pub(crate) fn clip_polygon<V>(polygon: &[V], inside: impl Fn(&V) -> f32) -> Vec<V>
+where
+    V: Copy
+        + std::ops::Add<Output = V>
+        + std::ops::Sub<Output = V>
+        + std::ops::Mul<f32, Output = V>,
+{
+    let mut clipped = vec![];
+    for (i, current) in polygon.iter().enumerate() {
+        let next = &polygon[(i + 1) % polygon.len()];
+        let (d_current, d_next) = (inside(current), inside(next));
+        if d_current >= 0.0 {
+            clipped.push(*current);
+        }
+        if (d_current >= 0.0) != (d_next >= 0.0) {
+            let t = d_current / (d_current - d_next);
+            clipped.push(*current + (*next - *current) * t);
+        }
+    }
+    clipped
+}
+
+fn polygon_bbox(polygon: &[[f32; 2]]) -> [f32; 4] {
+    polygon.iter().fold(
+        [
+            f32::INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        ],
+        |[x0, y0, x1, y1], [x, y]| [x0.min(*x), y0.min(*y), x1.max(*x), y1.max(*y)],
+    )
+}
+
+/// Maps panorama annotations onto the faces and tiles written by `split`
+pub struct AnnotationSplitter {
+    /// Projection of the source panorama
+    pub equ: Equirectangular,
+    /// Rotation from world rays to image rays, as used when rendering the faces
+    pub orientation: Option<Matrix3<f32>>,
+    pub face_size: u32,
+    /// Emit annotations for whole faces
+    pub faces: bool,
+    /// Emit annotations for tiles of this size
+    pub patch_size: Option<u32>,
+    /// Faces and tiles `split` rendered, others get no annotations
+    pub selection: Selection,
+    /// Extension of the face and tile files fragments refer to
+    pub extension: String,
+    pub min_visible: f32,
+}
+
+impl AnnotationSplitter {
+    /// Outline of the annotation as world rays, edges sampled about once per degree
+    fn rays(&self, annotation: &PanoramaAnnotation) -> Vec<Vector3<f32>> {
+        let vertices = match annotation.bbox {
+            Some(bbox) if annotation.polygon.is_empty() => {
+                box_outline(&bbox, 1).iter().map(|p| [p.x, p.y]).collect()
+            }
+            _ => annotation.polygon.clone(),
+        };
+        let step = self.equ.area.full_width as f32 / 360.0;
+        let world = self.orientation.map(|o| o.transpose());
+        (0..vertices.len())
+            .flat_map(|i| {
+                let a = Vector2::from(vertices[i]);
+                let b = Vector2::from(vertices[(i + 1) % vertices.len()]);
+                let n = ((b - a).magnitude() / step).ceil().max(1.0) as u32;
+                (0..n).map(move |j| a + (b - a) * (j as f32 / n as f32))
+            })
+            .filter_map(|p| self.equ.pixel_to_ray(&p))
+            .map(|ray| world.map_or(ray, |w| w * ray))
+            .collect()
+    }
+
+    fn face_pixel(&self, face: Face, ray: &Vector3<f32>) -> Vector2<f32> {
+        let basis = face.basis();
+        let half = self.face_size as f32 / 2.0;
+        let z = ray.dot(basis.center);
+        Vector2::new(
+            half + half * ray.dot(basis.right) / z,
+            half + half * ray.dot(basis.down) / z,
+        )
+    }
+
+    /// Fragments of one annotation for every face and tile it is visible in
+    fn split_one(&self, annotation: &PanoramaAnnotation) -> Vec<ViewAnnotation> {
+        let rays = self.rays(annotation);
+        let total = solid_angle(&rays);
+        if total == 0.0 {
+            return vec![];
+        }
+        let mut fragments = vec![];
+        let mut fragment = |file: String, polygon: Vec<Vector2<f32>>, visible: f32| {
+            if polygon.len() < 3 || visible < self.min_visible {
+                return;
+            }
+            let mut polygon = polygon.iter().map(|p| [p.x, p.y]).collect::<Vec<_>>();
+            // clipping leaves repeated vertices where the outline runs along an edge
+            polygon.dedup_by(|a, b| (a[0] - b[0]).abs() < 1e-3 && (a[1] - b[1]).abs() < 1e-3);
+            fragments.push(ViewAnnotation {
+                file,
+                label: annotation.label.clone(),
+                score: annotation.score,
+                bbox: polygon_bbox(&polygon),
+                polygon,
+                visible,
+            });
+        };
+        for face in Face::ALL {
+            let basis = face.basis();
+            // 90 degree frustum of the face
+            let mut on_face = rays.clone();
+            for normal in [
+                basis.center - basis.right,
+                basis.center + basis.right,
+                basis.center - basis.down,
+                basis.center + basis.down,
+            ] {
+                on_face = clip_polygon(&on_face, |ray| ray.dot(normal));
+            }
+            if on_face.len() < 3 {
+                continue;
+            }
+            let face_polygon = on_face
+                .iter()
+                .map(|ray| self.face_pixel(face, ray))
+                .collect::<Vec<_>>();
+            if self.faces && self.selection.view(face, None) {
+                fragment(
+                    format!("{}.{}", view_name(face, None), self.extension),
+                    face_polygon.clone(),
+                    solid_angle(&on_face) / total,
+                );
+            }
+            let patch_size = match self.patch_size {
+                Some(patch_size) => patch_size,
+                None => continue,
+            };
+            let cube_face = CubeFace {
+                face,
+                size: self.face_size,
+            };
+            let pieces = self.face_size / patch_size;
+            for px in 0..pieces {
+                for py in 0..pieces {
+                    let tile = (pieces, px * pieces + py);
+                    if !self.selection.view(face, Some(tile)) {
+                        continue;
+                    }
+                    let origin = Vector2::new((px * patch_size) as f32, (py * patch_size) as f32);
+                    let end = origin + Vector2::new(patch_size as f32, patch_size as f32);
+                    let mut on_tile = face_polygon.clone();
+                    on_tile = clip_polygon(&on_tile, |p| p.x - origin.x);
+                    on_tile = clip_polygon(&on_tile, |p| end.x - p.x);
+                    on_tile = clip_polygon(&on_tile, |p| p.y - origin.y);
+                    on_tile = clip_polygon(&on_tile, |p| end.y - p.y);
+                    if on_tile.len() < 3 {
+                        continue;
+                    }
+                    let tile_rays = on_tile
+                        .iter()
+                        .filter_map(|p| cube_face.pixel_to_ray(p))
+                        .collect::<Vec<_>>();
+                    fragment(
+                        format!("{}.{}", view_name(face, Some(tile)), self.extension),
+                        on_tile.iter().map(|p| p - origin).collect(),
+                        solid_angle(&tile_rays) / total,
+                    );
+                }
+            }
+        }
+        fragments
+    }
+
+    pub fn split(&self, annotations: &[PanoramaAnnotation]) -> Vec<ViewAnnotation> {
+        annotations
+            .iter()
+            .flat_map(|annotation| self.split_one(annotation))
+            .collect()
+    }
+}
+
+/// Maps detections on faces or tiles to the equirectangular panorama laid on the sphere as
+/// `equ` says (as `split` rendered the faces)
+pub fn project_detections(
+    detections: &[Detection],
+    layout: &CubeLayout,
+    equ: &EquLayout,
+    edge_points: u32,
+) -> error::Result<Vec<ProjectedDetection>> {
+    detections
+        .iter()
+        .map(|detection| {
+            let view = layout.locate(&detection.file)?;
+            let offset = Vector2::new(view.offset.x as f32, view.offset.y as f32);
+            let outline = box_outline(&detection.bbox, edge_points)
+                .iter()
+                .map(|p| p + offset)
+                .collect::<Vec<_>>();
+            let (polygon, bbox, wraps) =
+                face_outline_to_equ(view.face, layout.face_size, &outline, equ).ok_or(
+                    Error::Layout(format!(
+                        "Detection in {} is outside of the face",
+                        detection.file
+                    )),
+                )?;
+            Ok(ProjectedDetection {
+                file: detection.file.clone(),
+                label: detection.label.clone(),
+                score: detection.score,
+                polygon,
+                bbox,
+                wraps,
+                merged: vec![],
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::TileRegion;
+
+    fn equ() -> EquLayout {
+        EquLayout {
+            area: CroppedArea::full(Vector2::new(400, 200)),
+            orientation: None,
+        }
+    }
+
+    fn layout() -> CubeLayout {
+        CubeLayout {
+            face_size: 100,
+            patch_size: 50,
+        }
+    }
+
+    #[test]
+    fn tile_names_are_located_column_by_column() {
+        assert_eq!(
+            layout().locate("tiles/left_p2_3.jpg").unwrap(),
+            View {
+                face: Face::Left,
+                offset: Vector2::new(50, 50),
+                size: 50,
+            }
+        );
+        assert_eq!(
+            layout().locate("front_p2_1.png").unwrap().offset,
+            Vector2::new(0, 50)
+        );
+        assert_eq!(layout().locate("up.jpg").unwrap().size, 100);
+        assert!(matches!(
+            layout().locate("sideways_p2_1.jpg"),
+            Err(Error::UnknownView(_))
+        ));
+    }
+
+    #[test]
+    fn tiles_cut_differently_are_rejected() {
+        for file in ["front_p3_1.jpg", "front_p2_4.jpg"] {
+            assert!(matches!(layout().locate(file), Err(Error::Layout(_))));
+        }
+        let larger_patches = CubeLayout {
+            face_size: 100,
+            patch_size: 480,
+        };
+        assert!(larger_patches.locate("front_p1_0.jpg").is_err());
+        assert_eq!(
+            larger_patches.image_size("front.jpg"),
+            Some(Vector2::new(100, 100))
+        );
+    }
+
+    #[test]
+    fn detections_follow_pose_and_cropped_area() {
+        // heading 90 and a panorama covering the upper half of the sphere only
+        let equ = EquLayout {
+            area: CroppedArea {
+                full_width: 400,
+                full_height: 200,
+                left: 0,
+                top: 0,
+                width: 400,
+                height: 100,
+            },
+            orientation: crate::xmp::GPano {
+                pose_heading: Some(90.0),
+                ..Default::default()
+            }
+            .orientation(),
+        };
+        let detection = Detection {
+            file: "front.jpg".to_string(),
+            bbox: [45.0, 30.0, 55.0, 40.0],
+            label: String::new(),
+            score: None,
+        };
+        let projected = project_detections(&[detection], &layout(), &equ, 4).unwrap();
+        let [x0, y0, x1, y1] = projected[0].bbox;
+        // split samples the same pixel for the ray through the box center
+        let ray = CubeFace {
+            face: Face::Front,
+            size: 100,
+        }
+        .pixel_to_ray(&Vector2::new(50.0, 35.0))
+        .unwrap();
+        let pixel = crate::equ_source_pixel(&equ, &ray).unwrap();
+        let (x, y) = (pixel.x as f32, pixel.y as f32);
+        assert!(((x0 + x1) / 2.0 - x).abs() < 1.5);
+        assert!(y0 < y && y1 > y);
+        assert!((x0 - 100.0).abs() < 10.0, "{:?}", projected[0].bbox);
+    }
+
+    #[test]
+    fn front_box_maps_to_panorama_center() {
+        let detection = Detection {
+            file: "front.jpg".to_string(),
+            bbox: [40.0, 40.0, 60.0, 60.0],
+            label: "car".to_string(),
+            score: Some(0.5),
+        };
+        let projected = project_detections(&[detection], &layout(), &equ(), 4).unwrap();
+        let [x0, y0, x1, y1] = projected[0].bbox;
+        assert!(x0 < 200.0 && x1 > 200.0 && y0 < 100.0 && y1 > 100.0);
+        assert!(!projected[0].wraps);
+        assert_eq!(projected[0].polygon.len(), 16);
+    }
+
+    #[test]
+    fn back_box_straddling_seam_is_unwrapped() {
+        let detection = Detection {
+            file: "back.jpg".to_string(),
+            bbox: [40.0, 40.0, 60.0, 60.0],
+            label: String::new(),
+            score: None,
+        };
+        let projected = project_detections(&[detection], &layout(), &equ(), 4).unwrap();
+        let [x0, _, x1, _] = projected[0].bbox;
+        assert!(projected[0].wraps);
+        assert!(x0 > 380.0 && x0 < 400.0 && x1 > 400.0 && x1 < 420.0);
+    }
+
+    fn splitter(patch_size: Option<u32>) -> AnnotationSplitter {
+        AnnotationSplitter {
+            equ: Equirectangular {
+                area: CroppedArea::full(Vector2::new(400, 200)),
+            },
+            orientation: None,
+            face_size: 100,
+            faces: true,
+            patch_size,
+            selection: Selection::all(),
+            extension: "jpg".to_string(),
+            min_visible: 0.1,
+        }
+    }
+
+    #[test]
+    fn annotation_in_front_lands_on_front_face_only() {
+        let annotation = PanoramaAnnotation {
+            label: "car".to_string(),
+            score: None,
+            bbox: Some([190.0, 90.0, 210.0, 110.0]),
+            polygon: vec![],
+        };
+        let fragments = splitter(None).split(&[annotation]);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].file, "front.jpg");
+        assert!((fragments[0].visible - 1.0).abs() < 1e-3);
+        let [x0, y0, x1, y1] = fragments[0].bbox;
+        assert!(x0 < 50.0 && x1 > 50.0 && y0 < 50.0 && y1 > 50.0);
+    }
+
+    #[test]
+    fn annotation_on_face_edge_is_clipped_to_both_faces_and_tiles() {
+        // front/right boundary is at 45 degrees right of center = x 250
+        let annotation = PanoramaAnnotation {
+            label: String::new(),
+            score: None,
+            bbox: None,
+            polygon: vec![[240.0, 90.0], [260.0, 90.0], [260.0, 110.0], [240.0, 110.0]],
+        };
+        let fragments = splitter(Some(50)).split(&[annotation]);
+        let files = fragments
+            .iter()
+            .map(|f| f.file.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                "front.jpg",
+                "front_p2_2.jpg",
+                "front_p2_3.jpg",
+                "right.jpg",
+                "right_p2_0.jpg",
+                "right_p2_1.jpg"
+            ]
+        );
+        let front = &fragments[0];
+        assert!((front.bbox[2] - 100.0).abs() < 1e-3);
+        assert!((front.visible - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn views_split_did_not_render_get_no_fragments() {
+        let annotation = PanoramaAnnotation {
+            label: String::new(),
+            score: None,
+            bbox: None,
+            polygon: vec![[240.0, 90.0], [260.0, 90.0], [260.0, 110.0], [240.0, 110.0]],
+        };
+        let mut splitter = splitter(Some(50));
+        splitter.selection = Selection {
+            faces: vec![Face::Right],
+            tiles: Some(TileRegion {
+                first: Vector2::new(0, 1),
+                last: Vector2::new(0, 1),
+            }),
+            patch_size: 50,
+            whole_faces: true,
+        };
+        let fragments = splitter.split(&[annotation]);
+        let files = fragments
+            .iter()
+            .map(|f| f.file.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["right.jpg", "right_p2_1.jpg"]);
+    }
+
+    #[test]
+    fn fragments_name_the_files_split_wrote() {
+        let annotation = PanoramaAnnotation {
+            label: String::new(),
+            score: None,
+            bbox: Some([190.0, 90.0, 210.0, 110.0]),
+            polygon: vec![],
+        };
+        let mut splitter = splitter(Some(50));
+        splitter.extension = "npy".to_string();
+        let fragments = splitter.split(&[annotation]);
+        assert_eq!(fragments[0].file, "front.npy");
+        assert!(fragments[1..].iter().all(|f| f.file.ends_with(".npy")));
+    }
+
+    #[test]
+    fn small_fragments_are_dropped() {
+        let annotation = PanoramaAnnotation {
+            label: String::new(),
+            score: None,
+            bbox: Some([200.0, 90.0, 252.0, 110.0]),
+            polygon: vec![],
+        };
+        let fragments = splitter(None).split(&[annotation]);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].file, "front.jpg");
+    }
+
+    #[test]
+    fn box_around_pole_covers_all_longitudes() {
+        let detection = Detection {
+            file: "up.jpg".to_string(),
+            bbox: [40.0, 40.0, 60.0, 60.0],
+            label: String::new(),
+            score: None,
+        };
+        let projected = project_detections(&[detection], &layout(), &equ(), 4).unwrap();
+        assert_eq!(projected[0].bbox[0], 0.0);
+        assert_eq!(projected[0].bbox[1], 0.0);
+        assert_eq!(projected[0].bbox[2], 400.0);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{Cursor, Read, Write};
+
+use crate::error::{self, Error};
+
+/// Container of the faces and tiles streamed through stdin and stdout
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// GNU tar, long names and large files are written with GNU extensions
+    Tar,
+    /// Stored without compression, faces are already compressed images
+    Zip,
+}
+
+/// Named file of an archive
+pub type Entry = (String, Vec<u8>);
+
+/// Writes `entries` as a tar or zip archive, entry by entry
+pub fn write_archive(
+    format: ArchiveFormat,
+    entries: &[Entry],
+    mut writer: impl Write,
+) -> error::Result<()> {
+    match format {
+        ArchiveFormat::Tar => tar(entries, &mut writer)?,
+        ArchiveFormat::Zip => zip(entries, &mut writer)?,
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Files of a tar or zip archive (told apart by their first bytes), directories and other
+/// special entries are skipped
+pub fn read_archive(bytes: &[u8]) -> error::Result<Vec<Entry>> {
+    match bytes.starts_with(b"PK") {
+        true => unzip(bytes),
+        false => untar(bytes),
+    }
+}
+
+fn tar(entries: &[Entry], writer: &mut impl Write) -> error::Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    for (name, data) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, name, data.as_slice())?;
+    }
+    builder.finish()?;
+    Ok(())
+}
+
+fn untar(bytes: &[u8]) -> error::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for entry in tar::Archive::new(bytes).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        entries.push((name, data));
+    }
+    Ok(entries)
+}
+
+fn zip(entries: &[Entry], writer: &mut impl Write) -> error::Result<()> {
+    let mut zip = zip::ZipWriter::new_stream(writer);
+    for (name, data) in entries {
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        zip.start_file(name.as_str(), options)
+            .map_err(Error::format)?;
+        zip.write_all(data)?;
+    }
+    zip.finish().map_err(Error::format)?;
+    Ok(())
+}
+
+fn unzip(bytes: &[u8]) -> error::Result<Vec<Entry>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(Error::format)?;
+    let mut entries = vec![];
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(Error::format)?;
+        if !file.is_file() {
+            continue;
+        }
+        let mut data = vec![];
+        // the crc is checked once the file is read to its end
+        file.read_to_end(&mut data)
+            .map_err(|e| Error::format(format!("{}: {}", file.name(), e)))?;
+        entries.push((file.name().to_string(), data));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            (
+                "front_p2_0.jpg".to_string(),
+                (0..700).map(|v| v as u8).collect(),
+            ),
+            ("manifest.json".to_string(), b"{}".to_vec()),
+            ("empty".to_string(), vec![]),
+            (format!("{}/up.jpg", "long".repeat(40)), b"up".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        for format in [ArchiveFormat::Tar, ArchiveFormat::Zip] {
+            let mut bytes = vec![];
+            write_archive(format, &entries(), &mut bytes).unwrap();
+            assert_eq!(read_archive(&bytes).unwrap(), entries());
+        }
+    }
+
+    #[test]
+    fn deflated_zip_entries_are_read() {
+        let data = b"front front front front front".to_vec();
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        writer.start_file("up.jpg", options).unwrap();
+        writer.write_all(&data).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(
+            read_archive(&bytes).unwrap(),
+            [("up.jpg".to_string(), data)]
+        );
+    }
+
+    #[test]
+    fn zip_holds_more_files_than_fit_without_zip64() {
+        let entries = (0..u16::MAX as usize + 1)
+            .map(|i| (format!("{}.jpg", i), vec![]))
+            .collect::<Vec<_>>();
+        let mut bytes = vec![];
+        write_archive(ArchiveFormat::Zip, &entries, &mut bytes).unwrap();
+        assert_eq!(read_archive(&bytes).unwrap().len(), entries.len());
+    }
+}
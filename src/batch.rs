@@ -0,0 +1,350 @@
+use serde::Serialize;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+
+use crate::{is_stdio, split, SplitArgs};
+
+/// Written into the folder of a panorama once all of its outputs are saved
+const COMPLETE_MARKER: &str = ".complete";
+
+/// Outcome of a batch, written with `--report`
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub split: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<Failure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    pub input: PathBuf,
+    pub error: String,
+}
+
+/// Matches one path component against `*` (any characters) and `?` (one character)
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            component_matches(&pattern[1..], name)
+                || (!name.is_empty() && component_matches(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => component_matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => component_matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Matches path components against pattern components, `**` stands for any number of
+/// directories
+fn glob_matches(pattern: &[String], path: &[String]) -> bool {
+    match pattern.first().map(String::as_str) {
+        None => path.is_empty(),
+        Some("**") => {
+            glob_matches(&pattern[1..], path)
+                || (!path.is_empty() && glob_matches(pattern, &path[1..]))
+        }
+        Some(component) => {
+            !path.is_empty()
+                && component_matches(
+                    &component.chars().collect::<Vec<_>>(),
+                    &path[0].chars().collect::<Vec<_>>(),
+                )
+                && glob_matches(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+fn is_glob(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Files under `directory`, recursively and sorted
+fn walk(directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries = fs::read_dir(directory)?
+        .map(|e| Ok(e?.path()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files.extend(walk(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn components(path: &Path) -> Vec<String> {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+/// Deepest directory holding all `paths`
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let mut ancestor = paths
+        .first()
+        .and_then(|p| p.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    for path in paths {
+        while !path.starts_with(&ancestor) {
+            match ancestor.parent() {
+                Some(parent) => ancestor = parent.to_path_buf(),
+                None => break,
+            }
+        }
+    }
+    ancestor
+}
+
+/// Panoramas named by `input` and the directory their relative paths are mirrored from.
+/// `input` is a directory (every image in it, recursively), a glob pattern or, with
+/// `file_list`, a text file listing one panorama per line (relative to the list, `#` starts
+/// a comment).
+pub fn inputs(input: &Path, file_list: bool) -> anyhow::Result<(PathBuf, Vec<PathBuf>)> {
+    if file_list {
+        let base = input.parent().unwrap_or(Path::new(""));
+        let files = fs::read_to_string(input)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| base.join(line))
+            .collect::<Vec<_>>();
+        return Ok((common_ancestor(&files), files));
+    }
+    if input.is_dir() {
+        let files = walk(input)?
+            .into_iter()
+            .filter(|p| {
+                image::ImageFormat::from_path(p).is_ok()
+                    || p.extension().is_some_and(|e| e == "npy")
+            })
+            .collect();
+        return Ok((input.to_path_buf(), files));
+    }
+    let pattern = components(input);
+    let literal = pattern.iter().take_while(|c| !is_glob(c)).count();
+    if literal == pattern.len() {
+        return Ok((
+            input.parent().unwrap_or(Path::new("")).to_path_buf(),
+            vec![input.to_path_buf()],
+        ));
+    }
+    let root = match literal {
+        0 => PathBuf::from("."),
+        _ => input
+            .components()
+            .take(literal)
+            .map(Component::as_os_str)
+            .collect(),
+    };
+    let files = walk(&root)?
+        .into_iter()
+        .filter(|p| {
+            let relative = p.strip_prefix(&root).unwrap_or(p);
+            glob_matches(&pattern[literal..], &components(relative))
+        })
+        .collect();
+    Ok((root, files))
+}
+
+/// Output folder of every input, `output_root` mirroring its path from `root` without the
+/// extension. Inputs differing only by extension would share a folder and are refused.
+fn folders(root: &Path, inputs: &[PathBuf], output_root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let folders = inputs
+        .iter()
+        .map(|input| {
+            let relative = input.strip_prefix(root).unwrap_or(input);
+            output_root.join(relative.with_extension(""))
+        })
+        .collect::<Vec<_>>();
+    for (i, folder) in folders.iter().enumerate() {
+        if let Some(j) = folders[..i].iter().position(|f| f == folder) {
+            return Err(anyhow::anyhow!(
+                "{} and {} would both be split into {}, rename one of them",
+                inputs[j].display(),
+                inputs[i].display(),
+                folder.display()
+            ));
+        }
+    }
+    Ok(folders)
+}
+
+/// Split arguments for one panorama, outputs go into its own `folder`
+fn panorama_args(args: &SplitArgs, input: &Path, folder: &Path) -> SplitArgs {
+    let mut args = args.clone();
+    args.input = input.to_path_buf();
+    args.cubemap_faces_output = args.cubemap_faces_output.map(|d| folder.join(d));
+    args.tiles_output = args.tiles_output.map(|d| folder.join(d));
+    args.npy_output = args.npy_output.map(|f| folder.join(f));
+    args
+}
+
+fn create_output_dirs(args: &SplitArgs) -> anyhow::Result<()> {
+    for directory in [&args.cubemap_faces_output, &args.tiles_output]
+        .into_iter()
+        .flatten()
+    {
+        fs::create_dir_all(directory)?;
+    }
+    if let Some(parent) = args.npy_output.as_ref().and_then(|f| f.parent()) {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// Splits every panorama named by `args.input` into a folder of `output_root` mirroring its
+/// directory, output paths in `args` are taken relative to that folder. Failures are
+/// collected and the rest of the batch goes on, `resume` skips panoramas completed before.
+pub fn split_all(
+    args: &SplitArgs,
+    file_list: bool,
+    output_root: &Path,
+    resume: bool,
+) -> anyhow::Result<Report> {
+    let outputs = [
+        &args.cubemap_faces_output,
+        &args.tiles_output,
+        &args.npy_output,
+    ];
+    if outputs
+        .iter()
+        .any(|o| o.as_ref().is_some_and(|p| p.is_absolute()))
+    {
+        return Err(anyhow::anyhow!(
+            "Batch outputs go inside of every panorama folder, give them as relative paths"
+        ));
+    }
+    if outputs.iter().any(|o| o.as_deref().is_some_and(is_stdio)) {
+        return Err(anyhow::anyhow!("Batch outputs can't be written to stdout"));
+    }
+    let (root, mut inputs) = inputs(&args.input, file_list)?;
+    // outputs of an earlier run are no panoramas
+    inputs.retain(|input| !input.starts_with(output_root));
+    eprintln!("[batch]: {} panoramas found", inputs.len());
+    let folders = folders(&root, &inputs, output_root)?;
+    let mut report = Report::default();
+    for (i, (input, folder)) in inputs.iter().zip(folders).enumerate() {
+        let marker = folder.join(COMPLETE_MARKER);
+        if resume && marker.exists() {
+            report.skipped.push(input.clone());
+            continue;
+        }
+        eprintln!("[batch]: {}/{} {}", i + 1, inputs.len(), input.display());
+        let panorama = panorama_args(args, input, &folder);
+        let result = (|| -> anyhow::Result<()> {
+            if marker.exists() {
+                fs::remove_file(&marker)?;
+            }
+            create_output_dirs(&panorama)?;
+            // a panic on one corrupt panorama must not end the batch
+            panic::catch_unwind(AssertUnwindSafe(|| split(&panorama)))
+                .map_err(|_| anyhow::anyhow!("Panicked while splitting"))??;
+            fs::write(&marker, b"")?;
+            Ok(())
+        })();
+        match result {
+            Ok(()) => report.split.push(input.clone()),
+            Err(e) => {
+                eprintln!("[batch]: Failed {}: {:#}", input.display(), e);
+                report.failed.push(Failure {
+                    input: input.clone(),
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> Vec<String> {
+        s.split('/').map(String::from).collect()
+    }
+
+    #[test]
+    fn globs_match_components_and_directories() {
+        assert!(glob_matches(&pattern("*.jpg"), &pattern("a.jpg")));
+        assert!(!glob_matches(&pattern("*.jpg"), &pattern("day1/a.jpg")));
+        assert!(glob_matches(&pattern("**/*.jpg"), &pattern("a.jpg")));
+        assert!(glob_matches(
+            &pattern("**/*.jpg"),
+            &pattern("day1/cam2/a.jpg")
+        ));
+        assert!(glob_matches(
+            &pattern("day?/**/pano_*.png"),
+            &pattern("day3/x/pano_01.png")
+        ));
+        assert!(!glob_matches(
+            &pattern("day?/**/pano_*.png"),
+            &pattern("day10/pano_01.png")
+        ));
+        assert!(!glob_matches(&pattern("*.jpg"), &pattern("a.jpeg")));
+    }
+
+    #[test]
+    fn failures_do_not_stop_the_batch_and_resume_skips_completed() {
+        use crate::{Cli, Commands};
+        use clap::Parser;
+        let directory =
+            std::env::temp_dir().join(format!("equistitch-batch-{}", std::process::id()));
+        let input = directory.join("input");
+        fs::create_dir_all(input.join("day1")).unwrap();
+        image::RgbaImage::new(64, 32)
+            .save(input.join("day1/a.png"))
+            .unwrap();
+        fs::write(input.join("broken.png"), b"not a png").unwrap();
+        let args = match Cli::parse_from([
+            "equistitch".to_string(),
+            "batch".to_string(),
+            format!("-i={}", input.display()),
+            "-t=tiles".to_string(),
+            "-p=8".to_string(),
+            "--output-root=unused".to_string(),
+        ])
+        .command
+        {
+            Some(Commands::Batch { split, .. }) => split,
+            _ => unreachable!(),
+        };
+        let output = directory.join("output");
+        let report = split_all(&args, false, &output, false).unwrap();
+        assert_eq!(report.split, vec![input.join("day1/a.png")]);
+        assert_eq!(report.failed[0].input, input.join("broken.png"));
+        assert!(output.join("day1/a/tiles/front_p2_3.jpg").exists());
+        let report = split_all(&args, false, &output, true).unwrap();
+        assert_eq!(report.skipped, vec![input.join("day1/a.png")]);
+        assert_eq!(report.failed.len(), 1);
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn inputs_sharing_a_folder_are_refused() {
+        let root = PathBuf::from("data");
+        let inputs = [root.join("day1/a.jpg"), root.join("day1/b.jpg")];
+        assert_eq!(
+            folders(&root, &inputs, Path::new("out")).unwrap(),
+            [PathBuf::from("out/day1/a"), PathBuf::from("out/day1/b")]
+        );
+        let inputs = [root.join("day1/a.jpg"), root.join("day1/a.png")];
+        assert!(folders(&root, &inputs, Path::new("out")).is_err());
+    }
+
+    #[test]
+    fn file_list_is_mirrored_from_common_ancestor() {
+        let files = [
+            PathBuf::from("data/day1/a.jpg"),
+            PathBuf::from("data/day2/cam/b.jpg"),
+        ];
+        assert_eq!(common_ancestor(&files), PathBuf::from("data"));
+        assert_eq!(common_ancestor(&files[1..]), PathBuf::from("data/day2/cam"));
+    }
+}
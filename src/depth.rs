@@ -0,0 +1,118 @@
+use crate::error;
+use cgmath::{InnerSpace, Matrix3, Vector3};
+use std::path::Path;
+
+use crate::projection::Equirectangular;
+use crate::selection::Selection;
+use crate::tensor::{equ_to_faces_nearest, faces_to_equ_nearest, load_cube, Tensor};
+
+/// Planar z-depth faces of a cubemap in `Face::ALL` order. Depth 0 means no measurement.
+#[non_exhaustive]
+pub struct DepthCube {
+    pub faces: Vec<Tensor>,
+}
+
+impl DepthCube {
+    /// Faces from `{face}.npy` or tiles, H×W or H×W×1 arrays
+    pub fn load(directory: &Path, extension: &str, tiles: bool) -> error::Result<DepthCube> {
+        Ok(DepthCube {
+            faces: load_cube(directory, extension, tiles, 1)?,
+        })
+    }
+
+    /// Faces of an equirectangular map of distances along the rays, `orientation` takes world
+    /// rays to image rays. Distances are sampled from the nearest pixel so depth edges are kept.
+    pub fn from_equ(
+        distance: &Tensor,
+        equ: &Equirectangular,
+        orientation: Option<Matrix3<f32>>,
+        face_size: u32,
+        selection: &Selection,
+    ) -> DepthCube {
+        let faces = equ_to_faces_nearest(
+            distance,
+            equ,
+            orientation,
+            face_size,
+            1,
+            selection,
+            |basis, ray, d, depth| depth[0] = radial_to_planar(d[0], ray, &basis.center),
+        );
+        DepthCube { faces }
+    }
+
+    /// Full equirectangular map of distances along the rays
+    pub fn to_equ(&self) -> Tensor {
+        // distance along the ray of the sampled face pixel, not the panorama pixel
+        faces_to_equ_nearest(&self.faces, 1, |basis, ray, depth, d| {
+            d[0] = planar_to_radial(depth[0], ray, &basis.center)
+        })
+    }
+}
+
+/// Distance along `ray` to z-depth along the optical axis `center`
+pub(crate) fn radial_to_planar(distance: f32, ray: &Vector3<f32>, center: &Vector3<f32>) -> f32 {
+    distance * ray.normalize().dot(*center)
+}
+
+/// Z-depth along the optical axis `center` to distance along `ray`
+pub(crate) fn planar_to_radial(depth: f32, ray: &Vector3<f32>, center: &Vector3<f32>) -> f32 {
+    depth / ray.normalize().dot(*center)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projection::{CroppedArea, CubeFace, Face, Projection};
+    use cgmath::Vector2;
+
+    #[test]
+    fn wall_facing_a_face_has_constant_depth() {
+        // wall at y = 2, seen by the front face
+        let dimensions = Vector2::new(64, 32);
+        let equ = Equirectangular {
+            area: CroppedArea::full(dimensions),
+        };
+        let mut distance = Tensor::new(dimensions.x, dimensions.y, 1);
+        for y in 0..dimensions.y {
+            for x in 0..dimensions.x {
+                let ray = equ
+                    .pixel_to_ray(&Vector2::new(x as f32 + 0.5, y as f32 + 0.5))
+                    .unwrap();
+                distance.pixel_mut(x, y)[0] = if ray.y > 0.0 { 2.0 / ray.y } else { 0.0 };
+            }
+        }
+        let cube = DepthCube::from_equ(&distance, &equ, None, 16, &Selection::all());
+        let front = &cube.faces[Face::ALL.iter().position(|f| *f == Face::Front).unwrap()];
+        // nearest sampling is off by up to half an equirectangular pixel
+        for (x, y) in [(8, 8), (2, 3), (13, 12)] {
+            assert!((front.pixel(x, y)[0] - 2.0).abs() < 0.15, "{:?}", (x, y));
+        }
+    }
+
+    #[test]
+    fn sphere_round_trips_to_constant_distance() {
+        let size = 16;
+        let cube = DepthCube {
+            faces: Face::ALL
+                .iter()
+                .map(|face| {
+                    let cube_face = CubeFace { face: *face, size };
+                    let mut depth = Tensor::new(size, size, 1);
+                    for y in 0..size {
+                        for x in 0..size {
+                            let ray = cube_face
+                                .pixel_to_ray(&Vector2::new(x as f32, y as f32))
+                                .unwrap();
+                            depth.pixel_mut(x, y)[0] =
+                                radial_to_planar(3.0, &ray, &face.basis().center);
+                        }
+                    }
+                    depth
+                })
+                .collect(),
+        };
+        let equ = cube.to_equ();
+        assert!(equ.data.iter().all(|d| (d - 3.0).abs() < 1e-4));
+    }
+}
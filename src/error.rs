@@ -0,0 +1,178 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::projection::Face;
+
+/// Result of the library functions
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why splitting, stitching or reading a file failed
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No faces or tiles at all in `directory` (or in the views given)
+    NoViews {
+        directory: Option<PathBuf>,
+        tiles: bool,
+    },
+    /// Face, or tile `index` of a face, needed to assemble a cube is not there
+    MissingView { face: Face, tile: Option<u32> },
+    /// Face, tile or image named by `name` is not of the size the others are
+    Dimensions {
+        name: String,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    /// File name that names no face or tile, or an unknown face in a manifest or annotation
+    UnknownView(String),
+    /// Tiles cut inconsistently, indices out of range, projection or coverage that can't be
+    /// laid out
+    Layout(String),
+    /// Malformed file contents (NumPy array, manifest, remap table, annotations, EXIF)
+    Format {
+        path: Option<PathBuf>,
+        message: String,
+    },
+    /// Valid input this library doesn't handle
+    Unsupported(String),
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// Image decoding or encoding failed
+    Image {
+        path: Option<PathBuf>,
+        source: image::ImageError,
+    },
+    /// Every problem validation found in a set of faces or tiles
+    Invalid(Vec<Error>),
+}
+
+impl Error {
+    /// Maps I/O error of reading or writing `path`
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Io {
+            path: Some(path.to_path_buf()),
+            source,
+        }
+    }
+
+    /// Maps codec error of decoding or encoding `path`
+    pub(crate) fn image(path: &Path) -> impl FnOnce(image::ImageError) -> Error + '_ {
+        move |source| Error::Image {
+            path: Some(path.to_path_buf()),
+            source,
+        }
+    }
+
+    pub(crate) fn format(message: impl fmt::Display) -> Error {
+        Error::Format {
+            path: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// Names the file a path-less error came from
+    pub(crate) fn at(self, file: &Path) -> Error {
+        let file = Some(file.to_path_buf());
+        match self {
+            Error::Format {
+                path: None,
+                message,
+            } => Error::Format {
+                path: file,
+                message,
+            },
+            Error::Io { path: None, source } => Error::Io { path: file, source },
+            Error::Image { path: None, source } => Error::Image { path: file, source },
+            e => e,
+        }
+    }
+}
+
+fn describe(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map_or(String::new(), |p| format!(" on {}", p.display()))
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoViews { directory, tiles } => write!(
+                f,
+                "No {}{}",
+                if *tiles { "tiles" } else { "faces" },
+                directory
+                    .as_ref()
+                    .map_or(String::new(), |d| format!(" in {}", d.display()))
+            ),
+            Error::MissingView { face, tile: None } => write!(f, "{} face missing", face.name()),
+            Error::MissingView {
+                face,
+                tile: Some(i),
+            } => write!(f, "{} tile {} missing", face.name(), i),
+            Error::Dimensions {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} is {}x{}, expected {}x{}",
+                name, found.0, found.1, expected.0, expected.1
+            ),
+            Error::UnknownView(name) => write!(f, "Can't recognize face or tile from {}", name),
+            Error::Layout(message) | Error::Unsupported(message) => write!(f, "{}", message),
+            Error::Format { path, message } => match path {
+                Some(path) => write!(f, "Invalid {}: {}", path.display(), message),
+                None => write!(f, "{}", message),
+            },
+            // the underlying error is the source, not repeated here
+            Error::Io { path, .. } => write!(f, "I/O failed{}", describe(path)),
+            Error::Image { path, .. } => write!(f, "Image codec failed{}", describe(path)),
+            Error::Invalid(problems) => write!(
+                f,
+                "{}",
+                problems
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Image { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(source: image::ImageError) -> Error {
+        Error::Image { path: None, source }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::format(e)
+    }
+}
+
+impl From<exif::Error> for Error {
+    fn from(e: exif::Error) -> Error {
+        Error::format(e)
+    }
+}
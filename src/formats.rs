@@ -0,0 +1,633 @@
+use crate::error::{self, Error};
+use cgmath::Vector2;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::annotations::{Detection, PanoramaAnnotation, ProjectedDetection, ViewAnnotation};
+
+/// Annotation file formats for face, tile and panorama labels
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    /// Equistitch JSON (list of annotations)
+    Json,
+    /// COCO JSON file
+    Coco,
+    /// Directory of YOLO txt files (one per image) and classes.txt
+    Yolo,
+    /// Directory of Pascal VOC XML files (one per image)
+    Voc,
+}
+
+/// Labelled box (and optional polygon) on an image, in pixels of that image
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct LabelRecord {
+    /// Image file name (face or tile name as written by `split`, or the panorama)
+    pub file: String,
+    pub label: String,
+    pub score: Option<f32>,
+    /// `[x_min, y_min, x_max, y_max]`
+    pub bbox: [f32; 4],
+    pub polygon: Vec<[f32; 2]>,
+}
+
+/// Reads labels of every image, YOLO needs `image_size` to turn normalised boxes into pixels
+pub fn read_labels(
+    path: &Path,
+    format: AnnotationFormat,
+    image_size: &dyn Fn(&str) -> Option<Vector2<u32>>,
+) -> error::Result<Vec<LabelRecord>> {
+    match format {
+        AnnotationFormat::Json => Err(Error::Unsupported(
+            "Equistitch JSON is read by the command itself".to_string(),
+        )),
+        AnnotationFormat::Coco => read_coco(path),
+        AnnotationFormat::Yolo => read_yolo(path, image_size),
+        AnnotationFormat::Voc => read_voc(path),
+    }
+}
+
+/// Writes labels, `image_size` gives dimensions of the labelled images
+pub fn write_labels(
+    path: &Path,
+    format: AnnotationFormat,
+    records: &[LabelRecord],
+    image_size: &dyn Fn(&str) -> Vector2<u32>,
+) -> error::Result<()> {
+    match format {
+        AnnotationFormat::Json => Err(Error::Unsupported(
+            "Equistitch JSON is written by the command itself".to_string(),
+        )),
+        AnnotationFormat::Coco => write_coco(path, records, image_size),
+        AnnotationFormat::Yolo => write_yolo(path, records, image_size),
+        AnnotationFormat::Voc => write_voc(path, records, image_size),
+    }
+}
+
+/// Detections on faces or tiles
+pub fn to_detections(records: &[LabelRecord]) -> Vec<Detection> {
+    records
+        .iter()
+        .map(|r| Detection {
+            file: r.file.clone(),
+            bbox: r.bbox,
+            label: r.label.clone(),
+            score: r.score,
+        })
+        .collect()
+}
+
+/// Panorama annotations, polygons are kept when the format has them
+pub fn to_panorama_annotations(records: &[LabelRecord]) -> Vec<PanoramaAnnotation> {
+    records
+        .iter()
+        .map(|r| PanoramaAnnotation {
+            label: r.label.clone(),
+            score: r.score,
+            bbox: r.polygon.is_empty().then_some(r.bbox),
+            polygon: r.polygon.clone(),
+        })
+        .collect()
+}
+
+pub fn from_view_annotations(annotations: &[ViewAnnotation]) -> Vec<LabelRecord> {
+    annotations
+        .iter()
+        .map(|a| LabelRecord {
+            file: a.file.clone(),
+            label: a.label.clone(),
+            score: a.score,
+            bbox: a.bbox,
+            polygon: a.polygon.clone(),
+        })
+        .collect()
+}
+
+/// Labels on the panorama image `file` of `width`, boxes crossing the seam become two boxes
+pub fn from_projected_detections(
+    detections: &[ProjectedDetection],
+    file: &str,
+    width: u32,
+) -> Vec<LabelRecord> {
+    let width = width as f32;
+    detections
+        .iter()
+        .flat_map(|d| {
+            let record = |bbox, polygon| LabelRecord {
+                file: file.to_string(),
+                label: d.label.clone(),
+                score: d.score,
+                bbox,
+                polygon,
+            };
+            let [x0, y0, x1, y1] = d.bbox;
+            if x1 > width {
+                vec![
+                    record([x0, y0, width, y1], vec![]),
+                    record([0.0, y0, x1 - width, y1], vec![]),
+                ]
+            } else {
+                vec![record(d.bbox, d.polygon.clone())]
+            }
+        })
+        .collect()
+}
+
+/// Labels in order of first appearance
+fn categories(records: &[LabelRecord]) -> Vec<String> {
+    let mut categories: Vec<String> = vec![];
+    for record in records {
+        if !categories.contains(&record.label) {
+            categories.push(record.label.clone());
+        }
+    }
+    categories
+}
+
+/// Records grouped per image, images in name order
+fn by_file(records: &[LabelRecord]) -> BTreeMap<&str, Vec<&LabelRecord>> {
+    let mut files: BTreeMap<&str, Vec<&LabelRecord>> = BTreeMap::new();
+    for record in records {
+        files.entry(&record.file).or_default().push(record);
+    }
+    files
+}
+
+fn file_stem(file: &str) -> String {
+    Path::new(file)
+        .file_stem()
+        .map_or(file.to_string(), |s| s.to_string_lossy().to_string())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Coco {
+    images: Vec<CocoImage>,
+    annotations: Vec<CocoAnnotation>,
+    categories: Vec<CocoCategory>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CocoImage {
+    id: u64,
+    file_name: String,
+    width: u32,
+    height: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CocoAnnotation {
+    id: u64,
+    image_id: u64,
+    category_id: u64,
+    /// `[x, y, width, height]`
+    bbox: [f32; 4],
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segmentation: Vec<Vec<f32>>,
+    #[serde(default)]
+    area: f32,
+    #[serde(default)]
+    iscrowd: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CocoCategory {
+    id: u64,
+    name: String,
+}
+
+fn write_coco(
+    path: &Path,
+    records: &[LabelRecord],
+    image_size: &dyn Fn(&str) -> Vector2<u32>,
+) -> error::Result<()> {
+    let categories = categories(records);
+    let files = by_file(records);
+    let images = files
+        .keys()
+        .enumerate()
+        .map(|(i, file)| {
+            let size = image_size(file);
+            CocoImage {
+                id: i as u64 + 1,
+                file_name: file.to_string(),
+                width: size.x,
+                height: size.y,
+            }
+        })
+        .collect::<Vec<_>>();
+    let annotations = files
+        .values()
+        .zip(images.iter())
+        .flat_map(|(records, image)| records.iter().map(move |r| (image.id, r)))
+        .enumerate()
+        .map(|(i, (image_id, r))| {
+            let [x0, y0, x1, y1] = r.bbox;
+            CocoAnnotation {
+                id: i as u64 + 1,
+                image_id,
+                category_id: categories.iter().position(|c| *c == r.label).unwrap_or(0) as u64 + 1,
+                bbox: [x0, y0, x1 - x0, y1 - y0],
+                segmentation: if r.polygon.is_empty() {
+                    vec![]
+                } else {
+                    vec![r.polygon.iter().flat_map(|p| [p[0], p[1]]).collect()]
+                },
+                area: (x1 - x0) * (y1 - y0),
+                iscrowd: 0,
+                score: r.score,
+            }
+        })
+        .collect();
+    let coco = Coco {
+        images,
+        annotations,
+        categories: categories
+            .iter()
+            .enumerate()
+            .map(|(i, name)| CocoCategory {
+                id: i as u64 + 1,
+                name: name.clone(),
+            })
+            .collect(),
+    };
+    fs::write(path, serde_json::to_string_pretty(&coco)?).map_err(Error::io(path))?;
+    Ok(())
+}
+
+fn read_coco(path: &Path) -> error::Result<Vec<LabelRecord>> {
+    let coco: Coco = serde_json::from_slice(&fs::read(path).map_err(Error::io(path))?)
+        .map_err(|e| Error::format(e).at(path))?;
+    coco.annotations
+        .iter()
+        .map(|a| {
+            let image = coco
+                .images
+                .iter()
+                .find(|i| i.id == a.image_id)
+                .ok_or(Error::format(format!("COCO image {} missing", a.image_id)))?;
+            let label = coco
+                .categories
+                .iter()
+                .find(|c| c.id == a.category_id)
+                .map_or(a.category_id.to_string(), |c| c.name.clone());
+            let [x, y, w, h] = a.bbox;
+            Ok(LabelRecord {
+                file: image.file_name.clone(),
+                label,
+                score: a.score,
+                bbox: [x, y, x + w, y + h],
+                polygon: a
+                    .segmentation
+                    .first()
+                    .map(|s| s.chunks_exact(2).map(|p| [p[0], p[1]]).collect())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn write_yolo(
+    path: &Path,
+    records: &[LabelRecord],
+    image_size: &dyn Fn(&str) -> Vector2<u32>,
+) -> error::Result<()> {
+    fs::create_dir_all(path).map_err(Error::io(path))?;
+    let categories = categories(records);
+    let classes = path.join("classes.txt");
+    fs::write(&classes, categories.join("\n") + "\n").map_err(Error::io(&classes))?;
+    for (file, records) in by_file(records) {
+        let size = image_size(file);
+        let (w, h) = (size.x as f32, size.y as f32);
+        let lines = records
+            .iter()
+            .map(|r| {
+                let [x0, y0, x1, y1] = r.bbox;
+                let class = categories.iter().position(|c| *c == r.label).unwrap_or(0);
+                let mut line = format!(
+                    "{} {:.6} {:.6} {:.6} {:.6}",
+                    class,
+                    (x0 + x1) / 2.0 / w,
+                    (y0 + y1) / 2.0 / h,
+                    (x1 - x0) / w,
+                    (y1 - y0) / h
+                );
+                if let Some(score) = r.score {
+                    line += &format!(" {:.6}", score);
+                }
+                line + "\n"
+            })
+            .collect::<String>();
+        let labels = path.join(format!("{}.txt", file_stem(file)));
+        fs::write(&labels, lines).map_err(Error::io(&labels))?;
+    }
+    Ok(())
+}
+
+/// Image file names by stem, from the labels directory and its sibling `images` directory
+/// (the usual YOLO layout)
+fn yolo_images(path: &Path) -> HashMap<String, String> {
+    let mut images = HashMap::new();
+    for directory in [path.join("../images"), path.to_path_buf()] {
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for file in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if file.extension().is_some_and(|e| e != "txt") {
+                let name = file.file_name().unwrap().to_string_lossy().to_string();
+                images.insert(file_stem(&name), name);
+            }
+        }
+    }
+    images
+}
+
+fn read_yolo(
+    path: &Path,
+    image_size: &dyn Fn(&str) -> Option<Vector2<u32>>,
+) -> error::Result<Vec<LabelRecord>> {
+    let classes = fs::read_to_string(path.join("classes.txt"))
+        .map(|c| c.lines().map(|l| l.trim().to_string()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let mut entries = fs::read_dir(path)
+        .map_err(Error::io(path))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.extension().is_some_and(|e| e == "txt")
+                && p.file_name().is_some_and(|n| n != "classes.txt")
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    let images = yolo_images(path);
+    let mut records = vec![];
+    for entry in entries {
+        let stem = file_stem(&entry.to_string_lossy());
+        // labels of faces and tiles without their images are for JPEGs written by split
+        let file = images
+            .get(&stem)
+            .cloned()
+            .unwrap_or_else(|| format!("{}.jpg", stem));
+        let size = image_size(&file).ok_or(Error::format(format!(
+            "Unknown image size for YOLO labels {}",
+            file
+        )))?;
+        let (w, h) = (size.x as f32, size.y as f32);
+        for line in fs::read_to_string(&entry)
+            .map_err(Error::io(&entry))?
+            .lines()
+        {
+            let values = line.split_whitespace().collect::<Vec<_>>();
+            if values.is_empty() {
+                continue;
+            }
+            if values.len() < 5 {
+                return Err(Error::format(format!("Invalid YOLO line {}", line)).at(&entry));
+            }
+            let class = values[0];
+            let numbers = values[1..]
+                .iter()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| Error::format(e).at(&entry))?;
+            let (cx, cy, bw, bh) = (
+                numbers[0] * w,
+                numbers[1] * h,
+                numbers[2] * w,
+                numbers[3] * h,
+            );
+            records.push(LabelRecord {
+                file: file.clone(),
+                label: class
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| classes.get(i).cloned())
+                    .unwrap_or_else(|| class.to_string()),
+                score: numbers.get(4).cloned(),
+                bbox: [cx - bw / 2.0, cy - bh / 2.0, cx + bw / 2.0, cy + bh / 2.0],
+                polygon: vec![],
+            });
+        }
+    }
+    Ok(records)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Text content of every `<tag>` element
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut elements = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let content = &rest[start + open.len()..];
+        match content.find(&close) {
+            Some(end) => {
+                elements.push(&content[..end]);
+                rest = &content[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    elements
+}
+
+fn xml_value<T: std::str::FromStr>(xml: &str, tag: &str) -> error::Result<T> {
+    xml_elements(xml, tag)
+        .first()
+        .and_then(|v| v.trim().parse::<T>().ok())
+        .ok_or(Error::format(format!(
+            "Invalid or missing <{}> in VOC file",
+            tag
+        )))
+}
+
+fn write_voc(
+    path: &Path,
+    records: &[LabelRecord],
+    image_size: &dyn Fn(&str) -> Vector2<u32>,
+) -> error::Result<()> {
+    fs::create_dir_all(path).map_err(Error::io(path))?;
+    for (file, records) in by_file(records) {
+        let size = image_size(file);
+        let objects = records
+            .iter()
+            .map(|r| {
+                let [x0, y0, x1, y1] = r.bbox;
+                format!(
+                    "  <object>\n    <name>{}</name>\n    <pose>Unspecified</pose>\n    \
+                     <truncated>0</truncated>\n    <difficult>0</difficult>\n    <bndbox>\n      \
+                     <xmin>{:.1}</xmin>\n      <ymin>{:.1}</ymin>\n      <xmax>{:.1}</xmax>\n      \
+                     <ymax>{:.1}</ymax>\n    </bndbox>\n  </object>\n",
+                    escape_xml(&r.label),
+                    x0,
+                    y0,
+                    x1,
+                    y1
+                )
+            })
+            .collect::<String>();
+        let xml = format!(
+            "<annotation>\n  <filename>{}</filename>\n  <size>\n    <width>{}</width>\n    \
+             <height>{}</height>\n    <depth>3</depth>\n  </size>\n{}</annotation>\n",
+            escape_xml(file),
+            size.x,
+            size.y,
+            objects
+        );
+        let labels = path.join(format!("{}.xml", file_stem(file)));
+        fs::write(&labels, xml).map_err(Error::io(&labels))?;
+    }
+    Ok(())
+}
+
+fn read_voc(path: &Path) -> error::Result<Vec<LabelRecord>> {
+    let mut entries = fs::read_dir(path)
+        .map_err(Error::io(path))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "xml"))
+        .collect::<Vec<_>>();
+    entries.sort();
+    let mut records = vec![];
+    for entry in entries {
+        let xml = fs::read_to_string(&entry).map_err(Error::io(&entry))?;
+        let file = xml_elements(&xml, "filename")
+            .first()
+            .map(|f| unescape_xml(f.trim()))
+            .unwrap_or_else(|| format!("{}.jpg", file_stem(&entry.to_string_lossy())));
+        for object in xml_elements(&xml, "object") {
+            records.push(LabelRecord {
+                file: file.clone(),
+                label: unescape_xml(xml_elements(object, "name").first().unwrap_or(&"").trim()),
+                score: None,
+                bbox: [
+                    xml_value(object, "xmin")?,
+                    xml_value(object, "ymin")?,
+                    xml_value(object, "xmax")?,
+                    xml_value(object, "ymax")?,
+                ],
+                polygon: vec![],
+            });
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<LabelRecord> {
+        vec![
+            LabelRecord {
+                file: "front_p2_1.jpg".to_string(),
+                label: "car".to_string(),
+                score: Some(0.5),
+                bbox: [10.0, 20.0, 30.0, 60.0],
+                polygon: vec![[10.0, 20.0], [30.0, 20.0], [30.0, 60.0]],
+            },
+            LabelRecord {
+                file: "back_p2_0.jpg".to_string(),
+                label: "person & dog".to_string(),
+                score: None,
+                bbox: [0.0, 0.0, 50.0, 100.0],
+                polygon: vec![],
+            },
+        ]
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("equistitch_{}_{}", std::process::id(), name))
+    }
+
+    fn round_trip(format: AnnotationFormat, name: &str) -> Vec<LabelRecord> {
+        let path = temp_path(name);
+        write_labels(&path, format, &records(), &|_| Vector2::new(100, 100)).unwrap();
+        let mut read = read_labels(&path, format, &|_| Some(Vector2::new(100, 100))).unwrap();
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        read.sort_by(|a, b| b.file.cmp(&a.file));
+        read
+    }
+
+    #[test]
+    fn seam_crossing_box_is_split_in_two() {
+        let detection = ProjectedDetection {
+            file: "back.jpg".to_string(),
+            label: "car".to_string(),
+            score: None,
+            polygon: vec![[390.0, 10.0], [410.0, 10.0], [410.0, 20.0]],
+            bbox: [390.0, 10.0, 410.0, 20.0],
+            wraps: true,
+            merged: vec![],
+        };
+        let records = from_projected_detections(&[detection], "panorama.jpg", 400);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].bbox, [390.0, 10.0, 400.0, 20.0]);
+        assert_eq!(records[1].bbox, [0.0, 10.0, 10.0, 20.0]);
+    }
+
+    #[test]
+    fn coco_round_trips_boxes_polygons_and_scores() {
+        assert_eq!(
+            round_trip(AnnotationFormat::Coco, "test_coco.json"),
+            records()
+        );
+    }
+
+    #[test]
+    fn yolo_round_trips_boxes() {
+        let read = round_trip(AnnotationFormat::Yolo, "test_yolo");
+        assert_eq!(read[0].label, "car");
+        assert_eq!(read[0].score, Some(0.5));
+        assert_eq!(read[1].label, "person & dog");
+        for (a, b) in read.iter().zip(records().iter()) {
+            assert_eq!(a.file, b.file);
+            for (x, y) in a.bbox.iter().zip(b.bbox.iter()) {
+                assert!((x - y).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn yolo_labels_name_the_images_next_to_them() {
+        let path = temp_path("test_yolo_images");
+        let labels = path.join("labels");
+        fs::create_dir_all(&labels).unwrap();
+        fs::create_dir_all(path.join("images")).unwrap();
+        fs::write(path.join("images/front.png"), b"").unwrap();
+        for stem in ["front", "back_p2_0"] {
+            fs::write(labels.join(format!("{}.txt", stem)), "0 0.5 0.5 0.1 0.1\n").unwrap();
+        }
+        let read = read_labels(&labels, AnnotationFormat::Yolo, &|_| {
+            Some(Vector2::new(100, 100))
+        })
+        .unwrap();
+        fs::remove_dir_all(&path).unwrap();
+        let files = read.iter().map(|r| r.file.as_str()).collect::<Vec<_>>();
+        assert_eq!(files, ["back_p2_0.jpg", "front.png"]);
+    }
+
+    #[test]
+    fn voc_round_trips_boxes() {
+        let read = round_trip(AnnotationFormat::Voc, "test_voc");
+        assert_eq!(read[1].label, "person & dog");
+        assert_eq!(read[0].bbox, records()[0].bbox);
+    }
+}
@@ -0,0 +1,1181 @@
+//! Equistitch splits 360 degree equirectangular panoramas into perspective cubemap faces and
+//! tiles and stitches them back.
+//!
+//! [`equ2cube`] renders the faces of a [`Cube`] from a panorama laid on the sphere as its
+//! [`EquLayout`] says, [`cube2equ`] stitches them back and [`split_image`] / [`stitch_image`]
+//! cut faces into tiles and join them. Face pixels look along [`face_pixel2ray`] and
+//! [`ray2equ_pixel`] finds the panorama pixel of a ray. Masks, depth, vector fields and
+//! annotations have their own modules. Functions fail with an [`Error`] telling what was
+//! missing or malformed.
+//!
+//! The command line tool needs the default `cli` feature, library users can turn it off to
+//! drop clap.
+
+pub use archive::{read_archive, write_archive, ArchiveFormat};
+use cgmath::{InnerSpace, Matrix3, Vector2, Vector3};
+pub use error::Error;
+use image::{
+    DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageFormat, Pixel, Rgba, RgbaImage,
+};
+pub use npy::PixelType;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{f32::consts::PI, fs};
+
+pub mod annotations;
+mod archive;
+pub mod depth;
+pub mod error;
+pub mod formats;
+pub mod manifest;
+pub mod mask;
+pub mod merge;
+pub mod metadata;
+mod npy;
+pub mod projection;
+pub mod remap;
+pub mod selection;
+pub mod tensor;
+pub mod validate;
+pub mod vectors;
+pub mod xmp;
+
+use manifest::{Manifest, ManifestView};
+use metadata::SourceExif;
+use projection::{CroppedArea, Face};
+use selection::Selection;
+use validate::ViewShape;
+
+/// Metadata found in an input image
+#[non_exhaustive]
+pub struct InputMetadata {
+    pub gpano: Option<xmp::GPano>,
+    pub exif: Option<SourceExif>,
+}
+
+impl InputMetadata {
+    /// GPano XMP and EXIF of an encoded image, or of its start when they precede the pixels
+    pub fn read(bytes: &[u8]) -> InputMetadata {
+        InputMetadata {
+            gpano: xmp::read_gpano(bytes),
+            exif: SourceExif::read(bytes),
+        }
+    }
+}
+
+/// Decodes encoded image of `format` together with its GPano XMP and EXIF metadata, the
+/// format is guessed from the data when not given
+pub fn decode_with_metadata(
+    bytes: &[u8],
+    format: Option<ImageFormat>,
+) -> error::Result<(DynamicImage, InputMetadata)> {
+    let metadata = InputMetadata::read(bytes);
+    let format = match format {
+        Some(format) => format,
+        None => image::guess_format(bytes)?,
+    };
+    Ok((
+        image::load_from_memory_with_format(bytes, format)?,
+        metadata,
+    ))
+}
+
+/// Loads image together with its GPano XMP and EXIF metadata
+pub fn load_with_metadata(path: &Path) -> error::Result<(DynamicImage, InputMetadata)> {
+    decode_with_metadata(
+        &fs::read(path).map_err(Error::io(path))?,
+        ImageFormat::from_path(path).ok(),
+    )
+    .map_err(|e| e.at(path))
+}
+
+/// EXIF written into saved faces and tiles
+pub struct FaceExif {
+    pub exif: SourceExif,
+    /// Compass heading of the panorama front, each view gets heading of its own center
+    pub front_heading: f32,
+}
+
+/// Heading in degrees (0 is front, 90 is right) of a pixel on a cube face
+pub(crate) fn view_heading(face: Face, pixel: &Vector2<u32>, dimensions: &Vector2<u32>) -> f32 {
+    let basis = face.basis();
+    let ray = face_pixel2ray(&basis.center, &basis.down, &basis.right, pixel, dimensions);
+    ray.x.atan2(ray.y).to_degrees()
+}
+
+/// Encodes single face or tile as `format`, `center` is the view center in face pixels
+pub(crate) fn encode_view(
+    image: &RgbaImage,
+    format: ImageFormat,
+    exif: Option<&FaceExif>,
+    face: Face,
+    center: Vector2<u32>,
+    face_dimensions: Vector2<u32>,
+) -> error::Result<Vec<u8>> {
+    let payloads = match exif {
+        Some(e) => vec![e.exif.app1(
+            Vector2::new(image.width(), image.height()),
+            Some(e.front_heading + view_heading(face, &center, &face_dimensions)),
+        )?],
+        None => vec![],
+    };
+    metadata::encode_with_app1(image, format, &payloads)
+}
+
+/// Saves single face or tile, `center` is the view center in face pixels
+pub fn save_view(
+    image: &RgbaImage,
+    path: &Path,
+    exif: Option<&FaceExif>,
+    face: Face,
+    center: Vector2<u32>,
+    face_dimensions: Vector2<u32>,
+) -> error::Result<()> {
+    let format = ImageFormat::from_path(path).map_err(Error::image(path))?;
+    let bytes =
+        encode_view(image, format, exif, face, center, face_dimensions).map_err(|e| e.at(path))?;
+    fs::write(path, bytes).map_err(Error::io(path))?;
+    Ok(())
+}
+
+/// Cuts covered area out of full equirectangular image, wrapping around the 0/360 degree seam
+pub fn crop_equ<P: Pixel + 'static>(equ: &Image<P>, area: &CroppedArea) -> Image<P> {
+    ImageBuffer::from_fn(area.width, area.height, |x, y| {
+        let full = area.full_pixel(Vector2::new(x as f32, y as f32));
+        *equ.get_pixel(
+            (full.x as u32).min(equ.width() - 1),
+            (full.y as u32).min(equ.height() - 1),
+        )
+    })
+}
+
+/// Image buffer of any pixel type (RGBA faces, single channel masks)
+pub type Image<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// `ImageBuffer::from_fn` rendering rows in parallel, every pixel is computed exactly as the
+/// sequential version would
+pub(crate) fn par_from_fn<P, F>(width: u32, height: u32, f: F) -> Image<P>
+where
+    P: Pixel + Send + Sync + 'static,
+    P::Subpixel: Send + Sync,
+    F: Fn(u32, u32) -> P + Sync,
+{
+    let mut image = ImageBuffer::new(width, height);
+    let row = width as usize * P::CHANNEL_COUNT as usize;
+    if row == 0 {
+        return image;
+    }
+    image
+        .par_chunks_mut(row)
+        .enumerate()
+        .for_each(|(y, pixels)| {
+            for (x, pixel) in pixels.chunks_mut(P::CHANNEL_COUNT as usize).enumerate() {
+                pixel.copy_from_slice(f(x as u32, y as u32).channels());
+            }
+        });
+    image
+}
+
+/// Face (`pieces` 1) or tile files of a directory written by `split`, indexed by face in
+/// `Face::ALL` order and then by tile, `None` where the file is missing
+#[non_exhaustive]
+pub struct CubeFiles {
+    pub face_size: u32,
+    /// Tiles per face row and column, 1 for faces
+    pub pieces: u32,
+    pub paths: Vec<Option<PathBuf>>,
+}
+
+impl CubeFiles {
+    /// Faces `{face}.{extension}` or tiles `{face}_p{pieces}_{i}.{extension}` of `directory`
+    pub fn find(directory: &Path, extension: &str, tiles: bool) -> error::Result<CubeFiles> {
+        let found = validate::shapes_in(directory, extension, tiles)?;
+        let (first, _) = *found.first().ok_or(Error::NoViews {
+            directory: Some(directory.to_path_buf()),
+            tiles,
+        })?;
+        // missing views are filled, anything else keeps the cube from being assembled
+        check(
+            &found.iter().map(|(shape, _)| *shape).collect::<Vec<_>>(),
+            true,
+        )?;
+        let pieces = first.tile.map_or(1, |(pieces, _)| pieces);
+        let per_face = (pieces * pieces) as usize;
+        let mut paths = vec![None; Face::ALL.len() * per_face];
+        for (shape, path) in found {
+            let f = Face::ALL.iter().position(|f| *f == shape.face).unwrap();
+            paths[f * per_face + shape.tile.map_or(0, |(_, i)| i as usize)] = Some(path);
+        }
+        if first.dimensions.0 == 0 {
+            return Err(Error::Layout(format!(
+                "Views of {} are empty",
+                directory.display()
+            )));
+        }
+        Ok(CubeFiles {
+            face_size: first.dimensions.0 * pieces,
+            pieces,
+            paths,
+        })
+    }
+
+    /// Side of a face or tile file
+    pub fn tile_size(&self) -> u32 {
+        self.face_size / self.pieces
+    }
+
+    /// View holding a face pixel and the pixel within it
+    pub fn locate(&self, face: Face, p: Vector2<u32>) -> (usize, Vector2<u32>) {
+        let size = self.tile_size();
+        let f = Face::ALL.iter().position(|f| *f == face).unwrap();
+        let (px, py) = (p.x / size, p.y / size);
+        (
+            (f as u32 * self.pieces * self.pieces + px * self.pieces + py) as usize,
+            Vector2::new(p.x % size, p.y % size),
+        )
+    }
+
+    /// View and pixel within it that `cube2equ` samples for pixel `(x, y)` of the stitched
+    /// panorama
+    pub fn source_pixel(&self, x: u32, y: u32) -> Option<(usize, Vector2<u32>)> {
+        cube2equ_source_pixel(x, y, self.face_size).map(|(face, p)| self.locate(face, p))
+    }
+}
+
+/// `{face}` or `{face}_p{pieces}_{i}` naming a face or tile
+pub(crate) fn view_name(face: Face, tile: Option<(u32, u32)>) -> String {
+    match tile {
+        None => face.name().to_string(),
+        Some((pieces, i)) => format!("{}_p{}_{}", face.name(), pieces, i),
+    }
+}
+
+/// Errors with every problem of `views`, missing views are no problem when they get `filled`
+fn check(views: &[ViewShape], filled: bool) -> error::Result<()> {
+    let mut problems = validate::problems(views);
+    if filled {
+        problems.retain(|p| !matches!(p, Error::MissingView { .. }));
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(Error::Invalid(problems)),
+    }
+}
+
+/// Face or tile of a cube held in memory
+#[non_exhaustive]
+pub struct CubeView {
+    pub face: Face,
+    /// Tiles per face row and column and tile index (column by column), `None` for faces
+    pub tile: Option<(u32, u32)>,
+    /// Side of the face the view is cut from
+    pub face_size: u32,
+    pub image: RgbaImage,
+}
+
+impl CubeView {
+    /// Face and, for tiles, pieces and index of a file stem written by `split`
+    pub fn parse_name(stem: &str) -> Option<(Face, Option<(u32, u32)>)> {
+        Face::ALL.iter().find_map(|face| {
+            if stem == face.name() {
+                return Some((*face, None));
+            }
+            let (pieces, i) = stem
+                .strip_prefix(&format!("{}_p", face.name()))?
+                .split_once('_')?;
+            let pieces = pieces.parse().ok().filter(|p| *p > 0)?;
+            Some((*face, Some((pieces, i.parse().ok()?))))
+        })
+    }
+
+    /// Decodes face or tile named `{face}.{ext}` or `{face}_p{pieces}_{i}.{ext}`
+    pub fn decode(name: &str, bytes: &[u8]) -> error::Result<CubeView> {
+        let stem = Path::new(name).file_stem().unwrap_or_default();
+        let (face, tile) = CubeView::parse_name(&stem.to_string_lossy())
+            .ok_or_else(|| Error::UnknownView(name.to_string()))?;
+        let image = image::load_from_memory(bytes).map_err(Error::image(Path::new(name)))?;
+        Ok(CubeView {
+            face,
+            tile,
+            face_size: image.width() * tile.map_or(1, |(pieces, _)| pieces),
+            image: image.into_rgba8(),
+        })
+    }
+
+    /// `{face}` or `{face}_p{pieces}_{i}`, the file stem `split` writes the view under
+    pub fn name(&self) -> String {
+        view_name(self.face, self.tile)
+    }
+
+    pub fn shape(&self) -> ViewShape {
+        ViewShape {
+            face: self.face,
+            tile: self.tile,
+            dimensions: self.image.dimensions(),
+        }
+    }
+
+    /// File name `split` writes the view under
+    pub fn file_name(&self, extension: &str) -> String {
+        format!("{}.{}", self.name(), extension)
+    }
+
+    /// Encodes view as `format`, EXIF gets the heading of the view center
+    pub fn encode(&self, format: ImageFormat, exif: Option<&FaceExif>) -> error::Result<Vec<u8>> {
+        let size = self.image.width();
+        let (pieces, i) = self.tile.unwrap_or((1, 0));
+        let (px, py) = (i / pieces, i % pieces);
+        let center = Vector2::new(px * size, py * size) + Vector2::new(size / 2, size / 2);
+        encode_view(
+            &self.image,
+            format,
+            exif,
+            self.face,
+            center,
+            Vector2::new(self.face_size, self.face_size),
+        )
+    }
+}
+
+/// Six 90 degree faces of a cubemap, equally sized squares
+pub struct Cube {
+    front: RgbaImage,
+    back: RgbaImage,
+    left: RgbaImage,
+    right: RgbaImage,
+    up: RgbaImage,
+    down: RgbaImage,
+}
+
+impl Cube {
+    /// Faces or tiles of `directory` written by `split`, missing ones are filled with `fill`
+    /// and returned as `Error::MissingView`
+    pub fn load(
+        directory: &Path,
+        extension: &str,
+        tiles: bool,
+        fill: Rgba<u8>,
+    ) -> error::Result<(Cube, Vec<Error>)> {
+        let files = CubeFiles::find(directory, extension, tiles)?;
+        let per_face = (files.pieces * files.pieces) as usize;
+        let missing = files
+            .paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| path.is_none())
+            .map(|(v, _)| Error::MissingView {
+                face: Face::ALL[v / per_face],
+                tile: tiles.then_some((v % per_face) as u32),
+            })
+            .collect();
+        let views = files
+            .paths
+            .par_iter()
+            .enumerate()
+            .filter_map(|(v, path)| Some((v, path.as_ref()?)))
+            .map(|(v, path)| {
+                Ok(CubeView {
+                    face: Face::ALL[v / per_face],
+                    tile: tiles.then_some((files.pieces, (v % per_face) as u32)),
+                    face_size: files.face_size,
+                    image: image::open(path).map_err(Error::image(path))?.into_rgba8(),
+                })
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        Ok((Cube::from_views(views, fill)?, missing))
+    }
+
+    /// Joins faces or tiles of one tiling, missing ones are filled with `fill`
+    pub fn from_views(views: Vec<CubeView>, fill: Rgba<u8>) -> error::Result<Cube> {
+        let first = views.first().ok_or(Error::NoViews {
+            directory: None,
+            tiles: false,
+        })?;
+        check(&views.iter().map(CubeView::shape).collect::<Vec<_>>(), true)?;
+        let face_size = first.face_size;
+        let pieces = first.tile.map_or(1, |(pieces, _)| pieces);
+        let tile_size = first.image.width();
+        let mut faces = vec![RgbaImage::from_pixel(face_size, face_size, fill); Face::ALL.len()];
+        for view in &views {
+            // tiles go column by column
+            let i = view.tile.map_or(0, |(_, i)| i);
+            let (px, py) = (i / pieces, i % pieces);
+            let f = Face::ALL.iter().position(|f| *f == view.face).unwrap();
+            faces[f].copy_from(&view.image, px * tile_size, py * tile_size)?;
+        }
+        Cube::from_faces(faces)
+    }
+
+    /// Faces given in `Face::ALL` order
+    pub fn from_faces(faces: Vec<RgbaImage>) -> error::Result<Cube> {
+        let [front, back, left, right, up, down]: [RgbaImage; 6] =
+            faces.try_into().map_err(|faces: Vec<RgbaImage>| {
+                Error::Layout(format!("Cube needs 6 faces, got {}", faces.len()))
+            })?;
+        Ok(Cube {
+            front,
+            back,
+            left,
+            right,
+            up,
+            down,
+        })
+    }
+
+    /// Side of every face in pixels
+    pub fn face_size(&self) -> u32 {
+        self.front.width()
+    }
+
+    pub fn face(&self, face: Face) -> &RgbaImage {
+        match face {
+            Face::Front => &self.front,
+            Face::Back => &self.back,
+            Face::Left => &self.left,
+            Face::Right => &self.right,
+            Face::Up => &self.up,
+            Face::Down => &self.down,
+        }
+    }
+
+    /// Selected faces
+    pub fn faces(&self, selection: &Selection) -> Vec<CubeView> {
+        selection
+            .faces
+            .iter()
+            .map(|face| CubeView {
+                face: *face,
+                tile: None,
+                face_size: self.face_size(),
+                image: self.face(*face).clone(),
+            })
+            .collect()
+    }
+
+    /// Selected tiles of `patch_size`
+    pub fn tiles(&self, patch_size: u32, selection: &Selection) -> Vec<CubeView> {
+        selection
+            .faces
+            .par_iter()
+            .flat_map_iter(|face| {
+                let image = self.face(*face);
+                let y_pieces = image.height() / patch_size;
+                let (patches, pieces) = split_image(image, patch_size);
+                patches
+                    .into_iter()
+                    .enumerate()
+                    // patches go column by column
+                    .filter(move |(i, _)| {
+                        selection.tile(*face, *i as u32 / y_pieces, *i as u32 % y_pieces)
+                    })
+                    .map(move |(i, image)| CubeView {
+                        face: *face,
+                        tile: Some((pieces, i as u32)),
+                        face_size: self.face_size(),
+                        image,
+                    })
+            })
+            .collect()
+    }
+
+    /// Writes selected faces as `{prefix}/{face}.jpg`
+    pub fn save(
+        &self,
+        prefix: &str,
+        exif: Option<&FaceExif>,
+        selection: &Selection,
+    ) -> error::Result<()> {
+        save_views(&self.faces(selection), Path::new(prefix), exif)
+    }
+
+    /// Writes selected tiles of `patch_size` as `{prefix}/{face}_p{pieces}_{i}.jpg`
+    pub fn save_patches(
+        &self,
+        prefix: &str,
+        patch_size: u32,
+        exif: Option<&FaceExif>,
+        selection: &Selection,
+    ) -> error::Result<()> {
+        save_views(&self.tiles(patch_size, selection), Path::new(prefix), exif)
+    }
+}
+
+/// Writes views into `directory` as JPEG
+fn save_views(views: &[CubeView], directory: &Path, exif: Option<&FaceExif>) -> error::Result<()> {
+    views.par_iter().try_for_each(|view| {
+        let bytes = view.encode(ImageFormat::Jpeg, exif)?;
+        let path = directory.join(view.file_name("jpg"));
+        fs::write(&path, bytes).map_err(Error::io(&path))?;
+        Ok(())
+    })
+}
+
+impl Cube {
+    /// Writes faces, or tiles of `patch_size`, as one N×H×W×3 array and its manifest next to it
+    pub fn save_npy(
+        &self,
+        path: &Path,
+        patch_size: Option<u32>,
+        pixel_type: PixelType,
+        selection: &Selection,
+    ) -> error::Result<()> {
+        let mut manifest = Manifest::new(self.front.width(), patch_size, "npy");
+        manifest.views.retain(|view| selection.manifest_view(view));
+        let mut data = vec![];
+        for view in &manifest.views {
+            let face = Face::ALL
+                .iter()
+                .find(|f| f.name() == view.face)
+                .ok_or_else(|| Error::UnknownView(view.face.clone()))?;
+            let image = self.face(*face);
+            for y in view.y..view.y + view.height {
+                for x in view.x..view.x + view.width {
+                    let pixel = image.get_pixel(x, y);
+                    data.extend(pixel.0[..3].iter().map(|v| *v as f32 * pixel_type.scale()));
+                }
+            }
+        }
+        let size = patch_size.unwrap_or(manifest.face_size) as usize;
+        let shape = vec![manifest.views.len(), size, size, 3];
+        npy::write(path, &shape, pixel_type.dtype(), &data)?;
+        manifest.array = path.file_name().map(|n| n.to_string_lossy().to_string());
+        manifest.dtype = Some(pixel_type.name().to_string());
+        manifest.shape = shape;
+        manifest.save(&path.with_extension("json"))
+    }
+
+    /// Reads array written by `save_npy`, float arrays are taken as normalised to 0..1
+    pub fn from_npy(path: &Path) -> error::Result<Cube> {
+        let manifest = Manifest::load(&path.with_extension("json"))?;
+        let array = npy::read(path)?;
+        let (n, height, width, channels) = match array.shape[..] {
+            [n, h, w, c] if c == 3 || c == 4 => (n, h, w, c),
+            _ => {
+                return Err(Error::format(format!(
+                    "Expected N×H×W×3 or N×H×W×4 array, got {:?}",
+                    array.shape
+                ))
+                .at(path))
+            }
+        };
+        if n != manifest.views.len() {
+            return Err(Error::Layout(format!(
+                "Array holds {} views but manifest lists {}",
+                n,
+                manifest.views.len()
+            )));
+        }
+        let scale = match array.dtype {
+            npy::Dtype::F32 | npy::Dtype::F64 => 255.0,
+            _ => 1.0,
+        };
+        let mut faces = Face::ALL
+            .iter()
+            .map(|_| RgbaImage::new(manifest.face_size, manifest.face_size))
+            .collect::<Vec<_>>();
+        for (view, values) in manifest
+            .views
+            .iter()
+            .zip(array.data.chunks_exact(height * width * channels))
+        {
+            let i = Face::ALL
+                .iter()
+                .position(|f| f.name() == view.face)
+                .ok_or_else(|| Error::UnknownView(view.face.clone()))?;
+            if (view.width as usize, view.height as usize) != (width, height)
+                || view.x + view.width > manifest.face_size
+                || view.y + view.height > manifest.face_size
+            {
+                return Err(Error::Layout(format!(
+                    "View {} doesn't fit the array",
+                    view.name
+                )));
+            }
+            for (j, pixel) in values.chunks_exact(channels).enumerate() {
+                let value = |c: usize| (pixel[c] * scale).round().clamp(0.0, 255.0) as u8;
+                let alpha = if channels == 4 { value(3) } else { 255 };
+                faces[i].put_pixel(
+                    view.x + (j % width) as u32,
+                    view.y + (j / width) as u32,
+                    Rgba([value(0), value(1), value(2), alpha]),
+                );
+            }
+        }
+        let mut faces = faces.into_iter();
+        let mut next = || faces.next().unwrap();
+        Ok(Cube {
+            front: next(),
+            back: next(),
+            left: next(),
+            right: next(),
+            up: next(),
+            down: next(),
+        })
+    }
+}
+
+static FRONT: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
+static BACK: Vector3<f32> = Vector3::new(0.0, -1.0, 0.0);
+static LEFT: Vector3<f32> = Vector3::new(-1.0, 0.0, 0.0);
+static RIGHT: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
+static UP: Vector3<f32> = Vector3::new(0.0, 0.0, 1.0);
+static DOWN: Vector3<f32> = Vector3::new(0.0, 0.0, -1.0);
+
+/// Cuts image into `patch_size` tiles column by column, returns them and tiles per row
+pub fn split_image<P: Pixel + 'static>(im: &Image<P>, patch_size: u32) -> (Vec<Image<P>>, u32) {
+    let (width, height) = im.dimensions();
+    let x_pieces = width / patch_size;
+    let y_pieces = height / patch_size;
+    let mut patches = vec![];
+    for px in 0..x_pieces {
+        for py in 0..y_pieces {
+            patches.push(
+                im.view(px * patch_size, py * patch_size, patch_size, patch_size)
+                    .to_image(),
+            );
+        }
+    }
+    (patches, x_pieces)
+}
+
+/// Joins tiles given column by column like `split_image` cuts them
+pub fn stitch_image<P: Pixel + 'static>(
+    patches: &[Image<P>],
+    x_pieces: u32,
+) -> error::Result<Image<P>> {
+    let example_patch = patches.first().ok_or(Error::NoViews {
+        directory: None,
+        tiles: true,
+    })?;
+    let (width, height) = example_patch.dimensions();
+    let full_width = width * x_pieces;
+    let y_pieces = patches.len() as u32 / x_pieces;
+    let full_height = height * y_pieces;
+    let mut full = image::ImageBuffer::new(full_width, full_height);
+    for (i, patch) in patches
+        .iter()
+        .enumerate()
+        .take((x_pieces * y_pieces) as usize)
+    {
+        if patch.dimensions() != (width, height) {
+            return Err(Error::Dimensions {
+                name: format!("Tile {}", i),
+                expected: (width, height),
+                found: patch.dimensions(),
+            });
+        }
+        let (px, py) = (i as u32 / y_pieces, i as u32 % y_pieces);
+        full.copy_from(patch, px * width, py * height)?;
+    }
+    Ok(full)
+}
+
+/// Unit ray of `pixel` on the face looking along `center`, `down` and `right` span half of the
+/// face
+pub fn face_pixel2ray(
+    center: &Vector3<f32>,
+    down: &Vector3<f32>,
+    right: &Vector3<f32>,
+    pixel: &Vector2<u32>,
+    dimensions: &Vector2<u32>,
+) -> Vector3<f32> {
+    let origo2d = center - right - down; // we want to head to upper left corner thats why multiply with -0.5
+    let x_scaled = (pixel.x as f32) / (dimensions.x as f32);
+    let y_scaled = (pixel.y as f32) / (dimensions.y as f32);
+
+    let point_in_face = origo2d + right * x_scaled * 2.0 + down * y_scaled * 2.0;
+    point_in_face.normalize()
+}
+
+/// Direction stored in ray maps, in the level north-facing frame of the faces
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayMap {
+    /// Unit vector x (right), y (front), z (up)
+    Xyz,
+    /// Latitude and longitude in degrees (0 is front, positive is right)
+    Latlon,
+}
+
+impl RayMap {
+    /// Values per pixel
+    pub fn channels(&self) -> usize {
+        match self {
+            RayMap::Xyz => 3,
+            RayMap::Latlon => 2,
+        }
+    }
+
+    /// H×W×C values for the view, rays come from `face_pixel2ray` like the face pixels
+    pub fn values(&self, face: Face, face_size: u32, view: &ManifestView) -> Vec<f32> {
+        let basis = face.basis();
+        let dimensions = Vector2::new(face_size, face_size);
+        let mut values = Vec::with_capacity((view.width * view.height) as usize * self.channels());
+        for y in view.y..view.y + view.height {
+            for x in view.x..view.x + view.width {
+                let ray = face_pixel2ray(
+                    &basis.center,
+                    &basis.down,
+                    &basis.right,
+                    &Vector2::new(x, y),
+                    &dimensions,
+                );
+                match self {
+                    RayMap::Xyz => values.extend([ray.x, ray.y, ray.z]),
+                    RayMap::Latlon => values.extend([
+                        ray.z.clamp(-1.0, 1.0).asin().to_degrees(),
+                        ray.x.atan2(ray.y).to_degrees(),
+                    ]),
+                }
+            }
+        }
+        values
+    }
+
+    /// Writes the maps of `views`, all of the same size, as one float32 NumPy array:
+    /// H×W×C for a single view and N×H×W×C when `stacked`
+    pub fn save(
+        &self,
+        path: &Path,
+        face_size: u32,
+        views: &[ManifestView],
+        stacked: bool,
+    ) -> error::Result<()> {
+        let mut values = vec![];
+        for view in views {
+            let face = Face::ALL
+                .iter()
+                .find(|f| f.name() == view.face)
+                .ok_or_else(|| Error::UnknownView(view.face.clone()))?;
+            values.extend(self.values(*face, face_size, view));
+        }
+        let (width, height) = views.first().map_or((0, 0), |v| (v.width, v.height));
+        let shape = [height as usize, width as usize, self.channels()];
+        let shape = match stacked {
+            true => [&[views.len()][..], &shape].concat(),
+            false => shape.to_vec(),
+        };
+        npy::write(path, &shape, npy::Dtype::F32, &values)
+    }
+}
+
+/// Pixel of the full equirectangular image of `dimensions` hit by `ray`
+pub fn ray2equ_pixel(dimensions: &Vector2<u32>, ray: &Vector3<f32>) -> Vector2<u32> {
+    // 2d angles:
+    // x-direction (longitude)
+    let longitude = PI + ray.x.atan2(ray.y); // will be between 0 to 2pi
+    let pixel_x = (dimensions.x as f32 / (PI * 2.0)) * longitude;
+    //let pixel_x = ((longitude / PI)*(dimensions.x as f32));
+    // y-direction (latitude)
+    let latitude = ray.z.acos(); // will be between 0 to pi
+    let pixel_y = (dimensions.y as f32 / PI) * (latitude);
+
+    // rays pointing straight back or down land on the far edge, keep them inside the image
+    Vector2::new(
+        (pixel_x as u32).min(dimensions.x - 1),
+        (pixel_y as u32).min(dimensions.y - 1),
+    )
+}
+/// How equirectangular image is laid on the sphere
+#[derive(Clone, Copy, Debug)]
+pub struct EquLayout {
+    pub area: CroppedArea,
+    /// Rotation from world rays to image rays (GPano pose), `None` for level north-facing image
+    pub orientation: Option<Matrix3<f32>>,
+}
+
+/// Pixel of the (possibly partial) equirectangular image hit by world `ray`
+pub fn equ_source_pixel(layout: &EquLayout, ray: &Vector3<f32>) -> Option<Vector2<u32>> {
+    let ray = layout
+        .orientation
+        .map_or(*ray, |orientation| orientation * ray);
+    let area = &layout.area;
+    let coordinate = ray2equ_pixel(&Vector2::new(area.full_width, area.full_height), &ray);
+    area.image_pixel(Vector2::new(coordinate.x as f32, coordinate.y as f32))
+        .map(|p| Vector2::new(p.x as u32, p.y as u32))
+}
+
+fn sample_equ(source: &DynamicImage, layout: &EquLayout, ray: &Vector3<f32>) -> Rgba<u8> {
+    match equ_source_pixel(layout, ray) {
+        Some(p) => source.get_pixel(p.x, p.y),
+        None => Rgba([0u8, 0u8, 0u8, 0u8]),
+    }
+}
+
+// equi -> cube
+// 1. for each cube face pixel to xyz-vector from center
+// 2. normalize
+// 3. get pixel value using the xyz-vector
+// Rays outside of the covered area of a partial panorama give transparent pixels.
+/// Renders cube faces of `face_size` from equirectangular `source` laid out as `layout`,
+/// pixels outside of `selection` stay transparent
+pub fn equ2cube(
+    source: DynamicImage,
+    face_size: u32,
+    layout: &EquLayout,
+    selection: &Selection,
+) -> error::Result<Cube> {
+    if face_size == 0 {
+        return Err(Error::Layout(
+            "Cube faces need at least one pixel".to_string(),
+        ));
+    }
+    let face_dimensions = Vector2::new(face_size, face_size);
+    let faces = Face::ALL
+        .iter()
+        .map(|face| {
+            let basis = face.basis();
+            par_from_fn(face_size, face_size, |x, y| {
+                if !selection.pixel(*face, x, y) {
+                    return Rgba([0u8, 0u8, 0u8, 0u8]);
+                }
+                let pixel = Vector2::new(x, y);
+                let ray = face_pixel2ray(
+                    &basis.center,
+                    &basis.down,
+                    &basis.right,
+                    &pixel,
+                    &face_dimensions,
+                );
+                sample_equ(&source, layout, &ray)
+            })
+        })
+        .collect();
+    Cube::from_faces(faces)
+}
+
+/// Point on a sphere, inclination measured from the up axis
+pub fn spherical2cartesian(radius: f32, inclination: f32, azimuth: f32) -> Vector3<f32> {
+    // inclination = y-direction = latitude
+    // azimuth = x-direction = longitude
+    Vector3::new(
+        radius * azimuth.cos() * inclination.sin(),
+        radius * azimuth.sin() * inclination.sin(),
+        radius * inclination.cos(),
+    )
+}
+
+// cube -> equi
+// 1. for each pixel in equ image calculate xyz-vector
+// 2. See which face it hits (up,down,left,right,front,back)
+// 3. See which pixel coordinate it is and sample the pixel
+/// Stitches faces back into a full equirectangular image twice as wide as high
+pub fn cube2equ(source: Cube) -> error::Result<RgbaImage> {
+    let faces = Face::ALL.map(|face| ViewShape {
+        face,
+        tile: None,
+        dimensions: source.face(face).dimensions(),
+    });
+    check(&faces, false)?;
+    let face_size = source.front.width();
+    if face_size == 0 {
+        return Err(Error::Layout("Cube faces are empty".to_string()));
+    }
+    let equ = par_from_fn(face_size * 4, face_size * 2, |x, y| -> Rgba<u8> {
+        match cube2equ_source_pixel(x, y, face_size) {
+            Some((face, p)) => source.face(face)[(p.x, p.y)],
+            None => Rgba([0u8, 0u8, 0u8, 0u8]),
+        }
+    });
+    Ok(equ)
+}
+
+/// Face and face pixel sampled for pixel `(x, y)` of the stitched equirectangular image, `None`
+/// for empty faces
+pub(crate) fn cube2equ_source_pixel(
+    x: u32,
+    y: u32,
+    face_size: u32,
+) -> Option<(Face, Vector2<u32>)> {
+    let width = face_size * 4;
+    let height = face_size * 2;
+    let last = face_size.checked_sub(1)? as f32;
+    let x_prop = (x as f32) / (width as f32);
+    let y_prop = (y as f32) / (height as f32);
+    let longitude = x_prop * PI * 2.0 + PI / 2.0;
+    let latitude = y_prop * PI;
+    let ray = spherical2cartesian(1.0, latitude, longitude).normalize();
+    let max_xyz = ray.x.abs().max(ray.y.abs()).max(ray.z.abs());
+    let ray_u = ray / max_xyz;
+    let coordinate = |i: f32| ((i + 1.0) / 2.0) * last;
+    let flip = |i: f32| face_size - 1 - coordinate(i) as u32;
+    match (ray_u.x, ray_u.y, ray_u.z) {
+        (1.0, ix, iy) => Some((Face::Right, Vector2::new(coordinate(ix) as u32, flip(iy)))),
+        (-1.0, ix, iy) => Some((Face::Left, Vector2::new(flip(ix), flip(iy)))),
+        (ix, 1.0, iy) => Some((Face::Back, Vector2::new(flip(ix), flip(iy)))),
+        (ix, -1.0, iy) => Some((Face::Front, Vector2::new(coordinate(ix) as u32, flip(iy)))),
+        (ix, iy, 1.0) => Some((Face::Up, Vector2::new(flip(ix), coordinate(iy) as u32))),
+        (ix, iy, -1.0) => Some((
+            Face::Down,
+            Vector2::new(coordinate(ix) as u32, coordinate(iy) as u32),
+        )),
+        (_, _, _) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector2;
+    use image::ImageFormat;
+
+    use crate::manifest::Manifest;
+    use crate::projection::{CroppedArea, Face};
+    use crate::selection::{Selection, TileRegion};
+    use crate::{
+        cube2equ, equ2cube, face_pixel2ray, ray2equ_pixel, Cube, CubeView, EquLayout, Error,
+        RayMap, BACK, DOWN, FRONT, LEFT, RIGHT,
+    };
+
+    #[test]
+    fn front_face_center_point_should_point_to_front_ray() {
+        let ray = face_pixel2ray(
+            &FRONT,
+            &DOWN,
+            &RIGHT,
+            &Vector2::new(5, 5),
+            &Vector2::new(10, 10),
+        );
+        assert_eq!(ray, FRONT);
+    }
+    #[test]
+    fn left_face_center_point_should_point_to_left_ray() {
+        let ray = face_pixel2ray(
+            &LEFT,
+            &DOWN,
+            &FRONT,
+            &Vector2::new(5, 5),
+            &Vector2::new(10, 10),
+        );
+        assert_eq!(ray, LEFT);
+    }
+    #[test]
+    fn front_center_should_point_to_center_of_equirectangular_image() {
+        // Assume 360 pixels wide, 180 pixels tall equi image
+        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &FRONT);
+        // Front center pixel should point to (180, 90) in equi image
+        assert_eq!(coordinate, Vector2::new(180, 90));
+    }
+    #[test]
+    fn front_down_center_should_point_to_center_bottom_of_equirectangular_image() {
+        // Assume 360 pixels wide, 180 pixels tall equi image
+        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &(FRONT + DOWN));
+        // Front center pixel should point to (180, 179) in equi image
+        assert_eq!(coordinate, Vector2::new(180, 179));
+    }
+    #[test]
+    fn back_center_should_point_to_start_of_equirectangular_image() {
+        // Assume 360 pixels wide, 180 pixels tall equi image
+        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &BACK);
+        // Front center pixel should point to (359, 90) in equi image
+        assert_eq!(coordinate, Vector2::new(359, 90));
+    }
+    #[test]
+    fn left_center_should_point_to_90_deg_of_equirectangular_image() {
+        // Assume 360 pixels wide, 180 pixels tall equi image
+        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &LEFT);
+        // Left center pixel should point to (90, 90) in equi image
+        assert_eq!(coordinate, Vector2::new(90, 90));
+    }
+    #[test]
+    fn right_center_should_point_to_270_deg_of_equirectangular_image() {
+        // Assume 360 pixels wide, 180 pixels tall equi image
+        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &RIGHT);
+        // Right center pixel should point to (270, 90) in equi image
+        assert_eq!(coordinate, Vector2::new(270, 90));
+    }
+    #[test]
+    fn ray_maps_of_tiles_match_their_face() {
+        let faces = Manifest::new(4, None, "npy");
+        let tiles = Manifest::new(4, Some(2), "npy");
+        let left = RayMap::Xyz.values(Face::Left, 4, &faces.views[2]);
+        // pixel (2, 2) of the face is the first pixel of its fourth tile
+        let tile = RayMap::Xyz.values(Face::Left, 4, &tiles.views[8 + 3]);
+        assert_eq!(&tile[..3], &left[(2 * 4 + 2) * 3..(2 * 4 + 2) * 3 + 3]);
+        assert_eq!(&tile[..3], &[LEFT.x, LEFT.y, LEFT.z]);
+        let latlon = RayMap::Latlon.values(Face::Left, 4, &tiles.views[8 + 3]);
+        assert_eq!(&latlon[..2], &[0.0, -90.0]);
+    }
+
+    #[test]
+    fn unselected_tiles_are_filled_on_load() {
+        let directory =
+            std::env::temp_dir().join(format!("equistitch-fill-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        // 12 tiles a row, tile colour tells its column and row
+        let face = |_| {
+            image::RgbaImage::from_fn(96, 96, |x, y| {
+                image::Rgba([(x / 8 * 20) as u8, (y / 8 * 20) as u8, 128, 255])
+            })
+        };
+        let cube = Cube::from_faces((0..6).map(face).collect()).unwrap();
+        let selection = Selection {
+            faces: vec![Face::Left],
+            tiles: Some(TileRegion {
+                first: Vector2::new(10, 0),
+                last: Vector2::new(11, 1),
+            }),
+            patch_size: 8,
+            whole_faces: false,
+        };
+        cube.save_patches(&directory.to_string_lossy(), 8, None, &selection)
+            .unwrap();
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 4);
+        let fill = image::Rgba([255, 0, 0, 255]);
+        let (loaded, missing) = Cube::load(&directory, "jpg", true, fill).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(missing.len(), 6 * 144 - 4);
+        assert!(matches!(
+            missing[0],
+            Error::MissingView {
+                face: Face::Front,
+                tile: Some(0)
+            }
+        ));
+        assert_eq!(loaded.front[(0, 0)], fill);
+        assert_eq!(loaded.left[(70, 12)], fill);
+        for (x, y) in [(84, 4), (92, 12)] {
+            let (expected, pixel) = (cube.left[(x, y)], loaded.left[(x, y)]);
+            for c in 0..3 {
+                assert!(
+                    (expected[c] as i32 - pixel[c] as i32).abs() <= 2,
+                    "{:?}",
+                    (x, y)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_round_trip_through_encoded_bytes() {
+        let face =
+            |f| image::RgbaImage::from_fn(32, 32, |x, y| image::Rgba([x as u8, y as u8, f, 255]));
+        let cube = Cube::from_faces((0..6).map(face).collect()).unwrap();
+        let encoded = cube
+            .tiles(16, &Selection::all())
+            .iter()
+            .map(|view| {
+                let bytes = view.encode(ImageFormat::Png, None).unwrap();
+                (view.file_name("png"), bytes)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(encoded.len(), 24);
+        assert_eq!(encoded[1].0, "front_p2_1.png");
+        let views = encoded
+            .iter()
+            .map(|(name, bytes)| CubeView::decode(name, bytes).unwrap())
+            .collect();
+        let decoded = Cube::from_views(views, image::Rgba([0, 0, 0, 0])).unwrap();
+        for face in Face::ALL {
+            assert!(decoded.face(face) == cube.face(face));
+        }
+        assert!(matches!(
+            CubeView::decode("front_p2_x.png", &encoded[0].1),
+            Err(Error::UnknownView(name)) if name == "front_p2_x.png"
+        ));
+    }
+
+    #[test]
+    fn errors_tell_missing_and_mismatched_views_apart() {
+        let directory =
+            std::env::temp_dir().join(format!("equistitch-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let fill = image::Rgba([0, 0, 0, 0]);
+        assert!(matches!(
+            Cube::load(&directory, "png", true, fill),
+            Err(Error::NoViews { tiles: true, .. })
+        ));
+        std::fs::remove_dir_all(&directory).unwrap();
+        let view = |tile, size| CubeView {
+            face: Face::Up,
+            tile: Some(tile),
+            face_size: 32,
+            image: image::RgbaImage::new(size, size),
+        };
+        match Cube::from_views(vec![view((2, 0), 16), view((2, 3), 8)], fill) {
+            Err(Error::Invalid(problems)) => match &problems[..] {
+                [Error::Dimensions {
+                    name,
+                    expected,
+                    found,
+                }] => {
+                    assert_eq!(name, "up_p2_3");
+                    assert_eq!((*expected, *found), ((16, 16), (8, 8)));
+                }
+                _ => panic!("expected one dimension mismatch, got {:?}", problems),
+            },
+            _ => panic!("expected dimension mismatch"),
+        }
+        assert!(matches!(
+            Cube::from_views(vec![view((2, 4), 16)], fill),
+            Err(Error::Invalid(problems)) if matches!(problems[..], [Error::Layout(_)])
+        ));
+        let faces =
+            Cube::from_faces((0..6).map(|f| image::RgbaImage::new(8, 8 + f)).collect()).unwrap();
+        assert!(matches!(
+            cube2equ(faces),
+            Err(Error::Invalid(problems)) if problems.len() == 5
+        ));
+        assert!(matches!(
+            Cube::from_faces(vec![image::RgbaImage::new(8, 8); 5]),
+            Err(Error::Layout(_))
+        ));
+    }
+
+    #[test]
+    fn npy_manifest_names_array_views() {
+        let faces = (0..6)
+            .map(|i| image::RgbaImage::from_pixel(4, 4, image::Rgba([i * 40, 0, 0, 255])))
+            .collect();
+        let cube = Cube::from_faces(faces).unwrap();
+        let path = std::env::temp_dir().join(format!("equistitch-npy-{}.npy", std::process::id()));
+        cube.save_npy(&path, Some(2), crate::PixelType::Uint8, &Selection::all())
+            .unwrap();
+        let manifest = Manifest::load(&path.with_extension("json")).unwrap();
+        let loaded = Cube::from_npy(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+        assert_eq!(manifest.views[1].name, "front_p2_1.npy");
+        assert_eq!(loaded.face(Face::Back), cube.face(Face::Back));
+    }
+
+    #[test]
+    fn panoramas_narrower_than_four_pixels_are_refused() {
+        let area = CroppedArea::full(Vector2::new(3, 2));
+        assert!(matches!(area.face_size(), Err(Error::Layout(_))));
+        assert_eq!(crate::cube2equ_source_pixel(0, 0, 0), None);
+        let empty = Cube::from_faces(vec![image::RgbaImage::new(0, 0); 6]).unwrap();
+        assert!(matches!(cube2equ(empty), Err(Error::Layout(_))));
+    }
+
+    #[test]
+    fn output_does_not_depend_on_thread_count() {
+        let equ = image::RgbaImage::from_fn(64, 32, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 8) as u8, (x ^ y) as u8, 255])
+        });
+        let layout = EquLayout {
+            area: CroppedArea::full(Vector2::new(64, 32)),
+            orientation: None,
+        };
+        let render = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| {
+                    let cube = equ2cube(
+                        image::DynamicImage::ImageRgba8(equ.clone()),
+                        16,
+                        &layout,
+                        &Selection::all(),
+                    )
+                    .unwrap();
+                    let faces = Face::ALL
+                        .iter()
+                        .map(|f| cube.face(*f).clone())
+                        .collect::<Vec<_>>();
+                    (faces, cube2equ(cube).unwrap())
+                })
+        };
+        assert!(render(1) == render(4));
+    }
+}
@@ -1,17 +1,46 @@
 extern crate image;
 
 use anyhow::{self};
-use cgmath::{InnerSpace, Vector2, Vector3};
-use clap::{Parser, Subcommand};
-use image::{DynamicImage, GenericImage, GenericImageView, ImageBuffer, Rgba, RgbaImage};
-use std::path::PathBuf;
-use std::{f32::consts::PI, fs};
+use cgmath::Vector2;
+use clap::{Args, Parser, Subcommand};
+use image::{GenericImageView, ImageFormat, Rgba};
+use rayon::prelude::*;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use equistitch::{
+    annotations, crop_equ, cube2equ, decode_with_metadata, equ2cube, equ_source_pixel,
+    face_pixel2ray, formats, load_with_metadata, mask, merge, metadata, save_view, tensor,
+    validate, xmp, ArchiveFormat, Cube, CubeFiles, CubeView, EquLayout, FaceExif, InputMetadata,
+    PixelType, RayMap,
+};
+
+mod batch;
+mod stream;
+
+use equistitch::depth::DepthCube;
+use equistitch::formats::AnnotationFormat;
+use equistitch::manifest::Manifest;
+use equistitch::mask::{MaskCube, MaskSampling};
+use equistitch::merge::MergeMethod;
+use equistitch::metadata::SourceExif;
+use equistitch::projection::{
+    reproject, Basis, CroppedArea, Equirectangular, Face, ProjectionKind, ProjectionParams,
+};
+use equistitch::remap::{Interpolation, RemapKey, RemapTable};
+use equistitch::selection::{Selection, TileRegion};
+use equistitch::tensor::Tensor;
+use equistitch::vectors::VectorCube;
 
 /// Equistitch is utility for manipulating 360-degree equirectangular images
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-
+    /// Worker threads for projection and image encoding, 0 uses all cores. Output does not
+    /// depend on it.
+    #[clap(long, global = true, default_value_t = 0)]
+    threads: usize,
     #[clap(subcommand)]
     command: Option<Commands>,
 }
@@ -19,538 +48,1319 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Splits equirectangular image into cubemap faces (up, down, left, right, front, back) and in addition to tiles (with defined patch size)
-    Split {
-        /// Input file (image)
-        #[clap(short, long, parse(from_os_str), value_name = "FILE")]
-        input: PathBuf,
-        /// Size for tiles
-        #[clap(short, long, default_value_t = 480)]
-        patch_size: u32,
-        /// Output directory for cubemap faces
-        #[clap(short, long, parse(from_os_str), value_name = "CUBEMAP_OUTPUT")]
-        cubemap_faces_output: Option<PathBuf>,
-        /// Output directory for tiles
-        #[clap(short, long, parse(from_os_str), value_name = "TILES_OUTPUT")]
-        tiles_output: Option<PathBuf>,
+    Split(SplitArgs),
+    /// Splits many panoramas like split in one process. Input is a directory (searched
+    /// recursively), a glob pattern (`*`, `?`, `**`) or a file list, every panorama gets a
+    /// folder in the output root mirroring its directory and the output paths are taken
+    /// relative to that folder.
+    Batch {
+        #[clap(flatten)]
+        split: SplitArgs,
+        /// Input is a text file listing one panorama per line
+        #[clap(long)]
+        file_list: bool,
+        /// Root of the per-panorama output folders
+        #[clap(long, parse(from_os_str), value_name = "OUTPUT_ROOT")]
+        output_root: PathBuf,
+        /// Skip panoramas completed by an earlier run
+        #[clap(long)]
+        resume: bool,
+        /// JSON report listing split, skipped and failed panoramas
+        #[clap(long, parse(from_os_str), value_name = "REPORT")]
+        report: Option<PathBuf>,
+    },
+    /// Splits equirectangular image like split and maps its annotations onto every face and tile they are visible in
+    SplitAnnotations {
+        #[clap(flatten)]
+        split: SplitArgs,
+        /// Panorama annotations in JSON, list of {"label": "car", "bbox": [x_min, y_min, x_max, y_max]} or {"label": "car", "polygon": [[x, y], ...]}
+        #[clap(short, long, parse(from_os_str), value_name = "ANNOTATIONS")]
+        annotations: PathBuf,
+        /// Format of panorama annotations (YOLO and VOC read a directory)
+        #[clap(long, arg_enum, default_value = "json")]
+        input_format: AnnotationFormat,
+        /// Output JSON for face and tile annotations
+        #[clap(
+            short = 'o',
+            long,
+            parse(from_os_str),
+            value_name = "ANNOTATIONS_OUTPUT"
+        )]
+        annotations_output: PathBuf,
+        /// Format of face and tile annotations (YOLO and VOC write a directory)
+        #[clap(long, arg_enum, default_value = "json")]
+        output_format: AnnotationFormat,
+        /// Drop fragments showing less than this fraction of the annotation (by solid angle)
+        #[clap(long, default_value_t = 0.2)]
+        min_visible: f32,
     },
     /// Stitches cubemap faces (up, down, left, right, front, back) or patches back into equirectangular image
     Stitch {
-        /// Input directory (tiles or cubemap faces)
+        /// Input directory (tiles or cubemap faces) or .npy array written by split, `-` reads
+        /// a tar or zip archive of faces or tiles from stdin
         #[clap(short, long, parse(from_os_str), value_name = "INPUT_DIR")]
         input_dir: PathBuf,
-        /// Output file
+        /// Output file, `-` writes the image to stdout
         #[clap(short, long, parse(from_os_str), value_name = "OUTPUT")]
         output: PathBuf,
+        /// Image format written to stdout (jpg, png, tif, ...)
+        #[clap(long, default_value = "png")]
+        output_format: String,
         /// Stitch tiles
         #[clap(short, long)]
         tiles: bool,
-        /// File extension
+        /// File extension of tiles (jpg, png for masks)
+        #[clap(short, long)]
+        extension: Option<String>,
+        #[clap(flatten)]
+        coverage: Coverage,
+        /// Compass heading of the output image center written into GPano metadata
+        #[clap(long, allow_hyphen_values = true)]
+        pose_heading: Option<f32>,
+        /// Image (usually the original panorama) whose EXIF is copied into the output
+        #[clap(short, long, parse(from_os_str), value_name = "REFERENCE")]
+        reference: Option<PathBuf>,
+        #[clap(flatten)]
+        mask: MaskArgs,
+        /// Directory of confidence maps named like the mask faces or tiles, weights the vote
+        #[clap(
+            long,
+            parse(from_os_str),
+            value_name = "CONFIDENCE_DIR",
+            requires = "mask"
+        )]
+        confidence: Option<PathBuf>,
+        /// Input faces or tiles are per-class probability (or logit) maps as H×W×C .npy arrays,
+        /// output is a float32 .npy array
+        #[clap(long, conflicts_with = "mask")]
+        probabilities: bool,
+        /// Input faces or tiles are float32 z-depth .npy arrays (as written by `split --depth`),
+        /// output is a float32 .npy array of distances along the rays
+        #[clap(long, conflicts_with_all = &["mask", "probabilities"])]
+        depth: bool,
+        /// Input faces or tiles are float32 H×W×3 .npy vector fields in the face camera frame
+        /// (as written by `split --vectors`), output is a float32 .npy array of world vectors
+        #[clap(long, conflicts_with_all = &["mask", "probabilities", "depth"])]
+        vectors: bool,
+        /// Lookup table of face pixels for the panorama, reused when it was built for the same
+        /// face size and rebuilt otherwise
+        #[clap(long, parse(from_os_str), value_name = "FILE")]
+        remap_table: Option<PathBuf>,
+        /// Colour of missing faces and tiles, `transparent` or hex `RRGGBB` / `RRGGBBAA`
+        #[clap(long, parse(try_from_str = parse_fill), default_value = "transparent")]
+        fill: Rgba<u8>,
+        /// Field of view of probability map faces, faces wider than 90 degrees overlap and are
+        /// averaged weighted by distance from the face edge
+        #[clap(long, default_value_t = 90.0)]
+        face_fov: f32,
+        /// Memory budget in MB, PNG output is written in strips holding only the faces or
+        /// tiles they need
+        #[clap(
+            long,
+            value_name = "MB",
+            conflicts_with_all = &["mask", "probabilities", "depth", "vectors", "remap-table"]
+        )]
+        max_memory: Option<usize>,
+    },
+    /// Checks faces or tiles written by split before stitching, lists every missing, duplicate,
+    /// misnamed or wrongly sized view
+    Validate {
+        /// Input directory (tiles or cubemap faces)
+        #[clap(short, long, parse(from_os_str), value_name = "INPUT_DIR")]
+        input_dir: PathBuf,
+        /// Validate tiles
+        #[clap(short, long)]
+        tiles: bool,
+        /// File extension of faces or tiles
         #[clap(short, long, default_value = "jpg")]
         extension: String,
     },
+    /// Maps bounding boxes detected on faces or tiles back onto the equirectangular image
+    ProjectAnnotations {
+        /// Detections in JSON, list of {"file": "front_p4_3.jpg", "bbox": [x_min, y_min, x_max, y_max], "label": "car", "score": 0.9}
+        #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+        input: PathBuf,
+        /// Format of detections (YOLO and VOC read a directory)
+        #[clap(long, arg_enum, default_value = "json")]
+        input_format: AnnotationFormat,
+        /// Output JSON with equirectangular polygons and bounding boxes
+        #[clap(short, long, parse(from_os_str), value_name = "OUTPUT")]
+        output: PathBuf,
+        /// Format of panorama labels, boxes crossing the seam are split in two for COCO, YOLO and VOC
+        #[clap(long, arg_enum, default_value = "json")]
+        output_format: AnnotationFormat,
+        /// Panorama file name the labels refer to in COCO, YOLO and VOC output
+        #[clap(long, default_value = "panorama.jpg")]
+        panorama_name: String,
+        /// Panorama the faces and tiles were split from, its size, GPano pose and cropped area
+        /// place the detections where split rendered them
+        #[clap(long, parse(from_os_str), value_name = "PANORAMA")]
+        panorama: Option<PathBuf>,
+        /// Width of the equirectangular image when no panorama is given (faces are a quarter of
+        /// it)
+        #[clap(
+            short,
+            long,
+            required_unless_present = "panorama",
+            conflicts_with = "panorama"
+        )]
+        width: Option<u32>,
+        /// Height of the equirectangular image (defaults to half of width)
+        #[clap(long, conflicts_with = "panorama")]
+        height: Option<u32>,
+        #[clap(flatten)]
+        coverage: Coverage,
+        /// Detections are on faces split with --ignore-pose
+        #[clap(long)]
+        ignore_pose: bool,
+        /// Tile size used in split
+        #[clap(short, long, default_value_t = 480)]
+        patch_size: u32,
+        /// Points sampled along each box edge for the warped polygon
+        #[clap(long, default_value_t = 8)]
+        edge_points: u32,
+        /// Merge duplicates of the same object seen on overlapping faces and tiles
+        #[clap(long, arg_enum)]
+        merge: Option<MergeMethod>,
+        /// Detections of the same label overlapping more than this (IoU on the sphere) are merged
+        #[clap(long, default_value_t = 0.5)]
+        iou_threshold: f32,
+    },
+    /// Converts image between any two supported projections
+    Convert {
+        /// Input file (image)
+        #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+        input: PathBuf,
+        /// Output file
+        #[clap(short, long, parse(from_os_str), value_name = "OUTPUT")]
+        output: PathBuf,
+        /// Projection of the input image
+        #[clap(long, arg_enum, default_value = "equirectangular")]
+        from: ProjectionKind,
+        #[clap(flatten)]
+        coverage: Coverage,
+        /// Projection of the output image
+        #[clap(long, arg_enum)]
+        to: ProjectionKind,
+        /// Field of view of the input image in degrees (not used for equirectangular or cube)
+        #[clap(long, default_value_t = 90.0)]
+        source_fov: f32,
+        /// Cube face of the input image
+        #[clap(long, arg_enum, default_value = "front")]
+        source_face: Face,
+        /// Output width (defaults to input width)
+        #[clap(long)]
+        width: Option<u32>,
+        /// Output height (defaults to input height)
+        #[clap(long)]
+        height: Option<u32>,
+        /// Field of view of the output image in degrees (not used for equirectangular or cube)
+        #[clap(long, default_value_t = 90.0)]
+        fov: f32,
+        /// Cube face of the output image
+        #[clap(long, arg_enum, default_value = "front")]
+        face: Face,
+        /// Output view direction, degrees to the right
+        #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+        yaw: f32,
+        /// Output view direction, degrees up
+        #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+        pitch: f32,
+        /// Output view rotation around viewing axis in degrees
+        #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+        roll: f32,
+    },
 }
 
-struct Cube {
-    front: RgbaImage,
-    back: RgbaImage,
-    left: RgbaImage,
-    right: RgbaImage,
-    up: RgbaImage,
-    down: RgbaImage,
+#[derive(Args, Clone)]
+struct SplitArgs {
+    /// Input file (image), `-` reads it from stdin
+    #[clap(short, long, parse(from_os_str), value_name = "FILE")]
+    input: PathBuf,
+    /// Size for tiles
+    #[clap(short, long, default_value_t = 480)]
+    patch_size: u32,
+    /// Output directory for cubemap faces, `-` writes them to stdout as an archive
+    #[clap(short, long, parse(from_os_str), value_name = "CUBEMAP_OUTPUT")]
+    cubemap_faces_output: Option<PathBuf>,
+    /// Output directory for tiles, `-` writes them to stdout as an archive
+    #[clap(short, long, parse(from_os_str), value_name = "TILES_OUTPUT")]
+    tiles_output: Option<PathBuf>,
+    /// Archive of faces or tiles written to stdout
+    #[clap(long, arg_enum, default_value = "tar")]
+    archive: ArchiveFormat,
+    #[clap(flatten)]
+    coverage: Coverage,
+    #[clap(flatten)]
+    views: ViewArgs,
+    /// Do not level and north-align faces using GPano pose (heading, pitch, roll) of the input
+    #[clap(long)]
+    ignore_pose: bool,
+    /// Output file for all faces (or tiles with --npy-tiles) as one N×H×W×3 NumPy array,
+    /// a JSON manifest listing the views is written next to it
+    #[clap(long, parse(from_os_str), value_name = "NPY_OUTPUT")]
+    npy_output: Option<PathBuf>,
+    /// Store tiles instead of faces in the NumPy array
+    #[clap(long, requires = "npy-output")]
+    npy_tiles: bool,
+    /// Element type of the NumPy array
+    #[clap(long, arg_enum, default_value = "uint8")]
+    npy_type: PixelType,
+    /// Write direction of every pixel next to each output as float32 .npy
+    /// (`front_rays.npy`, `front_p4_3_rays.npy`, `tiles_rays.npy`)
+    #[clap(long, arg_enum)]
+    ray_maps: Option<RayMap>,
+    #[clap(flatten)]
+    mask: MaskArgs,
+    /// Input is a float32 .npy map (H×W or H×W×1) of distances along the rays, faces and tiles
+    /// are written as float32 .npy planar z-depth. The array has no pose, give the paired RGB
+    /// panorama with --pose-from to keep them aligned.
+    #[clap(long, conflicts_with = "mask")]
+    depth: bool,
+    /// Input is a float32 H×W×3 .npy field of world vectors (x east, y north, z up) such as
+    /// surface normals, faces and tiles get the vectors rotated into their camera frame
+    /// (x right, y down, z forward)
+    #[clap(long, conflicts_with_all = &["mask", "depth"])]
+    vectors: bool,
+    /// Panorama whose GPano pose and cropped area lay out the --depth or --vectors input,
+    /// faces line up with those split from that panorama
+    #[clap(long, parse(from_os_str), value_name = "PANORAMA")]
+    pose_from: Option<PathBuf>,
+    /// Lookup table of source pixels for the faces, reused when it was built for the same
+    /// image size, coverage, pose and interpolation and rebuilt otherwise
+    #[clap(long, parse(from_os_str), value_name = "FILE")]
+    remap_table: Option<PathBuf>,
+    /// How image faces sample the panorama (masks, depth and vectors are always nearest)
+    #[clap(long, arg_enum, default_value = "nearest")]
+    interpolation: Interpolation,
+    /// Memory budget in MB, input must be non-interlaced PNG, read row by row in as many
+    /// passes as the faces and tiles need to fit
+    #[clap(
+        long,
+        value_name = "MB",
+        conflicts_with_all = &["npy-output", "remap-table", "mask", "depth", "vectors"]
+    )]
+    max_memory: Option<usize>,
 }
 
-impl Cube {
-    pub fn from_directory_of_patches(prefix: &str, extension: &str) -> anyhow::Result<Cube> {
-        let ext = format!(".{}", extension);
-        let mut all_files = fs::read_dir(prefix)?
-            .filter_map(|res| -> Option<(String, ImageBuffer<Rgba<u8>, Vec<u8>>)> {
-                let e = res.ok()?;
-                let filename = e.file_name().into_string().ok()?;
-                if !filename.to_lowercase().contains(&ext) {
-                    return None;
-                }
-                Some((filename, image::open(e.path()).ok()?.into_rgba8()))
-            })
-            .collect::<Vec<(String, RgbaImage)>>();
+/// Segmentation masks (single channel 8 or 16 bit label images) are sampled without blending
+/// labels and written as PNG
+#[derive(Args, Clone)]
+struct MaskArgs {
+    /// Input is a segmentation mask
+    #[clap(long)]
+    mask: bool,
+    /// How mask labels are sampled
+    #[clap(long, arg_enum, default_value = "vote")]
+    mask_sampling: MaskSampling,
+}
 
-        all_files.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+/// Faces and tiles to render, the rest is neither computed nor written
+#[derive(Args, Clone)]
+struct ViewArgs {
+    /// Faces to write, comma separated (all by default)
+    #[clap(long, arg_enum, value_delimiter = ',', value_name = "FACES")]
+    faces: Vec<Face>,
+    /// Tiles to write on every face, first and last column and row (inclusive, 0 is the top
+    /// left tile). Faces are still written whole when a cubemap output is given.
+    #[clap(
+        long,
+        number_of_values = 4,
+        value_names = &["FIRST_COLUMN", "FIRST_ROW", "LAST_COLUMN", "LAST_ROW"]
+    )]
+    tile_region: Option<Vec<u32>>,
+}
 
-        let front_files = all_files
-            .iter()
-            .filter(|(filename, _path)| filename.contains("front_"))
-            .collect::<Vec<&(String, RgbaImage)>>();
-        let (first_file, _) = front_files
-            .first()
-            .ok_or(anyhow::anyhow!("Front patches missing"))?;
-        let patch_count = first_file
-            .replace(&ext, "")
-            .replace("front_p", "")
-            .split_once("_")
-            .ok_or(anyhow::anyhow!("Failed to parse tile count"))?
-            .0
-            .parse::<u32>()?;
+/// Part of the sphere covered by a partial equirectangular panorama.
+/// Read from GPano XMP of the input when not given.
+#[derive(Args, Clone)]
+struct Coverage {
+    /// Covered longitudes in degrees, west and east edge (0 is front, -180 and 180 are back)
+    #[clap(long, number_of_values = 2, allow_hyphen_values = true, value_names = &["WEST", "EAST"])]
+    longitude: Option<Vec<f32>>,
+    /// Covered latitudes in degrees, south and north edge (-90 is down, 90 is up)
+    #[clap(long, number_of_values = 2, allow_hyphen_values = true, value_names = &["SOUTH", "NORTH"])]
+    latitude: Option<Vec<f32>>,
+}
 
-        let front_patches = front_files
-            .iter()
-            .map(|(_, im)| im.to_owned())
-            .collect::<Vec<_>>();
-        let back_patches = all_files
-            .iter()
-            .filter_map(|(fname, im)| {
-                if fname.contains("back_") {
-                    Some(im.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let left_patches = all_files
-            .iter()
-            .filter_map(|(fname, im)| {
-                if fname.contains("left_") {
-                    Some(im.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let right_patches = all_files
-            .iter()
-            .filter_map(|(fname, im)| {
-                if fname.contains("right_") {
-                    Some(im.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let up_patches = all_files
-            .iter()
-            .filter_map(|(fname, im)| {
-                if fname.contains("up_") {
-                    Some(im.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        let down_patches = all_files
-            .iter()
-            .filter_map(|(fname, im)| {
-                if fname.contains("down_") {
-                    Some(im.to_owned())
-                } else {
-                    None
-                }
-            })
-            .collect();
-        Ok(Cube {
-            front: stitch_image(&front_patches, patch_count)?,
-            back: stitch_image(&back_patches, patch_count)?,
-            left: stitch_image(&left_patches, patch_count)?,
-            right: stitch_image(&right_patches, patch_count)?,
-            up: stitch_image(&up_patches, patch_count)?,
-            down: stitch_image(&down_patches, patch_count)?,
-        })
-    }
-    pub fn from_directory(prefix: &str) -> anyhow::Result<Cube> {
-        Ok(Cube {
-            front: image::open(&format!("{}/front.jpg", prefix))?.into_rgba8(),
-            back: image::open(&format!("{}/back.jpg", prefix))?.into_rgba8(),
-            left: image::open(&format!("{}/left.jpg", prefix))?.into_rgba8(),
-            right: image::open(&format!("{}/right.jpg", prefix))?.into_rgba8(),
-            up: image::open(&format!("{}/up.jpg", prefix))?.into_rgba8(),
-            down: image::open(&format!("{}/down.jpg", prefix))?.into_rgba8(),
-        })
+impl Coverage {
+    fn ranges(&self) -> Option<((f32, f32), (f32, f32))> {
+        if self.longitude.is_none() && self.latitude.is_none() {
+            return None;
+        }
+        let range = |values: &Option<Vec<f32>>, default: (f32, f32)| {
+            values.as_ref().map_or(default, |v| (v[0], v[1]))
+        };
+        Some((
+            range(&self.longitude, (-180.0, 180.0)),
+            range(&self.latitude, (-90.0, 90.0)),
+        ))
     }
-    pub fn save(&self, prefix: &str) -> anyhow::Result<()> {
-        self.front.save(format!("{}/front.jpg", prefix))?;
-        self.back.save(format!("{}/back.jpg", prefix))?;
-        self.left.save(format!("{}/left.jpg", prefix))?;
-        self.right.save(format!("{}/right.jpg", prefix))?;
-        self.up.save(format!("{}/up.jpg", prefix))?;
-        self.down.save(format!("{}/down.jpg", prefix))?;
-        Ok(())
+
+    /// Covered area of an image with given dimensions and XMP metadata
+    fn area_of_image(
+        &self,
+        dimensions: Vector2<u32>,
+        gpano: Option<&xmp::GPano>,
+    ) -> anyhow::Result<CroppedArea> {
+        if let Some((longitude, latitude)) = self.ranges() {
+            return Ok(CroppedArea::from_degrees(dimensions, longitude, latitude)?);
+        }
+        match gpano.and_then(|g| g.cropped_area) {
+            Some(area) if Vector2::new(area.width, area.height) == dimensions => Ok(area),
+            Some(area) => {
+                eprintln!(
+                    "[main]: Warning, XMP cropped area {}x{} does not match image, scaling it",
+                    area.width, area.height
+                );
+                let scale = dimensions.x as f32 / area.width as f32;
+                let scaled = |v: u32| (v as f32 * scale).round() as u32;
+                Ok(CroppedArea {
+                    full_width: scaled(area.full_width),
+                    full_height: scaled(area.full_height),
+                    left: scaled(area.left),
+                    top: scaled(area.top),
+                    width: dimensions.x,
+                    height: dimensions.y,
+                })
+            }
+            None => Ok(CroppedArea::full(dimensions)),
+        }
     }
+}
 
-    pub fn save_patches(&self, prefix: &str, patch_size: u32) -> anyhow::Result<()> {
-        let (front_patches, front_pieces) = split_image(&self.front, patch_size);
-        front_patches.iter().enumerate().try_for_each(|(i, p)| {
-            p.save(format!("{}/front_p{}_{}.jpg", prefix, front_pieces, i))
-        })?;
-        // back
-        let (back_patches, back_pieces) = split_image(&self.back, patch_size);
-        back_patches
-            .iter()
-            .enumerate()
-            .try_for_each(|(i, p)| p.save(format!("{}/back_p{}_{}.jpg", prefix, back_pieces, i)))?;
-        // left
-        let (left_patches, left_pieces) = split_image(&self.left, patch_size);
-        left_patches
-            .iter()
-            .enumerate()
-            .try_for_each(|(i, p)| p.save(format!("{}/left_p{}_{}.jpg", prefix, left_pieces, i)))?;
-        // right
-        let (right_patches, right_pieces) = split_image(&self.right, patch_size);
-        right_patches.iter().enumerate().try_for_each(|(i, p)| {
-            p.save(format!("{}/right_p{}_{}.jpg", prefix, right_pieces, i))
-        })?;
-        // up
-        let (up_patches, up_pieces) = split_image(&self.up, patch_size);
-        up_patches
-            .iter()
-            .enumerate()
-            .try_for_each(|(i, p)| p.save(format!("{}/up_p{}_{}.jpg", prefix, up_pieces, i)))?;
-        // down
-        let (down_patches, down_pieces) = split_image(&self.down, patch_size);
-        down_patches
-            .iter()
-            .enumerate()
-            .try_for_each(|(i, p)| p.save(format!("{}/down_p{}_{}.jpg", prefix, down_pieces, i)))?;
+impl SplitArgs {
+    /// Extension of the face and tile files written: NumPy for depth and vectors, PNG for
+    /// masks and JPEG for images
+    fn extension(&self) -> &'static str {
+        if self.depth || self.vectors {
+            "npy"
+        } else if self.mask.mask {
+            "png"
+        } else {
+            "jpg"
+        }
+    }
 
-        Ok(())
+    /// Faces and tiles to render and write
+    fn selection(&self) -> anyhow::Result<Selection> {
+        let tiles = match self.views.tile_region.as_deref() {
+            Some(&[first_column, first_row, last_column, last_row]) => {
+                if first_column > last_column || first_row > last_row {
+                    return Err(anyhow::anyhow!(
+                        "Tile region ends before it starts: {:?}",
+                        self.views.tile_region
+                    ));
+                }
+                Some(TileRegion {
+                    first: Vector2::new(first_column, first_row),
+                    last: Vector2::new(last_column, last_row),
+                })
+            }
+            _ => None,
+        };
+        Ok(Selection {
+            faces: match self.views.faces.is_empty() {
+                true => Face::ALL.to_vec(),
+                false => self.views.faces.clone(),
+            },
+            tiles,
+            patch_size: self.patch_size,
+            whole_faces: self.cubemap_faces_output.is_some()
+                || (self.npy_output.is_some() && !self.npy_tiles),
+        })
     }
 }
 
-static FRONT: Vector3<f32> = Vector3::new(0.0, 1.0, 0.0);
-static BACK: Vector3<f32> = Vector3::new(0.0, -1.0, 0.0);
-static LEFT: Vector3<f32> = Vector3::new(-1.0, 0.0, 0.0);
-static RIGHT: Vector3<f32> = Vector3::new(1.0, 0.0, 0.0);
-static UP: Vector3<f32> = Vector3::new(0.0, 0.0, 1.0);
-static DOWN: Vector3<f32> = Vector3::new(0.0, 0.0, -1.0);
+/// Parses `--fill`, `transparent` or hex `RRGGBB` / `RRGGBBAA`
+fn parse_fill(value: &str) -> Result<Rgba<u8>, String> {
+    if value == "transparent" {
+        return Ok(Rgba([0, 0, 0, 0]));
+    }
+    let hex = value.trim_start_matches('#');
+    let channel = |i: usize| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok();
+    match hex.len() {
+        6 | 8 => {
+            let alpha = if hex.len() == 8 {
+                channel(3)
+            } else {
+                Some(255)
+            };
+            match (channel(0), channel(1), channel(2), alpha) {
+                (Some(r), Some(g), Some(b), Some(a)) => Ok(Rgba([r, g, b, a])),
+                _ => Err(format!("Invalid hex colour {}", value)),
+            }
+        }
+        _ => Err(format!(
+            "Expected transparent, RRGGBB or RRGGBBAA, got {}",
+            value
+        )),
+    }
+}
 
-fn split_image(im: &RgbaImage, patch_size: u32) -> (Vec<RgbaImage>, u32) {
-    let (width, height) = im.dimensions();
-    let x_pieces = width / patch_size;
-    let y_pieces = height / patch_size;
-    let mut patches = vec![];
-    for px in 0..x_pieces {
-        for py in 0..y_pieces {
-            patches.push(
-                im.view(px * patch_size, py * patch_size, patch_size, patch_size)
-                    .to_image(),
-            );
+/// Writes `manifest.json` with the camera of every view into the face and tile directories
+fn save_manifests(args: &SplitArgs, face_size: u32) -> anyhow::Result<()> {
+    let outputs = [
+        (&args.cubemap_faces_output, None),
+        (&args.tiles_output, Some(args.patch_size)),
+    ];
+    let selection = args.selection()?;
+    for (directory, patch_size) in outputs {
+        // archives on stdout carry their own manifest
+        if let Some(directory) = directory.as_ref().filter(|d| !is_stdio(d)) {
+            let mut manifest = Manifest::new(face_size, patch_size, args.extension());
+            manifest.views.retain(|view| selection.manifest_view(view));
+            manifest.save(&directory.join("manifest.json"))?;
         }
     }
-    (patches, x_pieces)
+    Ok(())
 }
 
-fn stitch_image(patches: &Vec<RgbaImage>, x_pieces: u32) -> anyhow::Result<RgbaImage> {
-    let example_patch = patches
-        .first()
-        .ok_or(anyhow::anyhow!("No patches to stitch"))?;
-    let (width, height) = example_patch.dimensions();
-    let full_width = width * x_pieces;
-    let y_pieces = patches.len() as u32 / x_pieces;
-    let full_height = height * y_pieces;
-    let mut full = image::ImageBuffer::new(full_width, full_height);
-    let mut patches_it = patches.iter();
-    for px in 0..x_pieces {
-        for py in 0..y_pieces {
-            full.copy_from(
-                patches_it
-                    .next()
-                    .ok_or(anyhow::anyhow!("not single patch"))?,
-                px * width,
-                py * height,
-            )?;
+/// Writes ray maps for faces, tiles and the NumPy array requested in `args`
+fn save_ray_maps(args: &SplitArgs, kind: RayMap, face_size: u32) -> anyhow::Result<()> {
+    let outputs = [
+        (&args.cubemap_faces_output, None),
+        (&args.tiles_output, Some(args.patch_size)),
+    ];
+    let selection = args.selection()?;
+    for (directory, patch_size) in outputs {
+        if let Some(directory) = directory {
+            let views = Manifest::new(face_size, patch_size, "npy").views;
+            for view in views.iter().filter(|view| selection.manifest_view(view)) {
+                let stem = view.name.trim_end_matches(".npy");
+                let path = directory.join(format!("{}_rays.npy", stem));
+                kind.save(&path, face_size, std::slice::from_ref(view), false)?;
+            }
         }
     }
-    Ok(full)
+    if let Some(output) = &args.npy_output {
+        let mut manifest =
+            Manifest::new(face_size, args.npy_tiles.then_some(args.patch_size), "npy");
+        manifest.views.retain(|view| selection.manifest_view(view));
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        let path = output.with_file_name(format!("{}_rays.npy", stem));
+        kind.save(&path, face_size, &manifest.views, true)?;
+    }
+    Ok(())
+}
+
+/// How the input of `split` is laid on the sphere and the EXIF its faces get
+fn input_layout(
+    args: &SplitArgs,
+    dimensions: Vector2<u32>,
+    InputMetadata { gpano, exif, .. }: InputMetadata,
+) -> anyhow::Result<(EquLayout, Option<FaceExif>)> {
+    let area = args.coverage.area_of_image(dimensions, gpano.as_ref())?;
+    if !area.is_full() {
+        eprintln!(
+            "[main]: Partial panorama, {}x{} at ({}, {}) of {}x{}",
+            area.width, area.height, area.left, area.top, area.full_width, area.full_height
+        );
+    }
+    let orientation = gpano
+        .as_ref()
+        .and_then(|g| g.orientation())
+        .filter(|_| !args.ignore_pose);
+    if orientation.is_some() {
+        eprintln!("[main]: Orienting faces using GPano pose");
+    }
+    let face_exif = exif.map(|exif| FaceExif {
+        // oriented faces are already north aligned
+        front_heading: match orientation {
+            Some(_) => 0.0,
+            None => gpano
+                .as_ref()
+                .and_then(|g| g.pose_heading)
+                .or_else(|| exif.heading())
+                .unwrap_or(0.0),
+        },
+        exif,
+    });
+    Ok((EquLayout { area, orientation }, face_exif))
 }
 
-fn face_pixel2ray(
-    center: &Vector3<f32>,
-    down: &Vector3<f32>,
-    right: &Vector3<f32>,
-    pixel: &Vector2<u32>,
-    dimensions: &Vector2<u32>,
-) -> Vector3<f32> {
-    let origo2d = center - right - down; // we want to head to upper left corner thats why multiply with -0.5
-    let x_scaled = (pixel.x as f32) / (dimensions.x as f32);
-    let y_scaled = (pixel.y as f32) / (dimensions.y as f32);
+/// Remap table cached at `path`, rebuilt when it was built for another key
+fn cached_table(
+    path: &Path,
+    key: &RemapKey,
+    build: impl FnOnce() -> RemapTable,
+) -> anyhow::Result<RemapTable> {
+    let existed = path.exists();
+    let (table, reused) = RemapTable::cached(path, key, build)?;
+    if reused {
+        eprintln!("[main]: Using remap table {}", path.display());
+    } else if existed {
+        eprintln!(
+            "[main]: Remap table {} does not match, rebuilding",
+            path.display()
+        );
+    }
+    Ok(table)
+}
 
-    let point_in_face = origo2d + right * x_scaled * 2.0 + down * y_scaled * 2.0;
-    point_in_face.normalize()
+/// `-` in place of a path names stdin or stdout
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
 }
 
-fn ray2equ_pixel(dimensions: &Vector2<u32>, ray: &Vector3<f32>) -> Vector2<u32> {
-    // 2d angles:
-    // x-direction (longitude)
-    let longitude = PI + ray.x.atan2(ray.y); // will be between 0 to 2pi
-    let pixel_x = (dimensions.x as f32 / (PI * 2.0)) * longitude;
-    //let pixel_x = ((longitude / PI)*(dimensions.x as f32));
-    // y-direction (latitude)
-    let latitude = ray.z.acos(); // will be between 0 to pi
-    let pixel_y = (dimensions.y as f32 / PI) * (latitude);
+fn read_stdin() -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
 
-    Vector2::new(pixel_x as u32, pixel_y as u32)
+/// Writes faces or tiles as JPEG with their manifest to stdout as one archive
+fn write_views_archive(
+    args: &SplitArgs,
+    views: &[CubeView],
+    patch_size: Option<u32>,
+    face_size: u32,
+    exif: Option<&FaceExif>,
+) -> anyhow::Result<()> {
+    let selection = args.selection()?;
+    let mut manifest = Manifest::new(face_size, patch_size, "jpg");
+    manifest.views.retain(|view| selection.manifest_view(view));
+    let mut entries = views
+        .par_iter()
+        .map(|view| Ok((view.file_name("jpg"), view.encode(ImageFormat::Jpeg, exif)?)))
+        .collect::<Result<Vec<_>, equistitch::Error>>()?;
+    entries.push((
+        "manifest.json".to_string(),
+        serde_json::to_vec_pretty(&manifest)?,
+    ));
+    equistitch::write_archive(args.archive, &entries, &mut io::stdout().lock())?;
+    Ok(())
 }
-// equi -> cube
-// 1. for each cube face pixel to xyz-vector from center
-// 2. normalize
-// 3. get pixel value using the xyz-vector
-fn equ2cube(source: DynamicImage, face_size: u32) -> anyhow::Result<Cube> {
-    let _v = Vector3::new(1.0, 2.0, 3.0);
+
+/// Fails on stdin and stdout used where only files and directories work
+fn check_stdio(args: &SplitArgs) -> anyhow::Result<()> {
+    let outputs = [&args.cubemap_faces_output, &args.tiles_output]
+        .into_iter()
+        .flatten()
+        .filter(|d| is_stdio(d))
+        .count();
+    if outputs > 1 {
+        return Err(anyhow::anyhow!(
+            "Only one of faces and tiles can be written to stdout"
+        ));
+    }
+    let field = args.depth || args.vectors || args.max_memory.is_some();
+    if (is_stdio(&args.input) || outputs > 0) && field {
+        return Err(anyhow::anyhow!(
+            "stdin and stdout are not supported with --depth, --vectors or --max-memory"
+        ));
+    }
+    if outputs > 0 && (args.mask.mask || args.ray_maps.is_some()) {
+        return Err(anyhow::anyhow!(
+            "Masks and ray maps can't be written to stdout"
+        ));
+    }
+    Ok(())
+}
+
+/// Splits equirectangular image into faces and tiles, returns how the image was laid on the sphere
+fn split(args: &SplitArgs) -> anyhow::Result<EquLayout> {
+    let SplitArgs {
+        input,
+        tiles_output,
+        cubemap_faces_output,
+        ..
+    } = args;
+    check_stdio(args)?;
+    if args.pose_from.is_some() && !(args.depth || args.vectors) {
+        return Err(anyhow::anyhow!(
+            "--pose-from is for --depth and --vectors, images carry their own pose"
+        ));
+    }
+    if args.depth || args.vectors {
+        return split_field(args);
+    }
+    if let Some(max_memory) = args.max_memory {
+        return stream::split(args, max_memory);
+    }
+    eprintln!("[main]: Load image...");
+    let (source, metadata) = match is_stdio(input) {
+        true => decode_with_metadata(&read_stdin()?, None)?,
+        false => load_with_metadata(input)?,
+    };
+    eprintln!("[main]: image loaded.");
     let (width, height) = source.dimensions();
-    let source_dims = Vector2::new(width, height);
-    let face_dimensions = Vector2::new(face_size, face_size);
+    let (layout, face_exif) = input_layout(args, Vector2::new(width, height), metadata)?;
+    let EquLayout { area, orientation } = layout;
+    let face_size = area.face_size()?;
+    let selection = args.selection()?;
+    if args.mask.mask {
+        if args.npy_output.is_some() {
+            return Err(anyhow::anyhow!("NumPy output is not supported for masks"));
+        }
+        let (labels, sixteen_bit) = mask::labels(source, input)?;
+        eprintln!("[Equ -> Cube]: processing mask...");
+        let cube = MaskCube::from_equ(
+            &labels,
+            &Equirectangular { area },
+            orientation,
+            face_size,
+            args.mask.mask_sampling,
+            sixteen_bit,
+            &selection,
+        );
+        eprintln!("[Equ -> Cube]: done.");
+        if let Some(cubemap_out) = cubemap_faces_output {
+            eprintln!("[main]: Saving mask cubemap...");
+            cube.save(cubemap_out, &selection)?;
+        }
+        if let Some(output) = tiles_output {
+            eprintln!("[main]: Saving mask tiles...");
+            cube.save_patches(output, args.patch_size, &selection)?;
+        }
+        save_manifests(args, face_size)?;
+        if let Some(kind) = args.ray_maps {
+            eprintln!("[main]: Saving ray maps...");
+            save_ray_maps(args, kind, face_size)?;
+        }
+        return Ok(layout);
+    }
+    eprintln!("[Equ -> Cube]: processing...");
+    let cube = if args.remap_table.is_some() || args.interpolation != Interpolation::Nearest {
+        let dimensions = Vector2::new(width, height);
+        let build = || RemapTable::equ_to_cube(dimensions, &layout, face_size, args.interpolation);
+        let table = match &args.remap_table {
+            Some(path) => cached_table(
+                path,
+                &RemapTable::equ_to_cube_key(dimensions, &layout, face_size, args.interpolation),
+                build,
+            )?,
+            None => build(),
+        };
+        let source = source.to_rgba8();
+        Cube::from_faces(table.apply_where(&[&source], |output, x, y| {
+            selection.pixel(Face::ALL[output], x, y)
+        })?)?
+    } else {
+        equ2cube(source, face_size, &layout, &selection)?
+    };
+    eprintln!("[Equ -> Cube]: done.");
+    if cubemap_faces_output.is_none() && tiles_output.is_none() && args.npy_output.is_none() {
+        eprintln!("[main]: Warning, no output type specified");
+    }
+    if let Some(cubemap_out) = cubemap_faces_output {
+        eprintln!("[main]: Saving cubemap...");
+        match is_stdio(cubemap_out) {
+            true => write_views_archive(
+                args,
+                &cube.faces(&selection),
+                None,
+                face_size,
+                face_exif.as_ref(),
+            )?,
+            false => cube.save(
+                &cubemap_out.to_string_lossy(),
+                face_exif.as_ref(),
+                &selection,
+            )?,
+        }
+    }
+    if let Some(output) = tiles_output {
+        eprintln!("[main]: Saving tiles...");
+        match is_stdio(output) {
+            true => write_views_archive(
+                args,
+                &cube.tiles(args.patch_size, &selection),
+                Some(args.patch_size),
+                face_size,
+                face_exif.as_ref(),
+            )?,
+            false => cube.save_patches(
+                &output.to_string_lossy(),
+                args.patch_size,
+                face_exif.as_ref(),
+                &selection,
+            )?,
+        }
+    }
+    if let Some(output) = &args.npy_output {
+        eprintln!("[main]: Saving NumPy array...");
+        cube.save_npy(
+            output,
+            args.npy_tiles.then_some(args.patch_size),
+            args.npy_type,
+            &selection,
+        )?;
+    }
+    save_manifests(args, face_size)?;
+    if let Some(kind) = args.ray_maps {
+        eprintln!("[main]: Saving ray maps...");
+        save_ray_maps(args, kind, face_size)?;
+    }
+    Ok(layout)
+}
 
-    // front
-    let front_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&FRONT, &DOWN, &RIGHT, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
-    // left
-    let left_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&LEFT, &DOWN, &FRONT, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
-    // right
-    let right_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&RIGHT, &DOWN, &BACK, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
-    // back
-    let back_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&BACK, &DOWN, &LEFT, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
-    // up
-    let up_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&UP, &BACK, &LEFT, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
-    // down
-    let down_face = ImageBuffer::from_fn(face_size, face_size, |x, y| {
-        let pixel = Vector2::new(x, y);
-        let ray = face_pixel2ray(&DOWN, &BACK, &RIGHT, &pixel, &face_dimensions);
-        let coordinate = ray2equ_pixel(&source_dims, &ray);
-        let p: Rgba<u8> = source.get_pixel(coordinate.x as u32, coordinate.y as u32);
-        p
-    });
+/// Splits float32 distance map or vector field into faces and tiles
+fn split_field(args: &SplitArgs) -> anyhow::Result<EquLayout> {
+    let (name, channels) = if args.depth {
+        ("depth", 1)
+    } else {
+        ("vector field", 3)
+    };
+    if args.npy_output.is_some() {
+        return Err(anyhow::anyhow!(
+            "NumPy array output is not supported for {}",
+            name
+        ));
+    }
+    eprintln!("[main]: Load {}...", name);
+    let source = Tensor::load(&args.input)?;
+    if source.channels != channels {
+        return Err(anyhow::anyhow!(
+            "{} has {} channels, expected {}",
+            args.input.display(),
+            source.channels,
+            channels
+        ));
+    }
+    eprintln!("[main]: {} loaded.", name);
+    let gpano = match &args.pose_from {
+        Some(panorama) => xmp::read_gpano(&fs::read(panorama)?),
+        None => None,
+    };
+    let area = args
+        .coverage
+        .area_of_image(Vector2::new(source.width, source.height), gpano.as_ref())?;
+    let orientation = gpano
+        .as_ref()
+        .and_then(|g| g.orientation())
+        .filter(|_| !args.ignore_pose);
+    if orientation.is_some() {
+        eprintln!("[main]: Orienting faces using GPano pose");
+    }
+    let face_size = area.face_size()?;
+    let equ = Equirectangular { area };
+    let selection = args.selection()?;
+    eprintln!("[Equ -> Cube]: processing {}...", name);
+    let faces = if args.depth {
+        DepthCube::from_equ(&source, &equ, orientation, face_size, &selection).faces
+    } else {
+        VectorCube::from_equ(&source, &equ, orientation, face_size, &selection).faces
+    };
+    eprintln!("[Equ -> Cube]: done.");
+    if let Some(cubemap_out) = &args.cubemap_faces_output {
+        eprintln!("[main]: Saving {} cubemap...", name);
+        tensor::save_cube(&faces, cubemap_out, &selection)?;
+    }
+    if let Some(output) = &args.tiles_output {
+        eprintln!("[main]: Saving {} tiles...", name);
+        tensor::save_cube_patches(&faces, output, args.patch_size, &selection)?;
+    }
+    save_manifests(args, face_size)?;
+    if let Some(kind) = args.ray_maps {
+        eprintln!("[main]: Saving ray maps...");
+        save_ray_maps(args, kind, face_size)?;
+    }
+    Ok(EquLayout { area, orientation })
+}
 
-    Ok(Cube {
-        front: front_face,
-        back: back_face,
-        left: left_face,
-        right: right_face,
-        up: up_face,
-        down: down_face,
-    })
+/// Stitches z-depth or vector field faces or tiles into equirectangular .npy array of
+/// distances or world vectors
+fn stitch_field(
+    input_dir: &Path,
+    tiles: bool,
+    extension: &str,
+    depth: bool,
+    coverage: &Coverage,
+    output: &Path,
+) -> anyhow::Result<()> {
+    eprintln!("[main]: Loading cube");
+    let equ = if depth {
+        let cube = DepthCube::load(input_dir, extension, tiles)?;
+        eprintln!("[Cube -> Equ]: converting depth to distances");
+        cube.to_equ()
+    } else {
+        let cube = VectorCube::load(input_dir, extension, tiles)?;
+        eprintln!("[Cube -> Equ]: rotating vectors to world frame");
+        cube.to_equ()
+    };
+    eprintln!("[Cube -> Equ]: done.");
+    let equ = match coverage.ranges() {
+        Some((longitude, latitude)) => equ.crop(&CroppedArea::within(
+            Vector2::new(equ.width, equ.height),
+            longitude,
+            latitude,
+        )?),
+        None => equ,
+    };
+    eprintln!("[main]: Save output array...");
+    equ.save(output)?;
+    eprintln!("[main]: array saved.");
+    Ok(())
 }
 
-fn spherical2cartesian(radius: f32, inclination: f32, azimuth: f32) -> Vector3<f32> {
-    // inclination = y-direction = latitude
-    // azimuth = x-direction = longitude
-    Vector3::new(
-        radius * azimuth.cos() * inclination.sin(),
-        radius * azimuth.sin() * inclination.sin(),
-        radius * inclination.cos(),
-    )
+/// Stitches segmentation mask faces or tiles, labels are never blended
+fn stitch_mask(
+    input_dir: &Path,
+    tiles: bool,
+    extension: &str,
+    confidence: &Option<PathBuf>,
+    mask: &MaskArgs,
+    coverage: &Coverage,
+    output: &Path,
+) -> anyhow::Result<()> {
+    eprintln!("[main]: Loading mask cube");
+    let cube = MaskCube::load(input_dir, extension, tiles, confidence.as_deref())?;
+    eprintln!("[main]: Mask cube loaded.");
+    eprintln!("[Cube -> Equ]: converting mask to equirectangular");
+    let labels = cube.to_equ(mask.mask_sampling);
+    eprintln!("[Cube -> Equ]: done.");
+    let labels = match coverage.ranges() {
+        Some((longitude, latitude)) => {
+            let area = CroppedArea::within(
+                Vector2::new(labels.width(), labels.height()),
+                longitude,
+                latitude,
+            )?;
+            crop_equ(&labels, &area)
+        }
+        None => labels,
+    };
+    eprintln!("[main]: Save output mask...");
+    mask::save_labels(&labels, output, cube.sixteen_bit)?;
+    eprintln!("[main]: mask saved.");
+    Ok(())
 }
 
-// cube -> equi
-// 1. for each pixel in equ image calculate xyz-vector
-// 2. See which face it hits (up,down,left,right,front,back)
-// 3. See which pixel coordinate it is and sample the pixel
-fn cube2equ(source: Cube) -> anyhow::Result<RgbaImage> {
-    // let faces = vec![
-    //     (FRONT, source.front, xz as Cget),
-    //     (BACK, source.back, xz as Cget),
-    //     (LEFT, source.left, yz as Cget),
-    //     (RIGHT, source.right, yz as Cget),
-    //     (UP, source.up, xy as Cget),
-    //     (DOWN, source.down, xy as Cget)
-    // ];
-    let (p_width, p_height) = source.front.dimensions();
-    let width = p_width * 4;
-    let height = p_height * 2;
-    let equ = ImageBuffer::from_fn(width, height, |x, y| -> Rgba<u8> {
-        let x_prop = (x as f32) / (width as f32);
-        let y_prop = (y as f32) / (height as f32);
-        let longitude = x_prop * PI * 2.0 + PI / 2.0;
-        let latitude = y_prop * PI;
-        let ray = spherical2cartesian(1.0, latitude, longitude).normalize();
-        let max_xyz = ray.x.abs().max(ray.y.abs()).max(ray.z.abs());
-        let ray_u = ray / max_xyz;
-        // println!("{:?}", ray_u);
-        match (ray_u.x, ray_u.y, ray_u.z) {
-            (s, ix, iy) if s == 1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.right.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.right.height() - 1) as f32;
-                source.right[(imx as u32, source.right.height() - 1 - imy as u32)]
-            }
-            (s, ix, iy) if s == -1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.left.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.left.height() - 1) as f32;
-                source.left[(
-                    source.left.width() - 1 - imx as u32,
-                    source.left.height() - 1 - imy as u32,
-                )]
-            }
-            (ix, s, iy) if s == 1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.back.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.back.height() - 1) as f32;
-                source.back[(
-                    source.back.width() - 1 - imx as u32,
-                    source.back.height() - 1 - imy as u32,
-                )]
-            }
-            (ix, s, iy) if s == -1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.front.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.front.height() - 1) as f32;
-                source.front[(imx as u32, source.front.height() - 1 - imy as u32)]
-            }
-            (ix, iy, s) if s == 1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.up.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.up.height() - 1) as f32;
-                source.up[(source.up.width() - 1 - imx as u32, imy as u32)]
-            }
-            (ix, iy, s) if s == -1.0 => {
-                let imx = ((ix + 1.0) / 2.0) * (source.down.width() - 1) as f32;
-                let imy = ((iy + 1.0) / 2.0) * (source.down.height() - 1) as f32;
-                source.down[(imx as u32, imy as u32)]
-            }
-            (_, _, _) => Rgba([0u8, 0u8, 0u8, 0u8]),
+/// Stitches per-face probability maps into equirectangular .npy array
+fn stitch_probabilities(
+    input_dir: &Path,
+    tiles: bool,
+    extension: &str,
+    fov: f32,
+    coverage: &Coverage,
+    output: &Path,
+) -> anyhow::Result<()> {
+    eprintln!("[main]: Loading probability maps");
+    let faces = mask::load_faces(input_dir, extension, tiles, Tensor::load, Tensor::stitch)?;
+    eprintln!("[main]: Probability maps loaded.");
+    // panorama resolution matches the 90 degree part of the faces
+    let face_size = tensor::face_size_within(faces[0].width, fov)?;
+    let full_dims = Vector2::new(face_size * 4, face_size * 2);
+    eprintln!("[Cube -> Equ]: averaging probability maps...");
+    let equ = tensor::faces_to_equ(&faces, fov, full_dims)?;
+    eprintln!("[Cube -> Equ]: done.");
+    let equ = match coverage.ranges() {
+        Some((longitude, latitude)) => {
+            equ.crop(&CroppedArea::within(full_dims, longitude, latitude)?)
         }
-    });
-    Ok(equ)
+        None => equ,
+    };
+    eprintln!("[main]: Save output array...");
+    equ.save(output)?;
+    eprintln!("[main]: array saved.");
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.threads)
+        .build_global()?;
 
     match &cli.command {
-        Some(Commands::Split {
-            input,
-            tiles_output,
-            patch_size,
-            cubemap_faces_output,
+        Some(Commands::Split(args)) => {
+            split(args)?;
+        }
+        Some(Commands::Batch {
+            split: args,
+            file_list,
+            output_root,
+            resume,
+            report,
         }) => {
-            println!("[main]: Load image...");
-            let source = image::open(input)?;
-            println!("[main]: image loaded.");
-            let (width, _height) = source.dimensions();
-            let face_size = width / 4;
-            println!("[Equ -> Cube]: processing...");
-            let cube = equ2cube(source, face_size)?;
-            println!("[Equ -> Cube]: done.");
-            if cubemap_faces_output.is_none() && tiles_output.is_none() {
-                println!("[main]: Warning, no output type specified");
+            let summary = batch::split_all(args, *file_list, output_root, *resume)?;
+            eprintln!(
+                "[batch]: {} split, {} skipped, {} failed",
+                summary.split.len(),
+                summary.skipped.len(),
+                summary.failed.len()
+            );
+            for failure in &summary.failed {
+                eprintln!("[batch]:   {}: {}", failure.input.display(), failure.error);
             }
-            if let Some(cubemap_out) = cubemap_faces_output {
-                println!("[main]: Saving cubemap...");
-                cube.save(&cubemap_out.to_string_lossy())?;
+            if let Some(report) = report {
+                fs::write(report, serde_json::to_vec_pretty(&summary)?)?;
             }
-            if let Some(output) = tiles_output {
-                println!("[main]: Saving tiles...");
-                cube.save_patches(&output.to_string_lossy(), *patch_size)?;
+            if !summary.failed.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} of {} panoramas failed",
+                    summary.failed.len(),
+                    summary.split.len() + summary.skipped.len() + summary.failed.len()
+                ));
             }
         }
+        Some(Commands::SplitAnnotations {
+            split: args,
+            annotations,
+            input_format,
+            annotations_output,
+            output_format,
+            min_visible,
+        }) => {
+            let layout = split(args)?;
+            eprintln!("[main]: Loading annotations...");
+            let panorama_dims = Vector2::new(layout.area.width, layout.area.height);
+            let panorama_annotations: Vec<annotations::PanoramaAnnotation> = match input_format {
+                AnnotationFormat::Json => serde_json::from_slice(&fs::read(annotations)?)?,
+                format => formats::to_panorama_annotations(&formats::read_labels(
+                    annotations,
+                    *format,
+                    &|_| Some(panorama_dims),
+                )?),
+            };
+            eprintln!("[main]: {} annotations loaded.", panorama_annotations.len());
+            let splitter = annotations::AnnotationSplitter {
+                equ: Equirectangular { area: layout.area },
+                orientation: layout.orientation,
+                face_size: layout.area.face_size()?,
+                faces: args.cubemap_faces_output.is_some(),
+                patch_size: args.tiles_output.as_ref().map(|_| args.patch_size),
+                selection: args.selection()?,
+                extension: args.extension().to_string(),
+                min_visible: *min_visible,
+            };
+            eprintln!("[Equ -> Cube]: projecting annotations...");
+            let view_annotations = splitter.split(&panorama_annotations);
+            eprintln!("[Equ -> Cube]: done.");
+            match output_format {
+                AnnotationFormat::Json => fs::write(
+                    annotations_output,
+                    serde_json::to_string_pretty(&view_annotations)?,
+                )?,
+                format => {
+                    let cube_layout = annotations::CubeLayout {
+                        face_size: splitter.face_size,
+                        patch_size: args.patch_size,
+                    };
+                    formats::write_labels(
+                        annotations_output,
+                        *format,
+                        &formats::from_view_annotations(&view_annotations),
+                        &|file| {
+                            cube_layout.image_size(file).unwrap_or(Vector2::new(
+                                cube_layout.face_size,
+                                cube_layout.face_size,
+                            ))
+                        },
+                    )?
+                }
+            }
+            eprintln!("[main]: {} annotations saved.", view_annotations.len());
+        }
+        Some(Commands::Validate {
+            input_dir,
+            tiles,
+            extension,
+        }) => {
+            let problems = validate::directory(input_dir, extension, *tiles)?;
+            for problem in &problems {
+                println!("[validate]: {}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} problems in {}",
+                    problems.len(),
+                    input_dir.display()
+                ));
+            }
+            println!(
+                "[validate]: {} in {} form a complete cube",
+                if *tiles { "Tiles" } else { "Faces" },
+                input_dir.display()
+            );
+        }
         Some(Commands::Stitch {
             input_dir,
             output,
+            output_format,
             tiles,
             extension,
+            coverage,
+            pose_heading,
+            reference,
+            mask,
+            confidence,
+            probabilities,
+            face_fov,
+            depth,
+            vectors,
+            remap_table,
+            fill,
+            max_memory,
         }) => {
-            println!("[main]: Loading cube");
-            let cube = if *tiles {
-                println!("[main]: Loading from tiles");
-                Cube::from_directory_of_patches(&input_dir.to_string_lossy(), extension)?
+            let field = *depth || *vectors || *probabilities || mask.mask;
+            if (is_stdio(input_dir) || is_stdio(output)) && (field || max_memory.is_some()) {
+                return Err(anyhow::anyhow!(
+                    "stdin and stdout are supported only for images without --max-memory"
+                ));
+            }
+            if *depth || *vectors {
+                let extension = extension.as_deref().unwrap_or("npy");
+                stitch_field(input_dir, *tiles, extension, *depth, coverage, output)?;
+                return Ok(());
+            }
+            if *probabilities {
+                let extension = extension.as_deref().unwrap_or("npy");
+                stitch_probabilities(input_dir, *tiles, extension, *face_fov, coverage, output)?;
+                return Ok(());
+            }
+            if mask.mask {
+                let extension = extension.as_deref().unwrap_or("png");
+                stitch_mask(
+                    input_dir, *tiles, extension, confidence, mask, coverage, output,
+                )?;
+                return Ok(());
+            }
+            if let Some(max_memory) = max_memory {
+                if reference.is_some() || pose_heading.is_some() {
+                    eprintln!("[main]: Warning, metadata is not written with --max-memory");
+                }
+                eprintln!("[Cube -> Equ]: stitching in strips");
+                stream::stitch(
+                    input_dir,
+                    extension.as_deref().unwrap_or("jpg"),
+                    *tiles,
+                    coverage,
+                    *fill,
+                    output,
+                    *max_memory,
+                )?;
+                eprintln!("[main]: image saved.");
+                return Ok(());
+            }
+            eprintln!("[main]: Loading cube");
+            let cube = if is_stdio(input_dir) {
+                eprintln!("[main]: Loading from archive on stdin");
+                let extension = extension.as_deref().unwrap_or("jpg");
+                // other files, such as the manifest, are skipped as in directories
+                let wanted = |name: &str| {
+                    let name = Path::new(name);
+                    name.extension().is_some_and(|e| e == extension)
+                        && CubeView::parse_name(&name.file_stem().unwrap().to_string_lossy())
+                            .is_some_and(|(_, tile)| tile.is_some() == *tiles)
+                };
+                let views = equistitch::read_archive(&read_stdin()?)?
+                    .into_iter()
+                    .filter(|(name, _)| wanted(name))
+                    .map(|(name, bytes)| CubeView::decode(&name, &bytes))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Cube::from_views(views, *fill)?
+            } else if input_dir.extension().is_some_and(|e| e == "npy") {
+                eprintln!("[main]: Loading from NumPy array");
+                Cube::from_npy(input_dir)?
             } else {
-                println!("[main]: Loading from cubemap");
-                Cube::from_directory(&input_dir.to_string_lossy())?
+                eprintln!(
+                    "[main]: Loading from {}",
+                    if *tiles { "tiles" } else { "cubemap" }
+                );
+                let (cube, missing) = Cube::load(
+                    input_dir,
+                    extension.as_deref().unwrap_or("jpg"),
+                    *tiles,
+                    *fill,
+                )?;
+                if !missing.is_empty() {
+                    eprintln!(
+                        "[main]: Warning, {} {} missing, filled",
+                        missing.len(),
+                        if *tiles { "tiles" } else { "faces" }
+                    );
+                }
+                cube
+            };
+            eprintln!("[main]: Cube loaded.");
+            eprintln!("[Cube -> Equ]: converting cubemap to equirectangular");
+            let restitched = match remap_table {
+                Some(path) => {
+                    let face_size = cube.face_size();
+                    let key = RemapTable::cube_to_equ_key(face_size);
+                    let table = cached_table(path, &key, || RemapTable::cube_to_equ(face_size))?;
+                    let faces = Face::ALL.map(|face| cube.face(face));
+                    table.apply(&faces)?.remove(0)
+                }
+                None => cube2equ(cube)?,
+            };
+            eprintln!("[Cube -> Equ]: done.");
+            eprintln!("[main]: Save output image...");
+            let full_dims = Vector2::new(restitched.width(), restitched.height());
+            let (restitched, area) = match coverage.ranges() {
+                Some((longitude, latitude)) => {
+                    let area = CroppedArea::within(full_dims, longitude, latitude)?;
+                    (crop_equ(&restitched, &area), area)
+                }
+                None => (restitched, CroppedArea::full(full_dims)),
+            };
+            let mut payloads = vec![];
+            if let Some(reference) = reference {
+                match SourceExif::read(&fs::read(reference)?) {
+                    Some(exif) => payloads.push(
+                        exif.app1(Vector2::new(restitched.width(), restitched.height()), None)?,
+                    ),
+                    None => eprintln!("[main]: Warning, no EXIF found in reference image"),
+                }
+            }
+            let gpano = xmp::GPano {
+                cropped_area: Some(area),
+                pose_heading: *pose_heading,
+                ..Default::default()
             };
-            println!("[main]: Cube loaded.");
-            println!("[Cube -> Equ]: converting cubemap to equirectangular");
-            let restitched = cube2equ(cube)?;
-            println!("[Cube -> Equ]: done.");
-            println!("[main]: Save output image...");
-            restitched.save(output)?;
-            println!("[main]: image saved.");
+            payloads.push(xmp::app1(&xmp::gpano_xmp(&gpano)));
+            let format = match is_stdio(output) {
+                true => ImageFormat::from_extension(output_format)
+                    .ok_or(anyhow::anyhow!("Unknown output format {}", output_format))?,
+                false => ImageFormat::from_path(output)?,
+            };
+            // the cropped area alone is no reason to warn, it is written whenever possible
+            if format != ImageFormat::Jpeg && (reference.is_some() || pose_heading.is_some()) {
+                eprintln!("[main]: Warning, EXIF and pose heading are written only to JPEG");
+            }
+            if is_stdio(output) {
+                let bytes = metadata::encode_with_app1(&restitched, format, &payloads)?;
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            } else {
+                metadata::save_with_app1(&restitched, output, &payloads)?;
+            }
+            eprintln!("[main]: image saved.");
+        }
+        Some(Commands::Convert {
+            input,
+            output,
+            from,
+            coverage,
+            to,
+            source_fov,
+            source_face,
+            width,
+            height,
+            fov,
+            face,
+            yaw,
+            pitch,
+            roll,
+        }) => {
+            eprintln!("[main]: Load image...");
+            let (source, InputMetadata { gpano, .. }) = load_with_metadata(input)?;
+            let source = source.into_rgba8();
+            eprintln!("[main]: image loaded.");
+            let (source_width, source_height) = source.dimensions();
+            let source_dims = Vector2::new(source_width, source_height);
+            let src_proj = ProjectionParams {
+                kind: *from,
+                dimensions: source_dims,
+                fov: *source_fov,
+                face: *source_face,
+                basis: Basis::default(),
+                area: Some(coverage.area_of_image(source_dims, gpano.as_ref())?),
+            }
+            .build()?;
+            let dst_proj = ProjectionParams {
+                kind: *to,
+                dimensions: Vector2::new(
+                    width.unwrap_or(source_width),
+                    height.unwrap_or(source_height),
+                ),
+                fov: *fov,
+                face: *face,
+                basis: Basis::from_angles(*yaw, *pitch, *roll),
+                area: None,
+            }
+            .build()?;
+            eprintln!("[{:?} -> {:?}]: processing...", from, to);
+            let converted = reproject(&source, src_proj.as_ref(), dst_proj.as_ref())?;
+            eprintln!("[{:?} -> {:?}]: done.", from, to);
+            eprintln!("[main]: Save output image...");
+            converted.save(output)?;
+            eprintln!("[main]: image saved.");
+        }
+        Some(Commands::ProjectAnnotations {
+            input,
+            input_format,
+            output,
+            output_format,
+            panorama_name,
+            panorama,
+            width,
+            height,
+            coverage,
+            ignore_pose,
+            patch_size,
+            edge_points,
+            merge,
+            iou_threshold,
+        }) => {
+            let (dimensions, gpano) = match (panorama, width) {
+                (Some(panorama), _) => (
+                    image::image_dimensions(panorama)?,
+                    xmp::read_gpano(&fs::read(panorama)?),
+                ),
+                (None, Some(width)) => ((*width, height.unwrap_or(width / 2)), None),
+                (None, None) => unreachable!("clap requires --width without --panorama"),
+            };
+            let area =
+                coverage.area_of_image(Vector2::new(dimensions.0, dimensions.1), gpano.as_ref())?;
+            let equ = EquLayout {
+                area,
+                orientation: gpano
+                    .as_ref()
+                    .and_then(|g| g.orientation())
+                    .filter(|_| !ignore_pose),
+            };
+            let layout = annotations::CubeLayout {
+                face_size: area.face_size()?,
+                patch_size: *patch_size,
+            };
+            eprintln!("[main]: Loading detections...");
+            let detections: Vec<annotations::Detection> = match input_format {
+                AnnotationFormat::Json => serde_json::from_slice(&fs::read(input)?)?,
+                format => formats::to_detections(&formats::read_labels(input, *format, &|file| {
+                    layout.image_size(file)
+                })?),
+            };
+            eprintln!("[main]: {} detections loaded.", detections.len());
+            let equ_dimensions = Vector2::new(area.width, area.height);
+            eprintln!("[Cube -> Equ]: projecting detections...");
+            let projected = match merge {
+                Some(method) => {
+                    let merged = merge::merge_detections(
+                        &detections,
+                        &layout,
+                        &equ,
+                        *edge_points,
+                        *method,
+                        *iou_threshold,
+                    )?;
+                    eprintln!(
+                        "[Cube -> Equ]: {} detections merged into {}.",
+                        detections.len(),
+                        merged.len()
+                    );
+                    merged
+                }
+                None => annotations::project_detections(&detections, &layout, &equ, *edge_points)?,
+            };
+            eprintln!("[Cube -> Equ]: done.");
+            match output_format {
+                AnnotationFormat::Json => {
+                    fs::write(output, serde_json::to_string_pretty(&projected)?)?
+                }
+                format => formats::write_labels(
+                    output,
+                    *format,
+                    &formats::from_projected_detections(&projected, panorama_name, area.full_width),
+                    &|_| equ_dimensions,
+                )?,
+            }
+            eprintln!("[main]: detections saved.");
         }
         None => {}
     }
     Ok(())
 }
-
-#[cfg(test)]
-mod tests {
-    use cgmath::Vector2;
-
-    use crate::{face_pixel2ray, ray2equ_pixel, BACK, DOWN, FRONT, LEFT, RIGHT, UP};
-
-    #[test]
-    fn front_face_center_point_should_point_to_front_ray() {
-        let ray = face_pixel2ray(
-            &FRONT,
-            &DOWN,
-            &RIGHT,
-            &Vector2::new(5, 5),
-            &Vector2::new(10, 10),
-        );
-        assert_eq!(ray, FRONT);
-    }
-    #[test]
-    fn left_face_center_point_should_point_to_left_ray() {
-        let ray = face_pixel2ray(
-            &LEFT,
-            &DOWN,
-            &FRONT,
-            &Vector2::new(5, 5),
-            &Vector2::new(10, 10),
-        );
-        assert_eq!(ray, LEFT);
-    }
-    #[test]
-    fn front_center_should_point_to_center_of_equirectangular_image() {
-        // Assume 360 pixels wide, 180 pixels tall equi image
-        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &FRONT);
-        // Front center pixel should point to (180, 90) in equi image
-        assert_eq!(coordinate, Vector2::new(180, 90));
-    }
-    #[test]
-    fn front_down_center_should_point_to_center_bottom_of_equirectangular_image() {
-        // Assume 360 pixels wide, 180 pixels tall equi image
-        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &(FRONT + DOWN));
-        // Front center pixel should point to (180, 179) in equi image
-        assert_eq!(coordinate, Vector2::new(180, 179));
-    }
-    #[test]
-    fn back_center_should_point_to_start_of_equirectangular_image() {
-        // Assume 360 pixels wide, 180 pixels tall equi image
-        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &BACK);
-        // Front center pixel should point to (359, 90) in equi image
-        assert_eq!(coordinate, Vector2::new(359, 90));
-    }
-    #[test]
-    fn left_center_should_point_to_90_deg_of_equirectangular_image() {
-        // Assume 360 pixels wide, 180 pixels tall equi image
-        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &LEFT);
-        // Left center pixel should point to (90, 90) in equi image
-        assert_eq!(coordinate, Vector2::new(90, 90));
-    }
-    #[test]
-    fn right_center_should_point_to_270_deg_of_equirectangular_image() {
-        // Assume 360 pixels wide, 180 pixels tall equi image
-        let coordinate = ray2equ_pixel(&Vector2::new(360, 180), &RIGHT);
-        // Right center pixel should point to (270, 90) in equi image
-        assert_eq!(coordinate, Vector2::new(270, 90));
-    }
-}
@@ -0,0 +1,213 @@
+use crate::error::{self, Error};
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::projection::Face;
+use crate::view_name;
+
+/// Pinhole camera of a face or tile.
+///
+/// World frame is the level north-facing frame of the faces: x right (east), y front (north),
+/// z up. Pixel centers are at integer coordinates as in OpenCV, face pixel `(x, y)` looks
+/// along the ray `face_pixel2ray` gives for it, so a face spans 90 degrees plus one pixel.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct Camera {
+    /// Intrinsic matrix (row-major), tiles are off-centre crops so the principal point may lie
+    /// outside of them
+    pub k: [[f32; 3]; 3],
+    /// World to camera rotation for OpenCV axes (x right, y down, z forward), translation is zero
+    pub rotation_opencv: [[f32; 3]; 3],
+    /// World to camera rotation for OpenGL axes (x right, y up, looking along -z)
+    pub rotation_opengl: [[f32; 3]; 3],
+    /// `glFrustum` left, right, bottom, top at near plane distance 1
+    pub frustum_opengl: [f32; 4],
+    /// Horizontal and vertical field of view in degrees
+    pub fov: [f32; 2],
+}
+
+impl Camera {
+    /// Camera of the `width`×`height` view at `offset` on a face of `face_size`
+    pub fn new(face: Face, face_size: u32, offset: [u32; 2], width: u32, height: u32) -> Camera {
+        let basis = face.basis();
+        let f = face_size as f32 / 2.0;
+        let cx = face_size as f32 / 2.0 - offset[0] as f32;
+        let cy = face_size as f32 / 2.0 - offset[1] as f32;
+        // adding zero turns negated zeros into plain ones in the JSON
+        let rows = |a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>| {
+            [a, b, c].map(|v| [v.x + 0.0, v.y + 0.0, v.z + 0.0])
+        };
+        let left = (-0.5 - cx) / f;
+        let right = (width as f32 - 0.5 - cx) / f;
+        let top = (cy + 0.5) / f;
+        let bottom = -(height as f32 - 0.5 - cy) / f;
+        Camera {
+            k: [[f, 0.0, cx], [0.0, f, cy], [0.0, 0.0, 1.0]],
+            rotation_opencv: rows(basis.right, basis.down, basis.center),
+            rotation_opengl: rows(basis.right, -basis.down, -basis.center),
+            frustum_opengl: [left, right, bottom, top],
+            fov: [
+                (right.atan() - left.atan()).to_degrees(),
+                (top.atan() - bottom.atan()).to_degrees(),
+            ],
+        }
+    }
+}
+
+/// Face or tile written by `split`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct ManifestView {
+    /// File name the view is written to (e.g. `front_p4_3.jpg`, or `front_p4_3.npy` for depth)
+    pub name: String,
+    pub face: String,
+    /// Tile index within the face, column by column
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tile: Option<u32>,
+    /// Upper left corner on the face
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Camera>,
+}
+
+/// Describes the faces or tiles of a split panorama, in the order they are stored in an array
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[non_exhaustive]
+pub struct Manifest {
+    pub face_size: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch_size: Option<u32>,
+    /// Array file holding the views, N×H×W×C
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub array: Option<String>,
+    /// `uint8` or `float32` (normalised to 0..1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dtype: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shape: Vec<usize>,
+    pub views: Vec<ManifestView>,
+}
+
+impl Manifest {
+    /// Faces, or tiles of `patch_size`, in the order `Cube::save` and `Cube::save_patches` write
+    /// them, named with the `extension` of their files
+    pub fn new(face_size: u32, patch_size: Option<u32>, extension: &str) -> Manifest {
+        let views = Face::ALL
+            .iter()
+            .flat_map(|face| match patch_size {
+                None => vec![ManifestView {
+                    name: format!("{}.{}", view_name(*face, None), extension),
+                    face: face.name().to_string(),
+                    tile: None,
+                    x: 0,
+                    y: 0,
+                    width: face_size,
+                    height: face_size,
+                    camera: Some(Camera::new(*face, face_size, [0, 0], face_size, face_size)),
+                }],
+                Some(patch_size) => {
+                    let pieces = face_size / patch_size;
+                    (0..pieces * pieces)
+                        .map(|i| {
+                            let (x, y) = (i / pieces * patch_size, i % pieces * patch_size);
+                            ManifestView {
+                                name: format!(
+                                    "{}.{}",
+                                    view_name(*face, Some((pieces, i))),
+                                    extension
+                                ),
+                                face: face.name().to_string(),
+                                tile: Some(i),
+                                x,
+                                y,
+                                width: patch_size,
+                                height: patch_size,
+                                camera: Some(Camera::new(
+                                    *face,
+                                    face_size,
+                                    [x, y],
+                                    patch_size,
+                                    patch_size,
+                                )),
+                            }
+                        })
+                        .collect()
+                }
+            })
+            .collect();
+        Manifest {
+            face_size,
+            patch_size,
+            array: None,
+            dtype: None,
+            shape: vec![],
+            views,
+        }
+    }
+
+    pub fn load(path: &Path) -> error::Result<Manifest> {
+        serde_json::from_slice(&fs::read(path).map_err(Error::io(path))?)
+            .map_err(|e| Error::format(e).at(path))
+    }
+
+    pub fn save(&self, path: &Path) -> error::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?).map_err(Error::io(path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_are_listed_column_by_column() {
+        let manifest = Manifest::new(100, Some(50), "jpg");
+        assert_eq!(manifest.views.len(), 24);
+        let view = &manifest.views[1];
+        assert_eq!(view.name, "front_p2_1.jpg");
+        assert_eq!((view.x, view.y), (0, 50));
+        assert_eq!(Manifest::new(100, None, "jpg").views[5].name, "down.jpg");
+        assert_eq!(Manifest::new(100, None, "npy").views[0].name, "front.npy");
+    }
+
+    #[test]
+    fn tile_camera_projects_face_rays_to_tile_pixels() {
+        let size = 100;
+        let manifest = Manifest::new(size, Some(50), "jpg");
+        // right face, tile 2 is the upper right one
+        let view = &manifest.views[3 * 4 + 2];
+        assert_eq!((view.x, view.y), (50, 0));
+        let camera = view.camera.as_ref().unwrap();
+        let basis = Face::Right.basis();
+        let ray = crate::face_pixel2ray(
+            &basis.center,
+            &basis.down,
+            &basis.right,
+            &cgmath::Vector2::new(53, 7),
+            &cgmath::Vector2::new(size, size),
+        );
+        let r = camera.rotation_opencv;
+        let p = [0, 1, 2].map(|i| r[i][0] * ray.x + r[i][1] * ray.y + r[i][2] * ray.z);
+        let k = camera.k;
+        let (u, v) = (
+            k[0][0] * p[0] / p[2] + k[0][2],
+            k[1][1] * p[1] / p[2] + k[1][2],
+        );
+        assert!(
+            (u - 3.0).abs() < 1e-3 && (v - 7.0).abs() < 1e-3,
+            "{} {}",
+            u,
+            v
+        );
+        // OpenGL camera looks along -z
+        let gl = camera.rotation_opengl;
+        assert!(gl[2][0] * ray.x + gl[2][1] * ray.y + gl[2][2] * ray.z < 0.0);
+        assert!(camera.fov[0] > 45.0 && camera.fov[0] < 46.0);
+    }
+}
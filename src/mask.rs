@@ -0,0 +1,407 @@
+use crate::error::{self, Error};
+use cgmath::{InnerSpace, Matrix3, Vector2, Vector3};
+use image::{DynamicImage, ImageBuffer, Luma};
+use std::path::Path;
+
+use crate::metadata::is_jpeg_path;
+use crate::projection::{CroppedArea, CubeFace, Equirectangular, Face, Projection};
+use crate::selection::Selection;
+use crate::validate;
+use crate::{par_from_fn, split_image, stitch_image};
+
+/// Single channel label image, 8 bit masks are widened on load
+pub type LabelImage = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+/// How labels are sampled, never interpolated
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskSampling {
+    /// Label of the pixel hit by the ray (same rays as the image faces use)
+    Nearest,
+    /// Majority of 3x3 rays around the nearest ray, also settles pixels on face edges
+    Vote,
+}
+
+impl MaskSampling {
+    /// Sample offsets within the pixel, centered on the ray `Nearest` uses
+    fn offsets(&self) -> Vec<Vector2<f32>> {
+        match self {
+            MaskSampling::Nearest => vec![Vector2::new(0.0, 0.0)],
+            MaskSampling::Vote => (0..9)
+                .map(|i| Vector2::new((i % 3) as f32 - 1.0, (i / 3) as f32 - 1.0) / 3.0)
+                .collect(),
+        }
+    }
+}
+
+/// Labels of a single channel 8 or 16 bit image, returns whether it was 16 bit
+pub fn labels(image: DynamicImage, path: &Path) -> error::Result<(LabelImage, bool)> {
+    match image {
+        DynamicImage::ImageLuma8(image) => Ok((
+            ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+                Luma([image[(x, y)][0] as u16])
+            }),
+            false,
+        )),
+        DynamicImage::ImageLuma16(image) => Ok((image, true)),
+        _ => Err(Error::Unsupported(format!(
+            "Mask {} is not a single channel 8 or 16 bit image",
+            path.display()
+        ))),
+    }
+}
+
+pub fn load_labels(path: &Path) -> error::Result<(LabelImage, bool)> {
+    labels(image::open(path).map_err(Error::image(path))?, path)
+}
+
+/// Saves labels losslessly with the bit depth of the source masks
+pub fn save_labels(labels: &LabelImage, path: &Path, sixteen_bit: bool) -> error::Result<()> {
+    if is_jpeg_path(path) {
+        return Err(Error::Unsupported(format!(
+            "Mask {} can't be saved as lossy JPEG, use PNG",
+            path.display()
+        )));
+    }
+    if sixteen_bit {
+        labels.save(path).map_err(Error::image(path))?;
+    } else {
+        ImageBuffer::<Luma<u8>, Vec<u8>>::from_fn(labels.width(), labels.height(), |x, y| {
+            Luma([labels[(x, y)][0].min(u8::MAX as u16) as u8])
+        })
+        .save(path)
+        .map_err(Error::image(path))?;
+    }
+    Ok(())
+}
+
+/// Label with the largest total weight, ties go to the smaller label
+fn vote(samples: &[(u16, f32)]) -> u16 {
+    let mut totals: Vec<(u16, f32)> = vec![];
+    for (label, weight) in samples {
+        match totals.iter_mut().find(|(l, _)| l == label) {
+            Some((_, total)) => *total += weight,
+            None => totals.push((*label, *weight)),
+        }
+    }
+    totals
+        .iter()
+        .fold(
+            None,
+            |best: Option<(u16, f32)>, &(label, total)| match best {
+                Some((l, t)) if t > total || (t == total && l < label) => best,
+                _ => Some((label, total)),
+            },
+        )
+        .map_or(0, |(label, _)| label)
+}
+
+/// Face images in `Face::ALL` order, read from `{face}.{extension}` or from tiles
+/// `{face}_p{pieces}_{i}.{extension}` after checking that all of them are there and fit
+pub fn load_faces<T>(
+    directory: &Path,
+    extension: &str,
+    tiles: bool,
+    load: impl Fn(&Path) -> error::Result<T>,
+    stitch: impl Fn(&[T], u32) -> error::Result<T>,
+) -> error::Result<Vec<T>> {
+    let found = validate::shapes_in(directory, extension, tiles)?;
+    let problems = validate::problems(&found.iter().map(|(shape, _)| *shape).collect::<Vec<_>>());
+    if !problems.is_empty() {
+        return Err(Error::Invalid(problems));
+    }
+    let pieces = found[0].0.tile.map_or(1, |(pieces, _)| pieces);
+    let per_face = (pieces * pieces) as usize;
+    // views come in face and tile order
+    found
+        .chunks(per_face)
+        .map(|views| {
+            let views = views
+                .iter()
+                .map(|(_, path)| load(path))
+                .collect::<error::Result<Vec<_>>>()?;
+            match tiles {
+                true => stitch(&views, pieces),
+                false => Ok(views.into_iter().next().unwrap()),
+            }
+        })
+        .collect()
+}
+
+/// Label faces of a cubemap with optional per-pixel confidence
+#[non_exhaustive]
+pub struct MaskCube {
+    /// In `Face::ALL` order
+    pub faces: Vec<LabelImage>,
+    /// Confidence of every label in `Face::ALL` order, any single channel image scaled to 0..1
+    pub confidence: Option<Vec<ImageBuffer<Luma<u16>, Vec<u16>>>>,
+    pub sixteen_bit: bool,
+}
+
+impl MaskCube {
+    pub fn load(
+        directory: &Path,
+        extension: &str,
+        tiles: bool,
+        confidence: Option<&Path>,
+    ) -> error::Result<MaskCube> {
+        let faces = load_faces(
+            directory,
+            extension,
+            tiles,
+            load_labels,
+            |patches, pieces| {
+                let images = patches.iter().map(|(i, _)| i.clone()).collect::<Vec<_>>();
+                Ok((
+                    stitch_image(&images, pieces)?,
+                    patches.iter().any(|(_, b)| *b),
+                ))
+            },
+        )?;
+        let sixteen_bit = faces.iter().any(|(_, b)| *b);
+        let faces = faces.into_iter().map(|(f, _)| f).collect::<Vec<_>>();
+        let confidence = confidence
+            .map(|directory| {
+                load_faces(
+                    directory,
+                    extension,
+                    tiles,
+                    |path| Ok(image::open(path).map_err(Error::image(path))?.into_luma16()),
+                    stitch_image,
+                )
+            })
+            .transpose()?;
+        let size = faces[0].width();
+        for (i, face) in faces.iter().enumerate() {
+            let confidence = confidence.as_ref().map(|c| c[i].dimensions());
+            for found in [Some(face.dimensions()), confidence].into_iter().flatten() {
+                if found != (size, size) {
+                    return Err(Error::Dimensions {
+                        name: format!("Mask face {}", Face::ALL[i].name()),
+                        expected: (size, size),
+                        found,
+                    });
+                }
+            }
+        }
+        Ok(MaskCube {
+            faces,
+            confidence,
+            sixteen_bit,
+        })
+    }
+
+    pub fn face_size(&self) -> u32 {
+        self.faces[0].width()
+    }
+
+    /// Label and its weight hit by the ray
+    fn sample(&self, ray: &Vector3<f32>) -> (u16, f32) {
+        let size = self.face_size();
+        let (i, basis) = Face::ALL
+            .iter()
+            .map(|face| face.basis())
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.center.dot(*ray).total_cmp(&b.center.dot(*ray)))
+            .unwrap();
+        // 90 degree face, rays on the edge may fall a rounding error outside of it
+        let half = size as f32 / 2.0;
+        let z = ray.dot(basis.center);
+        let coordinate =
+            |axis: Vector3<f32>| ((half + half * ray.dot(axis) / z).max(0.0) as u32).min(size - 1);
+        let (x, y) = (coordinate(basis.right), coordinate(basis.down));
+        let weight = self
+            .confidence
+            .as_ref()
+            .map_or(1.0, |c| c[i][(x, y)][0] as f32 / u16::MAX as f32);
+        (self.faces[i][(x, y)][0], weight)
+    }
+
+    /// Full equirectangular label image, conflicting samples resolved by vote weighted by
+    /// confidence when it is given
+    pub fn to_equ(&self, sampling: MaskSampling) -> LabelImage {
+        let size = self.face_size();
+        let equ = Equirectangular {
+            area: CroppedArea::full(Vector2::new(size * 4, size * 2)),
+        };
+        let offsets = sampling.offsets();
+        par_from_fn(size * 4, size * 2, |x, y| {
+            let samples = offsets
+                .iter()
+                .filter_map(|offset| {
+                    let ray = equ.pixel_to_ray(&(Vector2::new(x as f32, y as f32) + offset))?;
+                    Some(self.sample(&ray))
+                })
+                .collect::<Vec<_>>();
+            Luma([vote(&samples)])
+        })
+    }
+
+    /// Faces of an equirectangular label image, `orientation` takes world rays to image rays.
+    /// Rays outside of a partial panorama get label 0.
+    pub fn from_equ(
+        labels: &LabelImage,
+        equ: &Equirectangular,
+        orientation: Option<Matrix3<f32>>,
+        face_size: u32,
+        sampling: MaskSampling,
+        sixteen_bit: bool,
+        selection: &Selection,
+    ) -> MaskCube {
+        let offsets = sampling.offsets();
+        let faces = Face::ALL
+            .iter()
+            .map(|face| {
+                let cube_face = CubeFace {
+                    face: *face,
+                    size: face_size,
+                };
+                par_from_fn(face_size, face_size, |x, y| {
+                    if !selection.pixel(*face, x, y) {
+                        return Luma([0]);
+                    }
+                    let samples = offsets
+                        .iter()
+                        .filter_map(|offset| {
+                            let ray = cube_face
+                                .pixel_to_ray(&(Vector2::new(x as f32, y as f32) + offset))?;
+                            let ray = orientation.map_or(ray, |o| o * ray);
+                            let p = equ.ray_to_pixel(&ray)?;
+                            let (x, y) = (
+                                (p.x as u32).min(labels.width() - 1),
+                                (p.y as u32).min(labels.height() - 1),
+                            );
+                            Some((labels[(x, y)][0], 1.0))
+                        })
+                        .collect::<Vec<_>>();
+                    Luma([vote(&samples)])
+                })
+            })
+            .collect();
+        MaskCube {
+            faces,
+            confidence: None,
+            sixteen_bit,
+        }
+    }
+
+    /// Saves selected faces as `{face}.png`
+    pub fn save(&self, prefix: &Path, selection: &Selection) -> error::Result<()> {
+        Face::ALL
+            .iter()
+            .zip(&self.faces)
+            .filter(|(face, _)| selection.face(**face))
+            .try_for_each(|(face, labels)| {
+                save_labels(
+                    labels,
+                    &prefix.join(format!("{}.png", face.name())),
+                    self.sixteen_bit,
+                )
+            })
+    }
+
+    /// Saves selected tiles as `{face}_p{pieces}_{i}.png`, same order as image tiles
+    pub fn save_patches(
+        &self,
+        prefix: &Path,
+        patch_size: u32,
+        selection: &Selection,
+    ) -> error::Result<()> {
+        Face::ALL
+            .iter()
+            .zip(&self.faces)
+            .try_for_each(|(face, labels)| {
+                let (patches, pieces) = split_image(labels, patch_size);
+                patches.iter().enumerate().try_for_each(|(i, p)| {
+                    // patches go column by column
+                    if !selection.tile(*face, i as u32 / pieces, i as u32 % pieces) {
+                        return Ok(());
+                    }
+                    save_labels(
+                        p,
+                        &prefix.join(format!("{}_p{}_{}.png", face.name(), pieces, i)),
+                        self.sixteen_bit,
+                    )
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn face_labels(size: u32) -> MaskCube {
+        MaskCube {
+            faces: (0..6)
+                .map(|i| ImageBuffer::from_pixel(size, size, Luma([1000 + i as u16])))
+                .collect(),
+            confidence: None,
+            sixteen_bit: true,
+        }
+    }
+
+    #[test]
+    fn vote_prefers_heavier_then_smaller_label() {
+        assert_eq!(vote(&[(3, 1.0), (5, 1.0), (5, 1.0)]), 5);
+        assert_eq!(vote(&[(3, 0.9), (5, 0.2), (5, 0.2)]), 3);
+        assert_eq!(vote(&[(7, 1.0), (2, 1.0)]), 2);
+        assert_eq!(vote(&[]), 0);
+    }
+
+    #[test]
+    fn stitched_labels_are_never_blended() {
+        let cube = face_labels(16);
+        for sampling in [MaskSampling::Nearest, MaskSampling::Vote] {
+            let equ = cube.to_equ(sampling);
+            assert!(equ.pixels().all(|p| (1000..1006).contains(&p[0])));
+            // front face is at the panorama center
+            assert_eq!(equ[(32, 16)][0], 1000);
+        }
+    }
+
+    #[test]
+    fn confidence_settles_face_edges() {
+        let mut cube = face_labels(16);
+        // front is sure, left is not
+        cube.confidence = Some(
+            (0..6)
+                .map(|i| ImageBuffer::from_pixel(16, 16, Luma([if i == 0 { 60000 } else { 100 }])))
+                .collect(),
+        );
+        // pixel on the front/left edge at the horizon
+        let equ = cube.to_equ(MaskSampling::Vote);
+        assert_eq!(equ[(24, 16)][0], 1000);
+    }
+
+    #[test]
+    fn split_and_stitch_round_trip_labels() {
+        let size = 64;
+        let cube = face_labels(size);
+        let equ = cube.to_equ(MaskSampling::Nearest);
+        let projection = Equirectangular {
+            area: CroppedArea::full(Vector2::new(size * 4, size * 2)),
+        };
+        let split = MaskCube::from_equ(
+            &equ,
+            &projection,
+            None,
+            size,
+            MaskSampling::Vote,
+            true,
+            &Selection::all(),
+        );
+        for (a, b) in split.faces.iter().zip(cube.faces.iter()) {
+            // labels may move by a panorama pixel at face edges, which is wider at the corners
+            let interior = 4..size - 4;
+            let differing = a
+                .enumerate_pixels()
+                .filter(|(x, y, p)| {
+                    interior.contains(x) && interior.contains(y) && **p != b[(*x, *y)]
+                })
+                .map(|(x, y, p)| (x, y, p[0], b[(x, y)][0]))
+                .collect::<Vec<_>>();
+            assert!(differing.is_empty(), "{:?}", differing);
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use crate::error::{self, Error};
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+use crate::annotations::{
+    clip_polygon, rays_to_equ, solid_angle, world_ray, CubeLayout, Detection, ProjectedDetection,
+};
+use crate::projection::{CubeFace, Projection};
+use crate::{EquLayout, DOWN, FRONT, UP};
+
+/// How overlapping detections of the same object are merged
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeMethod {
+    /// Non-maximum suppression, keeps the best scoring detection
+    Nms,
+    /// Weighted box fusion, averages the boxes weighted by score
+    Wbf,
+}
+
+/// Box on the sphere bounded by four great circles, corners as unit rays in outline order.
+///
+/// Straight box edges on a face (or any rectilinear view) are great circles, so detection
+/// boxes keep their exact shape.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SphericalBox {
+    pub corners: [Vector3<f32>; 4],
+}
+
+impl SphericalBox {
+    /// Box of a detection on a face or tile
+    pub(crate) fn from_detection(
+        detection: &Detection,
+        layout: &CubeLayout,
+    ) -> error::Result<SphericalBox> {
+        let view = layout.locate(&detection.file)?;
+        let face = CubeFace {
+            face: view.face,
+            size: layout.face_size,
+        };
+        let [x0, y0, x1, y1] = detection.bbox;
+        let offset = Vector2::new(view.offset.x as f32, view.offset.y as f32);
+        let corner = |x, y| {
+            face.pixel_to_ray(&(Vector2::new(x, y) + offset))
+                .map(|r| r.normalize())
+        };
+        let corners = [
+            corner(x0, y0),
+            corner(x1, y0),
+            corner(x1, y1),
+            corner(x0, y1),
+        ];
+        match corners {
+            [Some(a), Some(b), Some(c), Some(d)] => Ok(SphericalBox {
+                corners: [a, b, c, d],
+            }),
+            _ => Err(Error::Layout(format!(
+                "Detection in {} is outside of the face",
+                detection.file
+            ))),
+        }
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        self.corners.iter().sum::<Vector3<f32>>().normalize()
+    }
+
+    /// Normals of the edge planes, pointing inside
+    fn edge_normals(&self) -> [Vector3<f32>; 4] {
+        let center = self.center();
+        let mut normals = [Vector3::new(0.0, 0.0, 0.0); 4];
+        for (i, normal) in normals.iter_mut().enumerate() {
+            let n = self.corners[i].cross(self.corners[(i + 1) % 4]);
+            *normal = if n.dot(center) < 0.0 { -n } else { n };
+        }
+        normals
+    }
+
+    pub fn contains(&self, ray: &Vector3<f32>) -> bool {
+        self.edge_normals().iter().all(|n| n.dot(*ray) >= 0.0)
+    }
+
+    pub fn area(&self) -> f32 {
+        solid_angle(&self.corners)
+    }
+
+    /// Intersection over union by solid angle
+    pub(crate) fn iou(&self, other: &SphericalBox) -> f32 {
+        if self.center().dot(other.center()) <= 0.0 {
+            return 0.0;
+        }
+        let intersection =
+            other
+                .edge_normals()
+                .iter()
+                .fold(self.corners.to_vec(), |polygon, normal| {
+                    if polygon.is_empty() {
+                        polygon
+                    } else {
+                        clip_polygon(&polygon, |p| normal.dot(*p))
+                    }
+                });
+        let intersection = solid_angle(&intersection);
+        let union = self.area() + other.area() - intersection;
+        if union > 0.0 {
+            intersection / union
+        } else {
+            0.0
+        }
+    }
+
+    /// Outline rays, `edge_points` per edge
+    pub(crate) fn outline(&self, edge_points: u32) -> Vec<Vector3<f32>> {
+        let n = edge_points.max(1);
+        (0..4)
+            .flat_map(|edge| {
+                let (a, b) = (self.corners[edge], self.corners[(edge + 1) % 4]);
+                (0..n).map(move |i| (a + (b - a) * (i as f32 / n as f32)).normalize())
+            })
+            .collect()
+    }
+}
+
+/// Plane tangent to the sphere at `center` with gnomonic projection, keeps great circles straight
+struct Tangent {
+    center: Vector3<f32>,
+    right: Vector3<f32>,
+    down: Vector3<f32>,
+}
+
+impl Tangent {
+    fn at(center: Vector3<f32>) -> Tangent {
+        let reference = if center.dot(UP).abs() < 0.99 {
+            UP
+        } else {
+            FRONT
+        };
+        let right = center.cross(reference).normalize();
+        Tangent {
+            center,
+            right,
+            down: center.cross(right),
+        }
+    }
+
+    fn project(&self, ray: &Vector3<f32>) -> Vector2<f32> {
+        let depth = ray.dot(self.center).max(1e-3);
+        Vector2::new(ray.dot(self.right), ray.dot(self.down)) / depth
+    }
+
+    fn ray(&self, point: Vector2<f32>) -> Vector3<f32> {
+        (self.center + self.right * point.x + self.down * point.y).normalize()
+    }
+
+    /// `[x_min, y_min, x_max, y_max]` of the box on the plane
+    fn bounds(&self, spherical_box: &SphericalBox) -> [f32; 4] {
+        spherical_box.corners.iter().map(|c| self.project(c)).fold(
+            [
+                f32::INFINITY,
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+            ],
+            |[x0, y0, x1, y1], p| [x0.min(p.x), y0.min(p.y), x1.max(p.x), y1.max(p.y)],
+        )
+    }
+
+    fn spherical_box(&self, [x0, y0, x1, y1]: [f32; 4]) -> SphericalBox {
+        SphericalBox {
+            corners: [
+                self.ray(Vector2::new(x0, y0)),
+                self.ray(Vector2::new(x1, y0)),
+                self.ray(Vector2::new(x1, y1)),
+                self.ray(Vector2::new(x0, y1)),
+            ],
+        }
+    }
+}
+
+/// Detections of one object
+struct Cluster {
+    members: Vec<(SphericalBox, usize)>,
+    merged: SphericalBox,
+}
+
+/// Score weighted average of the boxes, in the plane tangent at their weighted center
+fn fuse(members: &[(SphericalBox, usize)], weights: &[f32]) -> SphericalBox {
+    let total = members.iter().map(|(_, i)| weights[*i]).sum::<f32>();
+    // members all scored 0 count equally
+    let uniform = total <= 0.0;
+    let weight = |i: usize| if uniform { 1.0 } else { weights[i] };
+    let total = if uniform { members.len() as f32 } else { total };
+    let center = members
+        .iter()
+        .map(|(b, i)| b.center() * weight(*i))
+        .sum::<Vector3<f32>>()
+        .normalize();
+    let tangent = Tangent::at(center);
+    let mut bounds = [0.0; 4];
+    for (spherical_box, i) in members {
+        for (sum, value) in bounds.iter_mut().zip(tangent.bounds(spherical_box)) {
+            *sum += value * weight(*i) / total;
+        }
+    }
+    tangent.spherical_box(bounds)
+}
+
+/// Merges detections of the same label whose boxes overlap on the sphere by more than
+/// `iou_threshold` and maps the results to the equirectangular panorama laid out as `equ`
+pub fn merge_detections(
+    detections: &[Detection],
+    layout: &CubeLayout,
+    equ: &EquLayout,
+    edge_points: u32,
+    method: MergeMethod,
+    iou_threshold: f32,
+) -> error::Result<Vec<ProjectedDetection>> {
+    let boxes = detections
+        .iter()
+        .map(|d| SphericalBox::from_detection(d, layout))
+        .collect::<error::Result<Vec<_>>>()?;
+    let weights = detections
+        .iter()
+        .map(|d| d.score.unwrap_or(1.0))
+        .collect::<Vec<_>>();
+    let mut order = (0..detections.len()).collect::<Vec<_>>();
+    // stable sort keeps input order for equal scores so the result is deterministic
+    order.sort_by(|a, b| weights[*b].total_cmp(&weights[*a]));
+
+    let mut clusters: Vec<Cluster> = vec![];
+    for i in order {
+        let matching = clusters.iter().position(|c| {
+            detections[c.members[0].1].label == detections[i].label
+                && c.merged.iou(&boxes[i]) > iou_threshold
+        });
+        match matching {
+            Some(c) => {
+                let cluster = &mut clusters[c];
+                cluster.members.push((boxes[i], i));
+                if method == MergeMethod::Wbf {
+                    cluster.merged = fuse(&cluster.members, &weights);
+                }
+            }
+            None => clusters.push(Cluster {
+                members: vec![(boxes[i], i)],
+                merged: boxes[i],
+            }),
+        }
+    }
+
+    clusters
+        .iter()
+        .map(|cluster| {
+            let best = &detections[cluster.members[0].1];
+            let scores = cluster
+                .members
+                .iter()
+                .filter_map(|(_, i)| detections[*i].score)
+                .collect::<Vec<_>>();
+            let score = match method {
+                MergeMethod::Nms => best.score,
+                MergeMethod::Wbf if scores.is_empty() => None,
+                MergeMethod::Wbf => Some(scores.iter().sum::<f32>() / scores.len() as f32),
+            };
+            let pole = [UP, DOWN]
+                .into_iter()
+                .find(|pole| cluster.merged.contains(&world_ray(equ, pole)));
+            let (polygon, bbox, wraps) =
+                rays_to_equ(&cluster.merged.outline(edge_points), pole, equ).ok_or(
+                    Error::Layout(format!(
+                        "Merged detection from {} can't be projected",
+                        best.file
+                    )),
+                )?;
+            Ok(ProjectedDetection {
+                file: best.file.clone(),
+                label: best.label.clone(),
+                score,
+                polygon,
+                bbox,
+                wraps,
+                merged: cluster
+                    .members
+                    .iter()
+                    .map(|(_, i)| detections[*i].file.clone())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projection::CroppedArea;
+
+    fn equ() -> EquLayout {
+        EquLayout {
+            area: CroppedArea::full(Vector2::new(400, 200)),
+            orientation: None,
+        }
+    }
+
+    fn layout() -> CubeLayout {
+        CubeLayout {
+            face_size: 100,
+            patch_size: 50,
+        }
+    }
+
+    fn detection(file: &str, bbox: [f32; 4], score: f32) -> Detection {
+        Detection {
+            file: file.to_string(),
+            bbox,
+            label: "car".to_string(),
+            score: Some(score),
+        }
+    }
+
+    #[test]
+    fn same_box_on_face_and_tile_has_unit_iou() {
+        let face = SphericalBox::from_detection(
+            &detection("front.jpg", [60.0, 60.0, 80.0, 80.0], 1.0),
+            &layout(),
+        )
+        .unwrap();
+        let tile = SphericalBox::from_detection(
+            &detection("front_p2_3.jpg", [10.0, 10.0, 30.0, 30.0], 1.0),
+            &layout(),
+        )
+        .unwrap();
+        assert!((face.iou(&tile) - 1.0).abs() < 1e-3);
+        let shifted = SphericalBox::from_detection(
+            &detection("front.jpg", [70.0, 60.0, 90.0, 80.0], 1.0),
+            &layout(),
+        )
+        .unwrap();
+        let iou = face.iou(&shifted);
+        assert!(iou > 0.25 && iou < 0.4, "{}", iou);
+    }
+
+    #[test]
+    fn duplicates_from_face_and_tile_are_merged() {
+        let detections = [
+            detection("front.jpg", [60.0, 40.0, 90.0, 60.0], 0.6),
+            detection("front_p2_2.jpg", [12.0, 42.0, 42.0, 62.0], 0.9),
+            detection("back.jpg", [40.0, 40.0, 60.0, 60.0], 0.7),
+            detection("front.jpg", [10.0, 10.0, 20.0, 20.0], 0.8),
+        ];
+        let nms =
+            merge_detections(&detections, &layout(), &equ(), 4, MergeMethod::Nms, 0.5).unwrap();
+        assert_eq!(nms.len(), 3);
+        assert_eq!(nms[0].merged, vec!["front_p2_2.jpg", "front.jpg"]);
+        assert_eq!(nms[0].score, Some(0.9));
+
+        let wbf = merge_detections(
+            &detections[..2],
+            &layout(),
+            &equ(),
+            4,
+            MergeMethod::Wbf,
+            0.5,
+        )
+        .unwrap();
+        assert_eq!(wbf.len(), 1);
+        assert!((wbf[0].score.unwrap() - 0.75).abs() < 1e-6);
+        // fused box lies between the two boxes, weighted towards the tile detection
+        let single = |d: &Detection| {
+            merge_detections(
+                std::slice::from_ref(d),
+                &layout(),
+                &equ(),
+                4,
+                MergeMethod::Wbf,
+                0.5,
+            )
+            .unwrap()[0]
+                .bbox[0]
+        };
+        let (face_x, tile_x) = (single(&detections[0]), single(&detections[1]));
+        let fused_x = wbf[0].bbox[0];
+        assert!(fused_x > face_x && fused_x < tile_x);
+        assert!(fused_x - face_x > tile_x - fused_x);
+    }
+
+    #[test]
+    fn boxes_are_merged_across_the_seam() {
+        let detections = [
+            detection("back.jpg", [40.0, 40.0, 60.0, 60.0], 0.9),
+            detection("back.jpg", [42.0, 40.0, 62.0, 60.0], 0.8),
+        ];
+        let merged =
+            merge_detections(&detections, &layout(), &equ(), 4, MergeMethod::Wbf, 0.5).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert!(merged[0].wraps);
+    }
+
+    #[test]
+    fn boxes_scored_zero_are_fused_evenly() {
+        let detections = [
+            detection("front.jpg", [40.0, 40.0, 60.0, 60.0], 0.0),
+            detection("front.jpg", [44.0, 40.0, 64.0, 60.0], 0.0),
+        ];
+        let merged =
+            merge_detections(&detections, &layout(), &equ(), 4, MergeMethod::Wbf, 0.5).unwrap();
+        assert_eq!(merged.len(), 1);
+        assert!(merged[0].bbox.iter().all(|v| v.is_finite()));
+        let x = |d: &Detection| {
+            merge_detections(
+                std::slice::from_ref(d),
+                &layout(),
+                &equ(),
+                4,
+                MergeMethod::Wbf,
+                0.5,
+            )
+            .unwrap()[0]
+                .bbox[0]
+        };
+        let middle = (x(&detections[0]) + x(&detections[1])) / 2.0;
+        assert!((merged[0].bbox[0] - middle).abs() < 0.1);
+    }
+}
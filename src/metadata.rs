@@ -0,0 +1,244 @@
+use crate::error::{self, Error};
+use cgmath::Vector2;
+use exif::experimental::Writer;
+use exif::{Field, In, Rational, Reader, Tag, Value};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::{ColorType, DynamicImage, ImageFormat, RgbaImage};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Fields that describe the source image itself and must not be copied to derived images
+const DROPPED_TAGS: [Tag; 6] = [
+    Tag::ImageWidth,
+    Tag::ImageLength,
+    Tag::Orientation,
+    Tag::PixelXDimension,
+    Tag::PixelYDimension,
+    Tag::MakerNote,
+];
+
+/// EXIF (GPS, timestamps, camera) of a source image, to be written into derived images
+#[derive(Clone, Debug)]
+pub struct SourceExif {
+    fields: Vec<Field>,
+    little_endian: bool,
+}
+
+impl SourceExif {
+    /// Reads EXIF from any container supported by kamadak-exif (JPEG, PNG, TIFF, HEIF, WebP)
+    pub fn read(bytes: &[u8]) -> Option<SourceExif> {
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(bytes))
+            .ok()?;
+        Some(SourceExif {
+            fields: exif
+                .fields()
+                .filter(|f| f.ifd_num == In::PRIMARY && !DROPPED_TAGS.contains(&f.tag))
+                .cloned()
+                .collect(),
+            little_endian: exif.little_endian(),
+        })
+    }
+
+    /// Compass direction the camera was pointing to (GPSImgDirection) in degrees
+    pub fn heading(&self) -> Option<f32> {
+        self.fields
+            .iter()
+            .find(|f| f.tag == Tag::GPSImgDirection)
+            .and_then(|f| match &f.value {
+                Value::Rational(v) => v.first().map(|r| r.to_f64() as f32),
+                _ => None,
+            })
+    }
+
+    /// TIFF structure for an image of `dimensions`, replacing GPSImgDirection with `heading`
+    pub fn encode(&self, dimensions: Vector2<u32>, heading: Option<f32>) -> error::Result<Vec<u8>> {
+        let mut fields = self
+            .fields
+            .iter()
+            .filter(|f| {
+                heading.is_none()
+                    || (f.tag != Tag::GPSImgDirection && f.tag != Tag::GPSImgDirectionRef)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        fields.push(Field {
+            tag: Tag::PixelXDimension,
+            ifd_num: In::PRIMARY,
+            value: Value::Long(vec![dimensions.x]),
+        });
+        fields.push(Field {
+            tag: Tag::PixelYDimension,
+            ifd_num: In::PRIMARY,
+            value: Value::Long(vec![dimensions.y]),
+        });
+        if let Some(heading) = heading {
+            fields.push(Field {
+                tag: Tag::GPSImgDirectionRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"T".to_vec()]),
+            });
+            fields.push(Field {
+                tag: Tag::GPSImgDirection,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![Rational {
+                    num: (heading.rem_euclid(360.0) * 100.0).round() as u32,
+                    denom: 100,
+                }]),
+            });
+        }
+        let mut writer = Writer::new();
+        fields.iter().for_each(|f| writer.push_field(f));
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, self.little_endian)?;
+        Ok(tiff.into_inner())
+    }
+
+    /// EXIF APP1 segment payload for JPEG
+    pub fn app1(&self, dimensions: Vector2<u32>, heading: Option<f32>) -> error::Result<Vec<u8>> {
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend(self.encode(dimensions, heading)?);
+        Ok(payload)
+    }
+}
+
+/// Inserts APP1 segments into JPEG data after SOI, JFIF header and already existing APP1 segments
+pub(crate) fn insert_jpeg_app1(jpeg: &[u8], payloads: &[Vec<u8>]) -> error::Result<Vec<u8>> {
+    if jpeg.len() < 4 || jpeg[0..2] != [0xFF, 0xD8] {
+        return Err(Error::format("Not a JPEG image"));
+    }
+    let mut position = 2;
+    while jpeg.len() > position + 4
+        && jpeg[position] == 0xFF
+        && (jpeg[position + 1] == 0xE0 || jpeg[position + 1] == 0xE1)
+    {
+        position += 2 + u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+    }
+    let mut output =
+        Vec::with_capacity(jpeg.len() + payloads.iter().map(|p| p.len() + 4).sum::<usize>());
+    output.extend_from_slice(&jpeg[..position]);
+    for payload in payloads {
+        let segment_length = payload.len() + 2;
+        if segment_length > u16::MAX as usize {
+            return Err(Error::Unsupported(
+                "Metadata too large for JPEG segment".to_string(),
+            ));
+        }
+        output.extend_from_slice(&[0xFF, 0xE1]);
+        output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+        output.extend_from_slice(payload);
+    }
+    output.extend_from_slice(&jpeg[position..]);
+    Ok(output)
+}
+
+pub(crate) fn is_jpeg_path(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| e == "jpg" || e == "jpeg")
+}
+
+/// Encodes image as `format`, embedding the APP1 payloads (EXIF, XMP) when it is JPEG
+pub fn encode_with_app1(
+    image: &RgbaImage,
+    format: ImageFormat,
+    payloads: &[Vec<u8>],
+) -> error::Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let mut bytes = Cursor::new(vec![]);
+    match format {
+        ImageFormat::Jpeg => {
+            JpegEncoder::new(&mut bytes).encode(image, width, height, ColorType::Rgba8)?
+        }
+        ImageFormat::Png => {
+            PngEncoder::new(&mut bytes).encode(image, width, height, ColorType::Rgba8)?
+        }
+        ImageFormat::Tiff => {
+            TiffEncoder::new(&mut bytes).encode(image, width, height, ColorType::Rgba8)?
+        }
+        _ => DynamicImage::ImageRgba8(image.clone()).write_to(&mut bytes, format)?,
+    }
+    match format == ImageFormat::Jpeg && !payloads.is_empty() {
+        true => insert_jpeg_app1(&bytes.into_inner(), payloads),
+        false => Ok(bytes.into_inner()),
+    }
+}
+
+/// Saves image, embedding the APP1 payloads (EXIF, XMP) when it is written as JPEG
+pub fn save_with_app1(image: &RgbaImage, path: &Path, payloads: &[Vec<u8>]) -> error::Result<()> {
+    let format = ImageFormat::from_path(path).map_err(Error::image(path))?;
+    let bytes = encode_with_app1(image, format, payloads).map_err(|e| e.at(path))?;
+    fs::write(path, bytes).map_err(Error::io(path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        fields.iter().for_each(|f| writer.push_field(f));
+        let mut tiff = Cursor::new(vec![]);
+        writer.write(&mut tiff, false).unwrap();
+        let mut payload = EXIF_HEADER.to_vec();
+        payload.extend(tiff.into_inner());
+        let mut jpeg = vec![];
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbaImage::new(8, 8))
+            .unwrap();
+        insert_jpeg_app1(&jpeg, &[payload]).unwrap()
+    }
+
+    #[test]
+    fn exif_is_copied_with_new_dimensions_and_heading() {
+        let source = jpeg_with_exif(&[
+            Field {
+                tag: Tag::DateTimeOriginal,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![b"2022:02:02 12:00:00".to_vec()]),
+            },
+            Field {
+                tag: Tag::PixelXDimension,
+                ifd_num: In::PRIMARY,
+                value: Value::Long(vec![8000]),
+            },
+            Field {
+                tag: Tag::GPSImgDirection,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![Rational { num: 45, denom: 1 }]),
+            },
+        ]);
+        let exif = SourceExif::read(&source).unwrap();
+        assert_eq!(exif.heading(), Some(45.0));
+
+        let mut jpeg = vec![];
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbaImage::new(4, 4))
+            .unwrap();
+        let face = insert_jpeg_app1(
+            &jpeg,
+            &[exif.app1(Vector2::new(4, 4), Some(135.0)).unwrap()],
+        )
+        .unwrap();
+        assert!(image::load_from_memory(&face).is_ok());
+        let copied = Reader::new()
+            .read_from_container(&mut Cursor::new(&face))
+            .unwrap();
+        let field = |tag| {
+            copied
+                .get_field(tag, In::PRIMARY)
+                .unwrap()
+                .display_value()
+                .to_string()
+        };
+        assert_eq!(field(Tag::PixelXDimension), "4");
+        assert_eq!(field(Tag::DateTimeOriginal), "2022-02-02 12:00:00");
+        assert_eq!(SourceExif::read(&face).unwrap().heading(), Some(135.0));
+    }
+}
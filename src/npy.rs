@@ -0,0 +1,251 @@
+use crate::error::{self, Error};
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Element types we read, written ones are `U8` and `F32`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dtype {
+    U8,
+    U16,
+    F32,
+    F64,
+}
+
+impl Dtype {
+    fn descr(&self) -> &'static str {
+        match self {
+            Dtype::U8 => "|u1",
+            Dtype::U16 => "<u2",
+            Dtype::F32 => "<f4",
+            Dtype::F64 => "<f8",
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Dtype::U8 => 1,
+            Dtype::U16 => 2,
+            Dtype::F32 => 4,
+            Dtype::F64 => 8,
+        }
+    }
+
+    fn from_descr(descr: &str) -> Option<Dtype> {
+        match descr {
+            "|u1" | "<u1" => Some(Dtype::U8),
+            "<u2" => Some(Dtype::U16),
+            "<f4" => Some(Dtype::F32),
+            "<f8" => Some(Dtype::F64),
+            _ => None,
+        }
+    }
+}
+
+/// Element type of image arrays written by `split`
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelType {
+    /// 0..255 as in the images
+    Uint8,
+    /// Normalised to 0..1
+    Float32,
+}
+
+impl PixelType {
+    pub fn dtype(&self) -> Dtype {
+        match self {
+            PixelType::Uint8 => Dtype::U8,
+            PixelType::Float32 => Dtype::F32,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelType::Uint8 => "uint8",
+            PixelType::Float32 => "float32",
+        }
+    }
+
+    /// Multiplier from 8 bit pixel value to stored value
+    pub fn scale(&self) -> f32 {
+        match self {
+            PixelType::Uint8 => 1.0,
+            PixelType::Float32 => 1.0 / 255.0,
+        }
+    }
+}
+
+/// C-ordered NumPy array, values widened to f32
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    /// Type stored in the file
+    pub dtype: Dtype,
+    pub data: Vec<f32>,
+}
+
+/// Value of `'key': ` in the header dictionary, up to the next comma outside parentheses
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}'", key))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(rest[..end].trim())
+}
+
+pub fn parse(bytes: &[u8]) -> error::Result<NpyArray> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(Error::format("Not a NumPy .npy file"));
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        _ if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => return Err(Error::format("Truncated .npy header")),
+    };
+    let header = std::str::from_utf8(
+        bytes
+            .get(header_start..header_start + header_len)
+            .ok_or(Error::format("Truncated .npy header"))?,
+    )
+    .map_err(Error::format)?;
+    let descr = header_value(header, "descr")
+        .map(|d| d.trim_matches(|c| c == '\'' || c == '"'))
+        .ok_or(Error::format("Missing dtype in .npy header"))?;
+    let dtype = Dtype::from_descr(descr).ok_or(Error::Unsupported(format!(
+        "Unsupported .npy dtype {}",
+        descr
+    )))?;
+    if header_value(header, "fortran_order") == Some("True") {
+        return Err(Error::Unsupported(
+            "Fortran ordered .npy arrays are not supported".to_string(),
+        ));
+    }
+    let shape = header_value(header, "shape")
+        .ok_or(Error::format("Missing shape in .npy header"))?
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::format)?;
+    let count = shape.iter().product::<usize>();
+    let body = &bytes[header_start + header_len..];
+    if body.len() < count * dtype.size() {
+        return Err(Error::format(format!(
+            "NumPy array data is shorter than its shape {:?}",
+            shape
+        )));
+    }
+    let chunks = body[..count * dtype.size()].chunks_exact(dtype.size());
+    let data = match dtype {
+        Dtype::U8 => chunks.map(|c| c[0] as f32).collect(),
+        Dtype::U16 => chunks
+            .map(|c| u16::from_le_bytes([c[0], c[1]]) as f32)
+            .collect(),
+        Dtype::F32 => chunks
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        Dtype::F64 => chunks
+            .map(|c| f64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]) as f32)
+            .collect(),
+    };
+    Ok(NpyArray { shape, dtype, data })
+}
+
+pub fn read(path: &Path) -> error::Result<NpyArray> {
+    parse(&fs::read(path).map_err(Error::io(path))?).map_err(|e| e.at(path))
+}
+
+/// File contents for array of `shape` holding `data` as `dtype` (U8 values are rounded and clamped)
+pub fn encode(shape: &[usize], dtype: Dtype, data: &[f32]) -> Vec<u8> {
+    let shape = match shape {
+        [n] => format!("({},)", n),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        dtype.descr(),
+        shape
+    );
+    // magic, version and length take 10 bytes, data starts 64 byte aligned
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&[1, 0]);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.reserve(data.len() * dtype.size());
+    for value in data {
+        match dtype {
+            Dtype::U8 => bytes.push(value.round().clamp(0.0, 255.0) as u8),
+            Dtype::U16 => {
+                bytes.extend_from_slice(&(value.round().clamp(0.0, 65535.0) as u16).to_le_bytes())
+            }
+            Dtype::F32 => bytes.extend_from_slice(&value.to_le_bytes()),
+            Dtype::F64 => bytes.extend_from_slice(&(*value as f64).to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+pub fn write(path: &Path, shape: &[usize], dtype: Dtype, data: &[f32]) -> error::Result<()> {
+    fs::write(path, encode(shape, dtype, data)).map_err(Error::io(path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrays_round_trip() {
+        let data = (0..24).map(|v| v as f32 * 0.5).collect::<Vec<_>>();
+        for dtype in [Dtype::F32, Dtype::F64] {
+            let bytes = encode(&[2, 3, 4], dtype, &data);
+            assert_eq!((bytes.len() - data.len() * dtype.size()) % 64, 0);
+            assert_eq!(
+                parse(&bytes).unwrap(),
+                NpyArray {
+                    shape: vec![2, 3, 4],
+                    dtype,
+                    data: data.clone()
+                }
+            );
+        }
+        let bytes = encode(&[24], Dtype::U8, &data);
+        let array = parse(&bytes).unwrap();
+        assert_eq!(array.shape, vec![24]);
+        assert_eq!(array.data[3], 2.0);
+    }
+
+    #[test]
+    fn numpy_written_header_is_parsed() {
+        let mut bytes = MAGIC.to_vec();
+        let header = "{'descr': '<u2', 'fortran_order': False, 'shape': (1, 2), }          \n";
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(&[1, 0, 0, 1]);
+        let array = parse(&bytes).unwrap();
+        assert_eq!(array.shape, vec![1, 2]);
+        assert_eq!(array.data, vec![1.0, 256.0]);
+    }
+}
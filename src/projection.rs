@@ -0,0 +1,732 @@
+use crate::error::{self, Error};
+use cgmath::{InnerSpace, Matrix3, Rad, Vector2, Vector3};
+use image::{Rgba, RgbaImage};
+use std::f32::consts::PI;
+
+use crate::{par_from_fn, BACK, DOWN, FRONT, LEFT, RIGHT, UP};
+
+/// Maps image pixels to unit rays from the center of the sphere and back.
+///
+/// Pixel coordinates are continuous: pixel `(x, y)` covers `[x, x + 1) × [y, y + 1)`.
+/// `None` means the pixel or the ray is not covered by the projection.
+pub trait Projection: Sync {
+    fn dimensions(&self) -> Vector2<u32>;
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>>;
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>>;
+}
+
+/// Rotation from camera frame to world frame for camera turned by yaw (right positive),
+/// pitch (up positive) and roll (around view axis), all in degrees
+pub fn rotation_from_angles(yaw: f32, pitch: f32, roll: f32) -> Matrix3<f32> {
+    Matrix3::from_angle_z(Rad(-yaw.to_radians()))
+        * Matrix3::from_angle_x(Rad(pitch.to_radians()))
+        * Matrix3::from_angle_y(Rad(roll.to_radians()))
+}
+
+/// Viewing direction of a perspective-like projection (same vectors as `face_pixel2ray` takes)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Basis {
+    pub center: Vector3<f32>,
+    pub down: Vector3<f32>,
+    pub right: Vector3<f32>,
+}
+
+impl Basis {
+    /// Camera looking forward, rotated by roll (around view axis), pitch (up positive)
+    /// and yaw (right positive), all in degrees.
+    pub fn from_angles(yaw: f32, pitch: f32, roll: f32) -> Basis {
+        let rotation = rotation_from_angles(yaw, pitch, roll);
+        Basis {
+            center: rotation * FRONT,
+            down: rotation * DOWN,
+            right: rotation * RIGHT,
+        }
+    }
+}
+
+impl Default for Basis {
+    fn default() -> Self {
+        Face::Front.basis()
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Face {
+    Front,
+    Back,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::Front,
+        Face::Back,
+        Face::Left,
+        Face::Right,
+        Face::Up,
+        Face::Down,
+    ];
+
+    /// Face orientation as used by `equ2cube`
+    pub fn basis(&self) -> Basis {
+        let (center, down, right) = match self {
+            Face::Front => (FRONT, DOWN, RIGHT),
+            Face::Back => (BACK, DOWN, LEFT),
+            Face::Left => (LEFT, DOWN, FRONT),
+            Face::Right => (RIGHT, DOWN, BACK),
+            Face::Up => (UP, BACK, LEFT),
+            Face::Down => (DOWN, BACK, RIGHT),
+        };
+        Basis {
+            center,
+            down,
+            right,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Face::Front => "front",
+            Face::Back => "back",
+            Face::Left => "left",
+            Face::Right => "right",
+            Face::Up => "up",
+            Face::Down => "down",
+        }
+    }
+}
+
+fn inside(pixel: Vector2<f32>, dimensions: Vector2<u32>) -> Option<Vector2<f32>> {
+    if pixel.x >= 0.0
+        && pixel.y >= 0.0
+        && pixel.x < dimensions.x as f32
+        && pixel.y < dimensions.y as f32
+    {
+        Some(pixel)
+    } else {
+        None
+    }
+}
+
+/// Part of the full sphere panorama covered by an equirectangular image, in pixels of the
+/// full panorama (same fields as GPano `CroppedArea*` / `FullPano*` XMP properties)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CroppedArea {
+    pub full_width: u32,
+    pub full_height: u32,
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CroppedArea {
+    /// Image covering the whole sphere
+    pub fn full(dimensions: Vector2<u32>) -> CroppedArea {
+        CroppedArea {
+            full_width: dimensions.x,
+            full_height: dimensions.y,
+            left: 0,
+            top: 0,
+            width: dimensions.x,
+            height: dimensions.y,
+        }
+    }
+
+    /// Side of the cube faces the full panorama is split into, a quarter of its width
+    pub fn face_size(&self) -> error::Result<u32> {
+        match self.full_width / 4 {
+            0 => Err(Error::Layout(format!(
+                "Panorama {} pixels wide is too narrow for cube faces, 4 are needed",
+                self.full_width
+            ))),
+            size => Ok(size),
+        }
+    }
+
+    /// Image of `dimensions` covering longitudes `[west, east]` (0 is front, positive to the right)
+    /// and latitudes `[south, north]` (positive up), all in degrees
+    pub fn from_degrees(
+        dimensions: Vector2<u32>,
+        longitude: (f32, f32),
+        latitude: (f32, f32),
+    ) -> error::Result<CroppedArea> {
+        let (west, east) = longitude;
+        let (south, north) = latitude;
+        let lon_span = (east - west).rem_euclid(360.0);
+        let lon_span = if lon_span == 0.0 { 360.0 } else { lon_span };
+        let lat_span = north - south;
+        if !(-90.0..=90.0).contains(&south) || !(-90.0..=90.0).contains(&north) || lat_span <= 0.0 {
+            return Err(Error::Layout(format!(
+                "Invalid latitude range {}..{}",
+                south, north
+            )));
+        }
+        let full_width = (dimensions.x as f32 * 360.0 / lon_span).round() as u32;
+        let full_height = (dimensions.y as f32 * 180.0 / lat_span).round() as u32;
+        Ok(CroppedArea {
+            full_width,
+            full_height,
+            left: (((west + 180.0).rem_euclid(360.0)) / 360.0 * full_width as f32).round() as u32,
+            top: ((90.0 - north) / 180.0 * full_height as f32).round() as u32,
+            width: dimensions.x,
+            height: dimensions.y,
+        })
+    }
+
+    /// Part of full panorama of `full_dimensions` covering given longitudes and latitudes
+    /// (same conventions as `from_degrees`)
+    pub fn within(
+        full_dimensions: Vector2<u32>,
+        longitude: (f32, f32),
+        latitude: (f32, f32),
+    ) -> error::Result<CroppedArea> {
+        let (west, east) = longitude;
+        let (south, north) = latitude;
+        let lon_span = (east - west).rem_euclid(360.0);
+        let lon_span = if lon_span == 0.0 { 360.0 } else { lon_span };
+        let area = CroppedArea::from_degrees(
+            Vector2::new(
+                (full_dimensions.x as f32 * lon_span / 360.0).round() as u32,
+                (full_dimensions.y as f32 * (north - south) / 180.0).round() as u32,
+            ),
+            longitude,
+            latitude,
+        )?;
+        Ok(CroppedArea {
+            full_width: full_dimensions.x,
+            full_height: full_dimensions.y,
+            ..area
+        })
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.width == self.full_width && self.height == self.full_height
+    }
+
+    /// Full panorama pixel to pixel of the cropped image, `None` when it is not covered.
+    /// Coverage may wrap around the 0/360 degree seam.
+    pub fn image_pixel(&self, full_pixel: Vector2<f32>) -> Option<Vector2<f32>> {
+        let pixel = Vector2::new(
+            (full_pixel.x - self.left as f32).rem_euclid(self.full_width as f32),
+            full_pixel.y - self.top as f32,
+        );
+        inside(pixel, Vector2::new(self.width, self.height))
+    }
+
+    /// Pixel of the cropped image to full panorama pixel
+    pub fn full_pixel(&self, pixel: Vector2<f32>) -> Vector2<f32> {
+        Vector2::new(
+            (pixel.x + self.left as f32).rem_euclid(self.full_width as f32),
+            pixel.y + self.top as f32,
+        )
+    }
+}
+
+/// Equirectangular panorama, longitude on x-axis (back at the edges, front in the middle),
+/// latitude on y-axis. The image may cover only a part of the sphere.
+pub struct Equirectangular {
+    pub area: CroppedArea,
+}
+
+impl Projection for Equirectangular {
+    fn dimensions(&self) -> Vector2<u32> {
+        Vector2::new(self.area.width, self.area.height)
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        let full = self.area.full_pixel(*pixel);
+        let longitude = full.x / self.area.full_width as f32 * PI * 2.0 - PI;
+        let inclination = full.y / self.area.full_height as f32 * PI;
+        Some(Vector3::new(
+            inclination.sin() * longitude.sin(),
+            inclination.sin() * longitude.cos(),
+            inclination.cos(),
+        ))
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let ray = ray.normalize();
+        let longitude = PI + ray.x.atan2(ray.y);
+        let inclination = ray.z.clamp(-1.0, 1.0).acos();
+        let full = Vector2::new(
+            (longitude / (PI * 2.0) * self.area.full_width as f32)
+                .rem_euclid(self.area.full_width as f32),
+            (inclination / PI * self.area.full_height as f32)
+                .min(self.area.full_height as f32 - 0.5),
+        );
+        self.area.image_pixel(full)
+    }
+}
+
+/// Pinhole camera with horizontal field of view `fov` (degrees)
+#[non_exhaustive]
+pub struct Rectilinear {
+    pub dimensions: Vector2<u32>,
+    pub fov: f32,
+    pub basis: Basis,
+}
+
+impl Rectilinear {
+    fn focal_length(&self) -> f32 {
+        (self.dimensions.x as f32 / 2.0) / (self.fov.to_radians() / 2.0).tan()
+    }
+}
+
+impl Projection for Rectilinear {
+    fn dimensions(&self) -> Vector2<u32> {
+        self.dimensions
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        let f = self.focal_length();
+        let b = &self.basis;
+        let dx = pixel.x - self.dimensions.x as f32 / 2.0;
+        let dy = pixel.y - self.dimensions.y as f32 / 2.0;
+        Some((b.center * f + b.right * dx + b.down * dy).normalize())
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let b = &self.basis;
+        let z = ray.dot(b.center);
+        if z <= 0.0 {
+            return None;
+        }
+        let f = self.focal_length();
+        let pixel = Vector2::new(
+            self.dimensions.x as f32 / 2.0 + f * ray.dot(b.right) / z,
+            self.dimensions.y as f32 / 2.0 + f * ray.dot(b.down) / z,
+        );
+        inside(pixel, self.dimensions)
+    }
+}
+
+/// Single square cubemap face, a 90 degree rectilinear view
+#[non_exhaustive]
+pub struct CubeFace {
+    pub face: Face,
+    pub size: u32,
+}
+
+impl CubeFace {
+    fn camera(&self) -> Rectilinear {
+        Rectilinear {
+            dimensions: Vector2::new(self.size, self.size),
+            fov: 90.0,
+            basis: self.face.basis(),
+        }
+    }
+}
+
+impl Projection for CubeFace {
+    fn dimensions(&self) -> Vector2<u32> {
+        Vector2::new(self.size, self.size)
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        self.camera().pixel_to_ray(pixel)
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        self.camera().ray_to_pixel(ray)
+    }
+}
+
+/// Equidistant fisheye, image circle fills the shorter image side
+#[non_exhaustive]
+pub struct Fisheye {
+    pub dimensions: Vector2<u32>,
+    pub fov: f32,
+    pub basis: Basis,
+}
+
+/// Angle from the optical axis to image radius, shared by the radial projections
+fn radial_pixel_to_ray(
+    dimensions: Vector2<u32>,
+    basis: &Basis,
+    fov: f32,
+    pixel: &Vector2<f32>,
+    radius_to_angle: impl Fn(f32) -> f32,
+) -> Option<Vector3<f32>> {
+    let dx = pixel.x - dimensions.x as f32 / 2.0;
+    let dy = pixel.y - dimensions.y as f32 / 2.0;
+    let r = (dx * dx + dy * dy).sqrt();
+    let theta = radius_to_angle(r);
+    if theta > fov.to_radians() / 2.0 {
+        return None;
+    }
+    if r == 0.0 {
+        return Some(basis.center);
+    }
+    let side = (basis.right * dx + basis.down * dy) / r;
+    Some((basis.center * theta.cos() + side * theta.sin()).normalize())
+}
+
+fn radial_ray_to_pixel(
+    dimensions: Vector2<u32>,
+    basis: &Basis,
+    fov: f32,
+    ray: &Vector3<f32>,
+    angle_to_radius: impl Fn(f32) -> f32,
+) -> Option<Vector2<f32>> {
+    let ray = ray.normalize();
+    let theta = ray.dot(basis.center).clamp(-1.0, 1.0).acos();
+    if theta > fov.to_radians() / 2.0 {
+        return None;
+    }
+    let side = Vector2::new(ray.dot(basis.right), ray.dot(basis.down));
+    let side_len = side.magnitude();
+    let direction = if side_len > 0.0 {
+        side / side_len
+    } else {
+        Vector2::new(0.0, 0.0)
+    };
+    let center = dimensions.map(|d| d as f32 / 2.0);
+    inside(center + direction * angle_to_radius(theta), dimensions)
+}
+
+impl Fisheye {
+    fn focal_length(&self) -> f32 {
+        (self.dimensions.x.min(self.dimensions.y) as f32 / 2.0) / (self.fov.to_radians() / 2.0)
+    }
+}
+
+impl Projection for Fisheye {
+    fn dimensions(&self) -> Vector2<u32> {
+        self.dimensions
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        let f = self.focal_length();
+        radial_pixel_to_ray(self.dimensions, &self.basis, self.fov, pixel, |r| r / f)
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let f = self.focal_length();
+        radial_ray_to_pixel(self.dimensions, &self.basis, self.fov, ray, |t| t * f)
+    }
+}
+
+/// Stereographic ("little planet") projection, `fov` circle fills the shorter image side
+#[non_exhaustive]
+pub struct Stereographic {
+    pub dimensions: Vector2<u32>,
+    pub fov: f32,
+    pub basis: Basis,
+}
+
+impl Stereographic {
+    fn focal_length(&self) -> f32 {
+        (self.dimensions.x.min(self.dimensions.y) as f32 / 2.0)
+            / (2.0 * (self.fov.to_radians() / 4.0).tan())
+    }
+}
+
+impl Projection for Stereographic {
+    fn dimensions(&self) -> Vector2<u32> {
+        self.dimensions
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        let f = self.focal_length();
+        radial_pixel_to_ray(self.dimensions, &self.basis, self.fov, pixel, |r| {
+            2.0 * (r / (2.0 * f)).atan()
+        })
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let f = self.focal_length();
+        radial_ray_to_pixel(self.dimensions, &self.basis, self.fov, ray, |t| {
+            2.0 * f * (t / 2.0).tan()
+        })
+    }
+}
+
+/// Full 360 degrees horizontally (same longitudes as equirectangular), vertical `fov` in degrees
+#[non_exhaustive]
+pub struct Cylindrical {
+    pub dimensions: Vector2<u32>,
+    pub fov: f32,
+}
+
+impl Cylindrical {
+    fn half_height(&self) -> f32 {
+        (self.fov.to_radians() / 2.0).tan()
+    }
+}
+
+impl Projection for Cylindrical {
+    fn dimensions(&self) -> Vector2<u32> {
+        self.dimensions
+    }
+    fn pixel_to_ray(&self, pixel: &Vector2<f32>) -> Option<Vector3<f32>> {
+        let longitude = pixel.x / self.dimensions.x as f32 * PI * 2.0 - PI;
+        let height = (0.5 - pixel.y / self.dimensions.y as f32) * 2.0 * self.half_height();
+        Some(Vector3::new(longitude.sin(), longitude.cos(), height).normalize())
+    }
+    fn ray_to_pixel(&self, ray: &Vector3<f32>) -> Option<Vector2<f32>> {
+        let horizontal = (ray.x * ray.x + ray.y * ray.y).sqrt();
+        if horizontal == 0.0 {
+            return None;
+        }
+        let longitude = PI + ray.x.atan2(ray.y);
+        let height = ray.z / horizontal;
+        let pixel = Vector2::new(
+            (longitude / (PI * 2.0) * self.dimensions.x as f32)
+                .rem_euclid(self.dimensions.x as f32),
+            (0.5 - height / (2.0 * self.half_height())) * self.dimensions.y as f32,
+        );
+        inside(pixel, self.dimensions)
+    }
+}
+
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProjectionKind {
+    Equirectangular,
+    Cube,
+    Rectilinear,
+    Fisheye,
+    Cylindrical,
+    Stereographic,
+}
+
+/// Parameters for building any of the supported projections
+pub struct ProjectionParams {
+    pub kind: ProjectionKind,
+    pub dimensions: Vector2<u32>,
+    /// Field of view in degrees (horizontal for rectilinear, vertical for cylindrical,
+    /// image circle for fisheye and stereographic)
+    pub fov: f32,
+    pub face: Face,
+    pub basis: Basis,
+    /// Covered part of the sphere for equirectangular images, whole sphere when `None`
+    pub area: Option<CroppedArea>,
+}
+
+impl ProjectionParams {
+    pub fn build(&self) -> error::Result<Box<dyn Projection>> {
+        if self.dimensions.x == 0 || self.dimensions.y == 0 {
+            return Err(Error::Layout(
+                "Projection dimensions must be non-zero".to_string(),
+            ));
+        }
+        let max_fov = match self.kind {
+            ProjectionKind::Rectilinear | ProjectionKind::Cylindrical => 180.0,
+            ProjectionKind::Stereographic => 360.0,
+            _ => f32::INFINITY,
+        };
+        if self.kind != ProjectionKind::Equirectangular
+            && self.kind != ProjectionKind::Cube
+            && (self.fov <= 0.0 || self.fov >= max_fov)
+        {
+            return Err(Error::Layout(format!(
+                "Field of view {} is out of range for {:?}",
+                self.fov, self.kind
+            )));
+        }
+        Ok(match self.kind {
+            ProjectionKind::Equirectangular => {
+                let area = self
+                    .area
+                    .unwrap_or_else(|| CroppedArea::full(self.dimensions));
+                if Vector2::new(area.width, area.height) != self.dimensions {
+                    return Err(Error::Dimensions {
+                        name: "Cropped area".to_string(),
+                        expected: (self.dimensions.x, self.dimensions.y),
+                        found: (area.width, area.height),
+                    });
+                }
+                Box::new(Equirectangular { area })
+            }
+            ProjectionKind::Cube => {
+                if self.dimensions.x != self.dimensions.y {
+                    return Err(Error::Layout("Cube face must be square".to_string()));
+                }
+                Box::new(CubeFace {
+                    face: self.face,
+                    size: self.dimensions.x,
+                })
+            }
+            ProjectionKind::Rectilinear => Box::new(Rectilinear {
+                dimensions: self.dimensions,
+                fov: self.fov,
+                basis: self.basis,
+            }),
+            ProjectionKind::Fisheye => Box::new(Fisheye {
+                dimensions: self.dimensions,
+                fov: self.fov,
+                basis: self.basis,
+            }),
+            ProjectionKind::Cylindrical => Box::new(Cylindrical {
+                dimensions: self.dimensions,
+                fov: self.fov,
+            }),
+            ProjectionKind::Stereographic => Box::new(Stereographic {
+                dimensions: self.dimensions,
+                fov: self.fov,
+                basis: self.basis,
+            }),
+        })
+    }
+}
+
+/// Render `dst_proj` view by sampling `src` (nearest pixel) through `src_proj`.
+/// Pixels that are not covered by the source become transparent.
+pub fn reproject(
+    src: &RgbaImage,
+    src_proj: &dyn Projection,
+    dst_proj: &dyn Projection,
+) -> error::Result<RgbaImage> {
+    let (width, height) = src.dimensions();
+    if src_proj.dimensions() != Vector2::new(width, height) {
+        return Err(Error::Dimensions {
+            name: "Source image".to_string(),
+            expected: (src_proj.dimensions().x, src_proj.dimensions().y),
+            found: (width, height),
+        });
+    }
+    let dims = dst_proj.dimensions();
+    Ok(par_from_fn(dims.x, dims.y, |x, y| {
+        dst_proj
+            .pixel_to_ray(&Vector2::new(x as f32, y as f32))
+            .and_then(|ray| src_proj.ray_to_pixel(&ray))
+            .map(|p| *src.get_pixel((p.x as u32).min(width - 1), (p.y as u32).min(height - 1)))
+            .unwrap_or(Rgba([0, 0, 0, 0]))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Vector2, Vector3};
+
+    use super::*;
+
+    fn assert_round_trip(projection: &dyn Projection, pixel: Vector2<f32>) {
+        let ray = projection.pixel_to_ray(&pixel).unwrap();
+        let back = projection.ray_to_pixel(&ray).unwrap();
+        assert!(
+            (back - pixel).magnitude() < 0.05,
+            "{:?} != {:?}",
+            back,
+            pixel
+        );
+    }
+
+    #[test]
+    fn all_projections_round_trip_pixels() {
+        let dims = Vector2::new(200, 100);
+        let basis = Basis::from_angles(30.0, 10.0, 5.0);
+        let projections: Vec<Box<dyn Projection>> = vec![
+            Box::new(Equirectangular {
+                area: CroppedArea::full(dims),
+            }),
+            Box::new(CubeFace {
+                face: Face::Up,
+                size: 100,
+            }),
+            Box::new(Rectilinear {
+                dimensions: dims,
+                fov: 100.0,
+                basis,
+            }),
+            Box::new(Fisheye {
+                dimensions: dims,
+                fov: 180.0,
+                basis,
+            }),
+            Box::new(Cylindrical {
+                dimensions: dims,
+                fov: 120.0,
+            }),
+            Box::new(Stereographic {
+                dimensions: dims,
+                fov: 200.0,
+                basis,
+            }),
+        ];
+        for projection in projections.iter() {
+            assert_round_trip(projection.as_ref(), Vector2::new(60.5, 40.5));
+            assert_round_trip(projection.as_ref(), Vector2::new(50.0, 50.0));
+        }
+    }
+
+    #[test]
+    fn cropped_area_from_degrees_matches_gpano_layout() {
+        // 360x120 degree drone panorama
+        let area =
+            CroppedArea::from_degrees(Vector2::new(3600, 1200), (-180.0, 180.0), (-60.0, 60.0))
+                .unwrap();
+        assert_eq!(area.full_width, 3600);
+        assert_eq!(area.full_height, 1800);
+        assert_eq!((area.left, area.top), (0, 300));
+        // 180x90 degree front half
+        let area = CroppedArea::from_degrees(Vector2::new(1800, 900), (-90.0, 90.0), (-45.0, 45.0))
+            .unwrap();
+        assert_eq!((area.full_width, area.full_height), (3600, 1800));
+        assert_eq!((area.left, area.top), (900, 450));
+        let within =
+            CroppedArea::within(Vector2::new(3600, 1800), (-90.0, 90.0), (-45.0, 45.0)).unwrap();
+        assert_eq!(within, area);
+    }
+
+    #[test]
+    fn cropped_equirectangular_does_not_cover_poles() {
+        let projection = Equirectangular {
+            area: CroppedArea::from_degrees(Vector2::new(360, 120), (-180.0, 180.0), (-60.0, 60.0))
+                .unwrap(),
+        };
+        assert_eq!(projection.ray_to_pixel(&UP), None);
+        let front = projection.ray_to_pixel(&FRONT).unwrap();
+        assert!((front - Vector2::new(180.0, 60.0)).magnitude() < 1e-3);
+        assert_round_trip(&projection, Vector2::new(10.5, 100.5));
+    }
+
+    #[test]
+    fn cube_face_matches_face_pixel2ray() {
+        let face = CubeFace {
+            face: Face::Right,
+            size: 10,
+        };
+        let basis = Face::Right.basis();
+        let expected = crate::face_pixel2ray(
+            &basis.center,
+            &basis.down,
+            &basis.right,
+            &Vector2::new(3, 7),
+            &Vector2::new(10, 10),
+        );
+        let ray = face.pixel_to_ray(&Vector2::new(3.0, 7.0)).unwrap();
+        assert!((ray - expected).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn yaw_turns_camera_right() {
+        let basis = Basis::from_angles(90.0, 0.0, 0.0);
+        assert!((basis.center - RIGHT).magnitude() < 1e-6);
+        let basis = Basis::from_angles(0.0, 90.0, 0.0);
+        assert!((basis.center - UP).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn rectilinear_does_not_see_behind() {
+        let camera = Rectilinear {
+            dimensions: Vector2::new(10, 10),
+            fov: 90.0,
+            basis: Basis::default(),
+        };
+        assert_eq!(camera.ray_to_pixel(&Vector3::new(0.0, -1.0, 0.0)), None);
+    }
+
+    #[test]
+    fn reproject_equirectangular_to_front_face_samples_center() {
+        let mut src = RgbaImage::new(40, 20);
+        src.put_pixel(20, 10, Rgba([255, 0, 0, 255]));
+        let out = reproject(
+            &src,
+            &Equirectangular {
+                area: CroppedArea::full(Vector2::new(40, 20)),
+            },
+            &CubeFace {
+                face: Face::Front,
+                size: 10,
+            },
+        )
+        .unwrap();
+        assert_eq!(*out.get_pixel(5, 5), Rgba([255, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,450 @@
+use crate::error::{self, Error};
+use cgmath::{Vector2, Vector3};
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::projection::{CroppedArea, Equirectangular, Face, Projection};
+use crate::{cube2equ_source_pixel, equ_source_pixel, face_pixel2ray, par_from_fn, EquLayout};
+
+const MAGIC: &[u8] = b"EQREMAP\x01";
+/// Marks a tap that falls outside of the sources (uncovered part of a partial panorama)
+const NONE: u32 = u32::MAX;
+
+/// How split samples the equirectangular image
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    /// Nearest pixel, same as without a table
+    Nearest,
+    /// Weighted average of the four nearest pixels
+    Bilinear,
+}
+
+/// Everything a table depends on, a cached table is reused only when it matches
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RemapKey {
+    /// `equ_to_cube` or `cube_to_equ`
+    pub direction: String,
+    pub sources: Vec<[u32; 2]>,
+    pub outputs: Vec<[u32; 2]>,
+    /// Full width, full height, left, top of the covered area of a partial panorama
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub area: Option<[u32; 4]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<[[f32; 3]; 3]>,
+    pub interpolation: Interpolation,
+}
+
+/// Precomputed source pixels (and weights) of every output pixel. Sources and outputs are
+/// addressed as if concatenated, so the six faces are one source or one output.
+#[non_exhaustive]
+pub struct RemapTable {
+    pub key: RemapKey,
+    /// Source pixels per output pixel
+    pub taps: usize,
+    /// `taps` source pixel indices per output pixel, `u32::MAX` for none
+    pub indices: Vec<u32>,
+    /// `taps` weights per output pixel, empty for a single tap
+    pub weights: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    key: RemapKey,
+    taps: usize,
+}
+
+fn pixel_count(dims: &[[u32; 2]]) -> usize {
+    dims.iter().map(|[w, h]| *w as usize * *h as usize).sum()
+}
+
+impl RemapTable {
+    pub fn equ_to_cube_key(
+        source: Vector2<u32>,
+        layout: &EquLayout,
+        face_size: u32,
+        interpolation: Interpolation,
+    ) -> RemapKey {
+        let area = &layout.area;
+        RemapKey {
+            direction: "equ_to_cube".to_string(),
+            sources: vec![[source.x, source.y]],
+            outputs: vec![[face_size, face_size]; 6],
+            area: (!area.is_full()).then_some([
+                area.full_width,
+                area.full_height,
+                area.left,
+                area.top,
+            ]),
+            orientation: layout
+                .orientation
+                .map(|o| [o.x.into(), o.y.into(), o.z.into()]),
+            interpolation,
+        }
+    }
+
+    /// Table from an equirectangular image of `source` size to the faces in `Face::ALL` order,
+    /// sampling the same pixels as `equ2cube` when nearest
+    pub fn equ_to_cube(
+        source: Vector2<u32>,
+        layout: &EquLayout,
+        face_size: u32,
+        interpolation: Interpolation,
+    ) -> RemapTable {
+        let key = RemapTable::equ_to_cube_key(source, layout, face_size, interpolation);
+        let face_dimensions = Vector2::new(face_size, face_size);
+        let area = layout.area;
+        let full = Equirectangular {
+            area: CroppedArea::full(Vector2::new(area.full_width, area.full_height)),
+        };
+        let index = |p: Vector2<u32>| p.y * source.x + p.x;
+        // four nearest pixel centers around the ray and their bilinear weights
+        let bilinear = |ray: Vector3<f32>| {
+            let mut taps = [(NONE, 0.0); 4];
+            let p = match full.ray_to_pixel(&layout.orientation.map_or(ray, |o| o * ray)) {
+                Some(p) => p - Vector2::new(0.5, 0.5),
+                None => return taps,
+            };
+            let (x0, y0) = (p.x.floor(), p.y.floor());
+            let (fx, fy) = (p.x - x0, p.y - y0);
+            let corners = [
+                (0.0, 0.0, (1.0 - fx) * (1.0 - fy)),
+                (1.0, 0.0, fx * (1.0 - fy)),
+                (0.0, 1.0, (1.0 - fx) * fy),
+                (1.0, 1.0, fx * fy),
+            ];
+            for (tap, (dx, dy, w)) in taps.iter_mut().zip(corners) {
+                let full_pixel = Vector2::new(
+                    (x0 + dx).rem_euclid(area.full_width as f32),
+                    (y0 + dy).clamp(0.0, area.full_height as f32 - 1.0),
+                );
+                if let Some(p) = area.image_pixel(full_pixel) {
+                    *tap = (index(Vector2::new(p.x as u32, p.y as u32)), w);
+                }
+            }
+            taps
+        };
+        let rows = (0..6 * face_size)
+            .into_par_iter()
+            .map(|row| {
+                let face = Face::ALL[(row / face_size) as usize].basis();
+                let y = row % face_size;
+                let mut taps = vec![];
+                for x in 0..face_size {
+                    let ray = face_pixel2ray(
+                        &face.center,
+                        &face.down,
+                        &face.right,
+                        &Vector2::new(x, y),
+                        &face_dimensions,
+                    );
+                    match interpolation {
+                        Interpolation::Nearest => {
+                            taps.push((equ_source_pixel(layout, &ray).map_or(NONE, index), 1.0))
+                        }
+                        Interpolation::Bilinear => taps.extend(bilinear(ray)),
+                    }
+                }
+                taps
+            })
+            .collect::<Vec<_>>();
+        let taps = rows.iter().flatten();
+        RemapTable {
+            key,
+            taps: match interpolation {
+                Interpolation::Nearest => 1,
+                Interpolation::Bilinear => 4,
+            },
+            indices: taps.clone().map(|(i, _)| *i).collect(),
+            weights: match interpolation {
+                Interpolation::Nearest => vec![],
+                Interpolation::Bilinear => taps.map(|(_, w)| *w).collect(),
+            },
+        }
+    }
+
+    pub fn cube_to_equ_key(face_size: u32) -> RemapKey {
+        RemapKey {
+            direction: "cube_to_equ".to_string(),
+            sources: vec![[face_size, face_size]; 6],
+            outputs: vec![[face_size * 4, face_size * 2]],
+            area: None,
+            orientation: None,
+            interpolation: Interpolation::Nearest,
+        }
+    }
+
+    /// Table from faces in `Face::ALL` order to the equirectangular image, same pixels as
+    /// `cube2equ`
+    pub fn cube_to_equ(face_size: u32) -> RemapTable {
+        let key = RemapTable::cube_to_equ_key(face_size);
+        let width = face_size * 4;
+        let mut indices = vec![NONE; (width * face_size * 2) as usize];
+        indices
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, index) in row.iter_mut().enumerate() {
+                    if let Some((face, p)) = cube2equ_source_pixel(x as u32, y as u32, face_size) {
+                        let f = Face::ALL.iter().position(|f| *f == face).unwrap() as u32;
+                        *index = (f * face_size + p.y) * face_size + p.x;
+                    }
+                }
+            });
+        RemapTable {
+            key,
+            taps: 1,
+            indices,
+            weights: vec![],
+        }
+    }
+
+    /// Gathers the outputs from `sources`, pixels without a source become transparent
+    pub fn apply(&self, sources: &[&RgbaImage]) -> error::Result<Vec<RgbaImage>> {
+        self.apply_where(sources, |_, _, _| true)
+    }
+
+    /// `apply` gathering only output pixels `(output, x, y)` for which `keep` holds, the rest
+    /// stay transparent
+    pub fn apply_where(
+        &self,
+        sources: &[&RgbaImage],
+        keep: impl Fn(usize, u32, u32) -> bool + Sync,
+    ) -> error::Result<Vec<RgbaImage>> {
+        let dims = sources
+            .iter()
+            .map(|s| [s.width(), s.height()])
+            .collect::<Vec<_>>();
+        if dims != self.key.sources {
+            return Err(Error::Layout(format!(
+                "Remap table is for sources {:?}, got {:?}",
+                self.key.sources, dims
+            )));
+        }
+        let source_pixels = |i: u32| {
+            let mut i = i as usize;
+            for s in sources {
+                let n = s.width() as usize * s.height() as usize;
+                if i < n {
+                    return &s.as_raw()[i * 4..i * 4 + 4];
+                }
+                i -= n;
+            }
+            unreachable!("remap index out of range")
+        };
+        let mut offset = 0;
+        let outputs = self
+            .key
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(output, [width, height])| {
+                let start = offset;
+                offset += *width as usize * *height as usize;
+                par_from_fn(*width, *height, |x, y| {
+                    if !keep(output, x, y) {
+                        return Rgba([0, 0, 0, 0]);
+                    }
+                    let pixel = start + (y * width + x) as usize;
+                    let taps = &self.indices[pixel * self.taps..(pixel + 1) * self.taps];
+                    if self.taps == 1 {
+                        return match taps[0] {
+                            NONE => Rgba([0, 0, 0, 0]),
+                            i => {
+                                let p = source_pixels(i);
+                                Rgba([p[0], p[1], p[2], p[3]])
+                            }
+                        };
+                    }
+                    let weights = &self.weights[pixel * self.taps..(pixel + 1) * self.taps];
+                    // taps outside of a partial panorama are left out
+                    let mut sum = [0.0f32; 4];
+                    let mut total = 0.0;
+                    for (&i, &w) in taps.iter().zip(weights).filter(|(i, _)| **i != NONE) {
+                        for (s, v) in sum.iter_mut().zip(source_pixels(i)) {
+                            *s += *v as f32 * w;
+                        }
+                        total += w;
+                    }
+                    if total <= 0.0 {
+                        return Rgba([0, 0, 0, 0]);
+                    }
+                    Rgba(sum.map(|v| (v / total).round().clamp(0.0, 255.0) as u8))
+                })
+            })
+            .collect();
+        Ok(outputs)
+    }
+
+    pub fn save(&self, path: &Path) -> error::Result<()> {
+        let header = serde_json::to_vec(&Header {
+            key: self.key.clone(),
+            taps: self.taps,
+        })?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.reserve((self.indices.len() + self.weights.len()) * 4);
+        for i in &self.indices {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        for w in &self.weights {
+            bytes.extend_from_slice(&w.to_le_bytes());
+        }
+        fs::write(path, bytes).map_err(Error::io(path))?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> error::Result<RemapTable> {
+        let bytes = fs::read(path).map_err(Error::io(path))?;
+        let invalid = || Error::format("Not a remap table").at(path);
+        if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid());
+        }
+        let start = MAGIC.len() + 4;
+        let length = u32::from_le_bytes(bytes[MAGIC.len()..start].try_into().unwrap()) as usize;
+        let header: Header =
+            serde_json::from_slice(bytes.get(start..start + length).ok_or_else(invalid)?)
+                .map_err(|e| Error::format(e).at(path))?;
+        let count = pixel_count(&header.key.outputs) * header.taps;
+        let body = &bytes[start + length..];
+        let weight_count = if header.taps > 1 { count } else { 0 };
+        if body.len() != (count + weight_count) * 4 {
+            return Err(invalid());
+        }
+        let words = body
+            .chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect::<Vec<_>>();
+        let source_count = pixel_count(&header.key.sources) as u32;
+        let indices = words[..count]
+            .iter()
+            .map(|w| u32::from_le_bytes(*w))
+            .collect::<Vec<_>>();
+        if indices.iter().any(|i| *i != NONE && *i >= source_count) {
+            return Err(invalid());
+        }
+        Ok(RemapTable {
+            key: header.key,
+            taps: header.taps,
+            indices,
+            weights: words[count..]
+                .iter()
+                .map(|w| f32::from_le_bytes(*w))
+                .collect(),
+        })
+    }
+
+    /// Table cached at `path` when it was built for `key`, otherwise built and cached there.
+    /// Tells whether the cached table was used.
+    pub fn cached(
+        path: &Path,
+        key: &RemapKey,
+        build: impl FnOnce() -> RemapTable,
+    ) -> error::Result<(RemapTable, bool)> {
+        if path.exists() {
+            if let Ok(table) = RemapTable::load(path) {
+                if table.key == *key {
+                    return Ok((table, true));
+                }
+            }
+        }
+        let table = build();
+        table.save(path)?;
+        Ok((table, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::Selection;
+    use crate::{cube2equ, equ2cube};
+    use image::DynamicImage;
+
+    fn panorama() -> RgbaImage {
+        RgbaImage::from_fn(64, 32, |x, y| {
+            Rgba([(x * 4) as u8, (y * 8) as u8, (x ^ y) as u8, 255])
+        })
+    }
+
+    fn layout() -> EquLayout {
+        EquLayout {
+            area: CroppedArea::full(Vector2::new(64, 32)),
+            orientation: Some(crate::projection::rotation_from_angles(30.0, 10.0, 0.0)),
+        }
+    }
+
+    #[test]
+    fn nearest_tables_match_direct_projection() {
+        let equ = panorama();
+        let table =
+            RemapTable::equ_to_cube(Vector2::new(64, 32), &layout(), 16, Interpolation::Nearest);
+        let faces = table.apply(&[&equ]).unwrap();
+        let cube = equ2cube(
+            DynamicImage::ImageRgba8(equ),
+            16,
+            &layout(),
+            &Selection::all(),
+        )
+        .unwrap();
+        for (face, image) in Face::ALL.iter().zip(&faces) {
+            assert!(cube.face(*face) == image, "{}", face.name());
+        }
+        let stitched = RemapTable::cube_to_equ(16)
+            .apply(&faces.iter().collect::<Vec<_>>())
+            .unwrap();
+        assert!(stitched[0] == cube2equ(cube).unwrap());
+    }
+
+    #[test]
+    fn cached_table_is_reused_only_for_the_same_key() {
+        let path =
+            std::env::temp_dir().join(format!("equistitch_remap_{}.bin", std::process::id()));
+        let key = RemapTable::equ_to_cube_key(
+            Vector2::new(64, 32),
+            &layout(),
+            8,
+            Interpolation::Bilinear,
+        );
+        let (built, reused) = RemapTable::cached(&path, &key, || {
+            RemapTable::equ_to_cube(Vector2::new(64, 32), &layout(), 8, Interpolation::Bilinear)
+        })
+        .unwrap();
+        assert!(!reused);
+        let (loaded, reused) = RemapTable::cached(&path, &key, || unreachable!()).unwrap();
+        assert!(reused);
+        assert_eq!(
+            (loaded.taps, &loaded.indices, &loaded.weights),
+            (4, &built.indices, &built.weights)
+        );
+        let other = RemapTable::equ_to_cube_key(
+            Vector2::new(64, 32),
+            &layout(),
+            4,
+            Interpolation::Bilinear,
+        );
+        let (rebuilt, reused) = RemapTable::cached(&path, &other, || {
+            RemapTable::equ_to_cube(Vector2::new(64, 32), &layout(), 4, Interpolation::Bilinear)
+        })
+        .unwrap();
+        assert!(!reused);
+        assert_eq!(rebuilt.key, other);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bilinear_keeps_flat_colors_across_the_seam() {
+        let equ = RgbaImage::from_pixel(64, 32, Rgba([10, 20, 30, 255]));
+        let table =
+            RemapTable::equ_to_cube(Vector2::new(64, 32), &layout(), 16, Interpolation::Bilinear);
+        for face in table.apply(&[&equ]).unwrap() {
+            assert!(face.pixels().all(|p| *p == Rgba([10, 20, 30, 255])));
+        }
+    }
+}
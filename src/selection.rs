@@ -0,0 +1,119 @@
+use cgmath::Vector2;
+
+use crate::manifest::ManifestView;
+use crate::projection::Face;
+
+/// Tiles of every selected face, first and last column and row (inclusive)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileRegion {
+    pub first: Vector2<u32>,
+    pub last: Vector2<u32>,
+}
+
+impl TileRegion {
+    fn contains(&self, column: u32, row: u32) -> bool {
+        (self.first.x..=self.last.x).contains(&column)
+            && (self.first.y..=self.last.y).contains(&row)
+    }
+}
+
+/// Faces and tiles `split` renders and writes, pixels of no selected view are never sampled
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub faces: Vec<Face>,
+    pub tiles: Option<TileRegion>,
+    pub patch_size: u32,
+    /// Whole selected faces are written, not only the tile region
+    pub whole_faces: bool,
+}
+
+impl Selection {
+    /// Every face and tile
+    pub fn all() -> Selection {
+        Selection {
+            faces: Face::ALL.to_vec(),
+            tiles: None,
+            patch_size: 1,
+            whole_faces: true,
+        }
+    }
+
+    pub fn face(&self, face: Face) -> bool {
+        self.faces.contains(&face)
+    }
+
+    pub fn tile(&self, face: Face, column: u32, row: u32) -> bool {
+        self.face(face) && self.tiles.is_none_or(|t| t.contains(column, row))
+    }
+
+    /// Face pixel falls in a selected view
+    pub fn pixel(&self, face: Face, x: u32, y: u32) -> bool {
+        self.face(face)
+            && (self.whole_faces || self.tile(face, x / self.patch_size, y / self.patch_size))
+    }
+
+    /// Face, or tile `(pieces, i)` of a face (column by column), is selected
+    pub fn view(&self, face: Face, tile: Option<(u32, u32)>) -> bool {
+        match tile {
+            Some((pieces, i)) => self.tile(face, i / pieces, i % pieces),
+            None => self.face(face),
+        }
+    }
+
+    pub fn manifest_view(&self, view: &ManifestView) -> bool {
+        Face::ALL
+            .iter()
+            .find(|f| f.name() == view.face)
+            .is_some_and(|f| match view.tile {
+                Some(_) => self.tile(*f, view.x / view.width, view.y / view.width),
+                None => self.face(*f),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    #[test]
+    fn tile_region_limits_pixels_unless_whole_faces_are_written() {
+        let mut selection = Selection {
+            faces: vec![Face::Front, Face::Left],
+            tiles: Some(TileRegion {
+                first: Vector2::new(1, 0),
+                last: Vector2::new(2, 1),
+            }),
+            patch_size: 10,
+            whole_faces: false,
+        };
+        assert!(selection.pixel(Face::Front, 15, 19));
+        assert!(!selection.pixel(Face::Front, 5, 5));
+        assert!(!selection.pixel(Face::Front, 15, 20));
+        assert!(!selection.pixel(Face::Up, 15, 5));
+        // tiles 5 and 6 of a 4×4 grid are rows 1 and 2 of column 1
+        assert!(selection.view(Face::Left, Some((4, 5))));
+        assert!(!selection.view(Face::Left, Some((4, 6))));
+        assert!(selection.view(Face::Left, None));
+        selection.whole_faces = true;
+        assert!(selection.pixel(Face::Front, 5, 5));
+        assert!(!selection.pixel(Face::Back, 5, 5));
+    }
+
+    #[test]
+    fn faces_as_large_as_tiles_are_still_faces() {
+        let selection = Selection {
+            faces: vec![Face::Front],
+            tiles: Some(TileRegion {
+                first: Vector2::new(1, 1),
+                last: Vector2::new(1, 1),
+            }),
+            patch_size: 40,
+            whole_faces: true,
+        };
+        let faces = Manifest::new(40, None, "jpg");
+        let tiles = Manifest::new(40, Some(40), "jpg");
+        assert!(selection.manifest_view(&faces.views[0]));
+        assert!(!selection.manifest_view(&tiles.views[0]));
+    }
+}
@@ -0,0 +1,449 @@
+use cgmath::Vector2;
+use image::{Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use equistitch::manifest::Manifest;
+use equistitch::projection::{CroppedArea, Face};
+use equistitch::remap::Interpolation;
+use equistitch::selection::Selection;
+
+use crate::{
+    equ_source_pixel, face_pixel2ray, input_layout, save_manifests, save_ray_maps, save_view,
+    Coverage, CubeFiles, EquLayout, InputMetadata, SplitArgs,
+};
+
+/// Metadata is read from the start of the file only, it precedes pixel data in JPEG and in
+/// PNG written by common tools
+const METADATA_BYTES: u64 = 4 << 20;
+/// Output pixel, its source row and column and the pixel itself
+const SPLIT_BYTES_PER_PIXEL: usize = 16;
+const STITCH_STRIP_ROWS: u32 = 16;
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.to_string_lossy().to_lowercase() == "png")
+}
+
+/// Rows of a non-interlaced PNG as RGBA8, decoded one by one
+struct RowSource {
+    reader: Box<png::Reader<BufReader<File>>>,
+    color: png::ColorType,
+    width: u32,
+    height: u32,
+}
+
+impl RowSource {
+    /// Fails on input that can't be read row by row, decoding it whole would exceed
+    /// `--max-memory`
+    fn open(path: &Path) -> anyhow::Result<RowSource> {
+        if !is_png(path) {
+            return Err(anyhow::anyhow!(
+                "{} is not PNG, only non-interlaced PNG is read with --max-memory",
+                path.display()
+            ));
+        }
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let (info, mut reader) = decoder.read_info()?;
+        if reader.info().interlaced {
+            return Err(anyhow::anyhow!(
+                "{} is interlaced, only non-interlaced PNG is read with --max-memory",
+                path.display()
+            ));
+        }
+        let (color, _) = reader.output_color_type();
+        Ok(RowSource {
+            reader: Box::new(reader),
+            color,
+            width: info.width,
+            height: info.height,
+        })
+    }
+
+    /// Reads the next row into `row` as RGBA8
+    fn next_row(&mut self, row: &mut [u8]) -> anyhow::Result<()> {
+        let data = self
+            .reader
+            .next_row()?
+            .ok_or(anyhow::anyhow!("PNG ended before its last row"))?;
+        let channels = match self.color {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            png::ColorType::Indexed => return Err(anyhow::anyhow!("Unexpanded indexed PNG")),
+        };
+        for (pixel, source) in row.chunks_exact_mut(4).zip(data.chunks_exact(channels)) {
+            *pixel.first_chunk_mut::<4>().unwrap() = match *source {
+                [l] => [l, l, l, 255],
+                [l, a] => [l, l, l, a],
+                [r, g, b] => [r, g, b, 255],
+                [r, g, b, a] => [r, g, b, a],
+                _ => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+}
+
+fn read_metadata(path: &Path) -> anyhow::Result<InputMetadata> {
+    let mut bytes = vec![];
+    File::open(path)?
+        .take(METADATA_BYTES)
+        .read_to_end(&mut bytes)?;
+    Ok(InputMetadata::read(&bytes))
+}
+
+/// Face or tile written by `split`
+struct View {
+    face: Face,
+    offset: Vector2<u32>,
+    size: u32,
+    path: PathBuf,
+}
+
+/// Selected faces and tiles requested by `args`, named as `Cube::save` and
+/// `Cube::save_patches` do
+fn split_views(args: &SplitArgs, face_size: u32, selection: &Selection) -> Vec<View> {
+    let outputs = [
+        (&args.cubemap_faces_output, None),
+        (&args.tiles_output, Some(args.patch_size)),
+    ];
+    let mut views = vec![];
+    for (directory, patch_size) in outputs {
+        if let Some(directory) = directory {
+            let manifest = Manifest::new(face_size, patch_size, "jpg");
+            views.extend(
+                manifest
+                    .views
+                    .into_iter()
+                    .filter(|view| selection.manifest_view(view))
+                    .filter_map(|view| {
+                        Some(View {
+                            face: *Face::ALL.iter().find(|f| f.name() == view.face)?,
+                            offset: Vector2::new(view.x, view.y),
+                            size: view.width,
+                            path: directory.join(view.name),
+                        })
+                    }),
+            );
+        }
+    }
+    views
+}
+
+/// Splits the panorama in passes over its rows, each pass renders as many faces and tiles as
+/// fit in `max_memory` megabytes. Output is the same as without streaming.
+pub fn split(args: &SplitArgs, max_memory: usize) -> anyhow::Result<EquLayout> {
+    if args.interpolation != Interpolation::Nearest {
+        return Err(anyhow::anyhow!(
+            "--max-memory samples the nearest pixel only"
+        ));
+    }
+    let mut source = RowSource::open(&args.input)?;
+    let dimensions = Vector2::new(source.width, source.height);
+    let (layout, face_exif) = input_layout(args, dimensions, read_metadata(&args.input)?)?;
+    let face_size = layout.area.face_size()?;
+    let face_dimensions = Vector2::new(face_size, face_size);
+    let selection = args.selection()?;
+    let views = split_views(args, face_size, &selection);
+    if views.is_empty() {
+        eprintln!("[main]: Warning, no output type specified");
+    }
+    let budget = (max_memory << 20).saturating_sub(source.width as usize * 4);
+    let mut groups: Vec<Vec<&View>> = vec![];
+    let mut used = budget;
+    for view in &views {
+        let cost = (view.size * view.size) as usize * SPLIT_BYTES_PER_PIXEL;
+        if cost > budget {
+            return Err(anyhow::anyhow!(
+                "--max-memory {} is too small for {}x{} views, {} MB needed",
+                max_memory,
+                view.size,
+                view.size,
+                ((cost + source.width as usize * 4) >> 20) + 1
+            ));
+        }
+        if used + cost > budget {
+            groups.push(vec![]);
+            used = 0;
+        }
+        groups.last_mut().unwrap().push(view);
+        used += cost;
+    }
+    let mut row = vec![0u8; source.width as usize * 4];
+    for (g, group) in groups.iter().enumerate() {
+        eprintln!(
+            "[Equ -> Cube]: pass {}/{}, {} views...",
+            g + 1,
+            groups.len(),
+            group.len()
+        );
+        let starts = group
+            .iter()
+            .scan(0, |start, view| {
+                let this = *start;
+                *start += (view.size * view.size) as usize;
+                Some(this)
+            })
+            .collect::<Vec<_>>();
+        // (source row, source column, output pixel) ordered by source pixel
+        let mut pixels = group
+            .par_iter()
+            .zip(&starts)
+            .flat_map_iter(|(view, start)| {
+                (0..view.size * view.size).filter_map(move |i| {
+                    let (x, y) = (i % view.size, i / view.size);
+                    let basis = view.face.basis();
+                    let ray = face_pixel2ray(
+                        &basis.center,
+                        &basis.down,
+                        &basis.right,
+                        &(view.offset + Vector2::new(x, y)),
+                        &face_dimensions,
+                    );
+                    equ_source_pixel(&layout, &ray).map(|p| (p.y, p.x, (start + i as usize) as u32))
+                })
+            })
+            .collect::<Vec<_>>();
+        pixels.par_sort_unstable();
+        let mut images = group
+            .iter()
+            .map(|view| RgbaImage::new(view.size, view.size))
+            .collect::<Vec<_>>();
+        if g > 0 {
+            source = RowSource::open(&args.input)?;
+        }
+        let mut next = 0;
+        for r in 0..pixels.last().map_or(0, |p| p.0 + 1) {
+            source.next_row(&mut row)?;
+            while let Some(&(_, x, output)) = pixels.get(next).filter(|p| p.0 == r) {
+                let v = starts.partition_point(|s| *s <= output as usize) - 1;
+                let i = (output as usize - starts[v]) * 4;
+                let x = x as usize * 4;
+                images[v].as_mut()[i..i + 4].copy_from_slice(&row[x..x + 4]);
+                next += 1;
+            }
+        }
+        drop(pixels);
+        group
+            .par_iter()
+            .zip(&images)
+            .try_for_each(|(view, image)| {
+                save_view(
+                    image,
+                    &view.path,
+                    face_exif.as_ref(),
+                    view.face,
+                    view.offset + Vector2::new(view.size / 2, view.size / 2),
+                    face_dimensions,
+                )
+            })?;
+    }
+    eprintln!("[Equ -> Cube]: done.");
+    save_manifests(args, face_size)?;
+    if let Some(kind) = args.ray_maps {
+        eprintln!("[main]: Saving ray maps...");
+        save_ray_maps(args, kind, face_size)?;
+    }
+    Ok(layout)
+}
+
+/// Stitches faces or tiles into PNG written strip by strip, holding only the views the
+/// current strip needs within `max_memory` megabytes. Missing views are filled with `fill`.
+pub fn stitch(
+    input_dir: &Path,
+    extension: &str,
+    tiles: bool,
+    coverage: &Coverage,
+    fill: Rgba<u8>,
+    output: &Path,
+    max_memory: usize,
+) -> anyhow::Result<()> {
+    if !is_png(output) {
+        return Err(anyhow::anyhow!(
+            "Output is written in strips as PNG with --max-memory"
+        ));
+    }
+    let input = CubeFiles::find(input_dir, extension, tiles)?;
+    let full = Vector2::new(input.face_size * 4, input.face_size * 2);
+    let area = match coverage.ranges() {
+        Some((longitude, latitude)) => CroppedArea::within(full, longitude, latitude)?,
+        None => CroppedArea::full(full),
+    };
+    // face pixel of output pixel, same as crop_equ of cube2equ
+    let source = |x: u32, y: u32| {
+        let p = area.full_pixel(Vector2::new(x as f32, y as f32));
+        input.source_pixel((p.x as u32).min(full.x - 1), (p.y as u32).min(full.y - 1))
+    };
+    let strips = area.height.div_ceil(STITCH_STRIP_ROWS);
+    let needed = (0..strips)
+        .into_par_iter()
+        .map(|s| {
+            let mut views = (s * STITCH_STRIP_ROWS..((s + 1) * STITCH_STRIP_ROWS).min(area.height))
+                .flat_map(|y| (0..area.width).filter_map(move |x| source(x, y).map(|(v, _)| v)))
+                .collect::<Vec<_>>();
+            views.sort_unstable();
+            views.dedup();
+            views
+        })
+        .collect::<Vec<_>>();
+    let mut first = vec![u32::MAX; input.paths.len()];
+    let mut last = vec![0; input.paths.len()];
+    for (s, views) in needed.iter().enumerate() {
+        for v in views {
+            first[*v] = first[*v].min(s as u32);
+            last[*v] = s as u32;
+        }
+    }
+    let view_bytes = (input.tile_size() * input.tile_size()) as usize * 4;
+    let strip_bytes = (area.width * STITCH_STRIP_ROWS) as usize * 4;
+    let peak = (0..strips)
+        .map(|s| {
+            (0..first.len())
+                .filter(|v| first[*v] <= s && s <= last[*v])
+                .count()
+        })
+        .max()
+        .unwrap_or(0)
+        * view_bytes
+        + strip_bytes;
+    if peak > max_memory << 20 {
+        return Err(anyhow::anyhow!(
+            "Stitching these {} needs {} MB, raise --max-memory{}",
+            if tiles { "tiles" } else { "faces" },
+            (peak >> 20) + 1,
+            if tiles { "" } else { " or stitch from tiles" }
+        ));
+    }
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(output)?),
+        area.width,
+        area.height,
+    );
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let mut stream = writer.stream_writer();
+    let mut loaded: Vec<Option<RgbaImage>> = vec![None; input.paths.len()];
+    let mut strip = vec![0u8; strip_bytes];
+    for s in 0..strips {
+        let opened = needed[s as usize]
+            .par_iter()
+            .filter(|v| first[**v] == s)
+            .filter_map(|v| {
+                let path = input.paths[*v].as_ref()?;
+                Some(image::open(path).map(|image| (*v, image.into_rgba8())))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (v, image) in opened {
+            loaded[v] = Some(image);
+        }
+        let rows = ((s + 1) * STITCH_STRIP_ROWS).min(area.height) - s * STITCH_STRIP_ROWS;
+        let strip = &mut strip[..(area.width * rows) as usize * 4];
+        strip
+            .par_chunks_mut(area.width as usize * 4)
+            .enumerate()
+            .for_each(|(r, row)| {
+                let y = s * STITCH_STRIP_ROWS + r as u32;
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let value = match source(x as u32, y) {
+                        Some((v, p)) => loaded[v].as_ref().map_or(fill, |image| image[(p.x, p.y)]),
+                        None => Rgba([0, 0, 0, 0]),
+                    };
+                    pixel.copy_from_slice(&value.0);
+                }
+            });
+        stream.write_all(strip)?;
+        for (v, image) in loaded.iter_mut().enumerate() {
+            if last[v] == s {
+                *image = None;
+            }
+        }
+    }
+    stream.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cube2equ, split as split_in_memory, Cli, Commands, Cube};
+    use clap::Parser;
+    use std::fs;
+
+    fn split_args(input: &Path, faces: &Path, tiles: &Path, max_memory: Option<&str>) -> SplitArgs {
+        let mut argv = vec![
+            "equistitch".to_string(),
+            "split".to_string(),
+            format!("-i={}", input.display()),
+            format!("-c={}", faces.display()),
+            format!("-t={}", tiles.display()),
+            "-p=32".to_string(),
+        ];
+        argv.extend(max_memory.map(|mb| format!("--max-memory={}", mb)));
+        match Cli::parse_from(argv).command {
+            Some(Commands::Split(args)) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn input_not_read_row_by_row_is_refused() {
+        let directory =
+            std::env::temp_dir().join(format!("equistitch-stream-jpeg-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("pano.jpg");
+        image::RgbImage::new(64, 32).save(&input).unwrap();
+        let args = split_args(&input, &directory, &directory, Some("1"));
+        assert!(split(&args, 1).is_err());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn streamed_split_and_stitch_match_in_memory() {
+        let directory =
+            std::env::temp_dir().join(format!("equistitch-stream-{}", std::process::id()));
+        let paths =
+            ["faces", "tiles", "streamed_faces", "streamed_tiles"].map(|d| directory.join(d));
+        for path in &paths {
+            fs::create_dir_all(path).unwrap();
+        }
+        let input = directory.join("pano.png");
+        RgbaImage::from_fn(896, 448, |x, y| {
+            image::Rgba([x as u8, y as u8, (x ^ y) as u8, 255])
+        })
+        .save(&input)
+        .unwrap();
+        split_in_memory(&split_args(&input, &paths[0], &paths[1], None)).unwrap();
+        // 224×224 faces need 784 KB each, so 1 MB takes a pass per face
+        split(&split_args(&input, &paths[2], &paths[3], Some("1")), 1).unwrap();
+        for (expected, streamed) in [(&paths[0], &paths[2]), (&paths[1], &paths[3])] {
+            for entry in fs::read_dir(expected).unwrap() {
+                let name = entry.unwrap().file_name();
+                assert!(
+                    fs::read(expected.join(&name)).unwrap()
+                        == fs::read(streamed.join(&name)).unwrap(),
+                    "{:?}",
+                    name
+                );
+            }
+        }
+
+        let output = directory.join("stitched.png");
+        let coverage = match Cli::parse_from(["equistitch", "stitch", "-i=.", "-o=."]).command {
+            Some(Commands::Stitch { coverage, .. }) => coverage,
+            _ => unreachable!(),
+        };
+        // a strip crossing the seam needs several faces at once
+        let fill = Rgba([0, 0, 0, 0]);
+        assert!(stitch(&paths[0], "jpg", false, &coverage, fill, &output, 1).is_err());
+        stitch(&paths[0], "jpg", false, &coverage, fill, &output, 4).unwrap();
+        let expected = cube2equ(Cube::load(&paths[0], "jpg", false, fill).unwrap().0).unwrap();
+        assert!(image::open(&output).unwrap().into_rgba8() == expected);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,485 @@
+use crate::error::{self, Error};
+use cgmath::{InnerSpace, Matrix3, Vector2, Vector3};
+use rayon::prelude::*;
+use std::path::Path;
+
+use crate::mask::load_faces;
+use crate::npy::{self, Dtype, NpyArray};
+use crate::projection::{Basis, CroppedArea, CubeFace, Equirectangular, Face, Projection};
+use crate::selection::Selection;
+use crate::view_name;
+
+/// Multi-channel float image (probabilities, logits) in height × width × channels order
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Tensor {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl Tensor {
+    pub fn new(width: u32, height: u32, channels: usize) -> Tensor {
+        Tensor {
+            width,
+            height,
+            channels,
+            data: vec![0.0; width as usize * height as usize * channels],
+        }
+    }
+
+    /// H×W×C or H×W array
+    pub(crate) fn from_npy(array: NpyArray) -> error::Result<Tensor> {
+        let (height, width, channels) = match array.shape[..] {
+            [h, w] => (h, w, 1),
+            [h, w, c] => (h, w, c),
+            _ => {
+                return Err(Error::format(format!(
+                    "Expected H×W×C array, got shape {:?}",
+                    array.shape
+                )))
+            }
+        };
+        Ok(Tensor {
+            width: width as u32,
+            height: height as u32,
+            channels,
+            data: array.data,
+        })
+    }
+
+    pub fn load(path: &Path) -> error::Result<Tensor> {
+        Tensor::from_npy(npy::read(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> error::Result<()> {
+        npy::write(
+            path,
+            &[self.height as usize, self.width as usize, self.channels],
+            Dtype::F32,
+            &self.data,
+        )
+    }
+
+    /// Tensor with rows filled in parallel, `f` gets the pixel coordinates and its zeroed
+    /// values, like `par_from_fn` for images
+    pub(crate) fn par_from_fn(
+        width: u32,
+        height: u32,
+        channels: usize,
+        f: impl Fn(u32, u32, &mut [f32]) + Sync,
+    ) -> Tensor {
+        let mut tensor = Tensor::new(width, height, channels);
+        let row = width as usize * channels;
+        if row == 0 {
+            return tensor;
+        }
+        tensor
+            .data
+            .par_chunks_mut(row)
+            .enumerate()
+            .for_each(|(y, pixels)| {
+                for (x, pixel) in pixels.chunks_mut(channels).enumerate() {
+                    f(x as u32, y as u32, pixel);
+                }
+            });
+        tensor
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * self.channels
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> &[f32] {
+        let i = self.index(x, y);
+        &self.data[i..i + self.channels]
+    }
+
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut [f32] {
+        let i = self.index(x, y);
+        &mut self.data[i..i + self.channels]
+    }
+
+    /// Adds bilinearly interpolated values at `position` (pixel `i` sits at `i`) times `weight`
+    fn add_bilinear(&self, position: Vector2<f32>, weight: f32, output: &mut [f32]) {
+        let clamp = |v: f32, size: u32| v.clamp(0.0, (size - 1) as f32);
+        let (x, y) = (
+            clamp(position.x, self.width),
+            clamp(position.y, self.height),
+        );
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        for (px, py, w) in [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x1, y0, fx * (1.0 - fy)),
+            (x0, y1, (1.0 - fx) * fy),
+            (x1, y1, fx * fy),
+        ] {
+            for (o, v) in output.iter_mut().zip(self.pixel(px, py)) {
+                *o += v * w * weight;
+            }
+        }
+    }
+
+    /// Covered part of a full equirectangular tensor, like `crop_equ`
+    pub fn crop(&self, area: &CroppedArea) -> Tensor {
+        let mut cropped = Tensor::new(area.width, area.height, self.channels);
+        for y in 0..area.height {
+            for x in 0..area.width {
+                let full = area.full_pixel(Vector2::new(x as f32, y as f32));
+                cropped.pixel_mut(x, y).copy_from_slice(self.pixel(
+                    (full.x as u32).min(self.width - 1),
+                    (full.y as u32).min(self.height - 1),
+                ));
+            }
+        }
+        cropped
+    }
+
+    /// Tiles of `patch_size` column by column and tiles per row, like `split_image`
+    pub fn split(&self, patch_size: u32) -> (Vec<Tensor>, u32) {
+        let pieces = self.width / patch_size;
+        let patches = (0..pieces * pieces)
+            .map(|i| {
+                let (px, py) = (i / pieces, i % pieces);
+                let mut patch = Tensor::new(patch_size, patch_size, self.channels);
+                for y in 0..patch_size {
+                    for x in 0..patch_size {
+                        patch
+                            .pixel_mut(x, y)
+                            .copy_from_slice(self.pixel(px * patch_size + x, py * patch_size + y));
+                    }
+                }
+                patch
+            })
+            .collect();
+        (patches, pieces)
+    }
+
+    /// Errors naming the tensor as `name` unless it has `expected` width, height and channels
+    fn check(&self, name: String, expected: (u32, u32, usize)) -> error::Result<()> {
+        let (width, height, channels) = expected;
+        if (self.width, self.height) != (width, height) {
+            return Err(Error::Dimensions {
+                name,
+                expected: (width, height),
+                found: (self.width, self.height),
+            });
+        }
+        if self.channels != channels {
+            return Err(Error::Layout(format!(
+                "{} has {} channels, expected {}",
+                name, self.channels, channels
+            )));
+        }
+        Ok(())
+    }
+
+    /// Joins tiles given column by column, like `stitch_image`
+    pub fn stitch(patches: &[Tensor], x_pieces: u32) -> error::Result<Tensor> {
+        let first = patches.first().ok_or(Error::NoViews {
+            directory: None,
+            tiles: true,
+        })?;
+        let y_pieces = patches.len() as u32 / x_pieces;
+        let mut full = Tensor::new(
+            first.width * x_pieces,
+            first.height * y_pieces,
+            first.channels,
+        );
+        for (i, patch) in patches.iter().enumerate() {
+            patch.check(
+                format!("Tile {}", i),
+                (first.width, first.height, first.channels),
+            )?;
+            let (px, py) = (i as u32 / y_pieces, i as u32 % y_pieces);
+            for y in 0..patch.height {
+                for x in 0..patch.width {
+                    full.pixel_mut(px * patch.width + x, py * patch.height + y)
+                        .copy_from_slice(patch.pixel(x, y));
+                }
+            }
+        }
+        Ok(full)
+    }
+}
+
+/// Faces wider than 90 degrees overlap, a 180 degree one would need an infinite plane
+fn check_face_fov(fov: f32) -> error::Result<()> {
+    match fov > 0.0 && fov < 180.0 {
+        true => Ok(()),
+        false => Err(Error::Layout(format!(
+            "Face field of view {} is out of range, expected between 0 and 180",
+            fov
+        ))),
+    }
+}
+
+/// Side of the 90 degree part of faces `width` pixels wide rendered with field of view `fov`,
+/// the face size of the equirectangular image matching their resolution
+pub fn face_size_within(width: u32, fov: f32) -> error::Result<u32> {
+    check_face_fov(fov)?;
+    let size = (width as f32 * (45.0f32.to_radians().tan() / (fov.to_radians() / 2.0).tan()))
+        .round() as u32;
+    match size {
+        0 => Err(Error::Layout(format!(
+            "Faces {} pixels wide have no 90 degree part at field of view {}",
+            width, fov
+        ))),
+        size => Ok(size),
+    }
+}
+
+/// Reprojects per-face maps (in `Face::ALL` order) rendered with field of view `fov` onto the
+/// equirectangular image. Where wider than 90 degree faces overlap, the contributions are
+/// averaged weighted by their distance from the face edge.
+pub fn faces_to_equ(faces: &[Tensor], fov: f32, dimensions: Vector2<u32>) -> error::Result<Tensor> {
+    check_face_fov(fov)?;
+    let first = faces.first().ok_or(Error::NoViews {
+        directory: None,
+        tiles: false,
+    })?;
+    for (face, tensor) in Face::ALL.iter().zip(faces) {
+        tensor.check(
+            format!("Face {}", face.name()),
+            (first.width, first.height, first.channels),
+        )?;
+    }
+    let bases = Face::ALL.iter().map(|f| f.basis()).collect::<Vec<_>>();
+    let half = Vector2::new(first.width as f32, first.height as f32) / 2.0;
+    let focal = half.x / (fov.to_radians() / 2.0).tan();
+    let equ = Equirectangular {
+        area: CroppedArea::full(dimensions),
+    };
+    Ok(Tensor::par_from_fn(
+        dimensions.x,
+        dimensions.y,
+        first.channels,
+        |x, y, output| {
+            let ray = match equ.pixel_to_ray(&Vector2::new(x as f32, y as f32)) {
+                Some(ray) => ray,
+                None => return,
+            };
+            let mut total = 0.0;
+            for (basis, tensor) in bases.iter().zip(faces) {
+                let z = ray.dot(basis.center);
+                if z <= 0.0 {
+                    continue;
+                }
+                let p = half + Vector2::new(ray.dot(basis.right), ray.dot(basis.down)) * focal / z;
+                let edge = p.x.min(p.y).min(half.x * 2.0 - p.x).min(half.y * 2.0 - p.y);
+                // rays on the edge of 90 degree faces may fall a rounding error outside
+                if edge < -1e-3 {
+                    continue;
+                }
+                let weight = edge.max(0.0) + 0.5;
+                tensor.add_bilinear(p, weight, output);
+                total += weight;
+            }
+            if total > 0.0 {
+                output.iter_mut().for_each(|v| *v /= total);
+            }
+        },
+    ))
+}
+
+/// Square faces with `channels` per pixel in `Face::ALL` order, from `{face}.{extension}` or
+/// tiles. H×W arrays are read as one channel.
+pub(crate) fn load_cube(
+    directory: &Path,
+    extension: &str,
+    tiles: bool,
+    channels: usize,
+) -> error::Result<Vec<Tensor>> {
+    let faces = load_faces(directory, extension, tiles, Tensor::load, Tensor::stitch)?;
+    let size = faces[0].width;
+    for (face, tensor) in Face::ALL.iter().zip(&faces) {
+        tensor.check(format!("Face {}", face.name()), (size, size, channels))?;
+    }
+    Ok(faces)
+}
+
+/// Saves selected faces of `faces` given in `Face::ALL` order as float32 `{face}.npy`
+pub fn save_cube(faces: &[Tensor], prefix: &Path, selection: &Selection) -> error::Result<()> {
+    Face::ALL
+        .iter()
+        .zip(faces)
+        .filter(|(face, _)| selection.face(**face))
+        .try_for_each(|(face, tensor)| {
+            tensor.save(&prefix.join(format!("{}.npy", view_name(*face, None))))
+        })
+}
+
+/// Saves selected tiles of faces as float32 `{face}_p{pieces}_{i}.npy`, same order as image
+/// tiles
+pub fn save_cube_patches(
+    faces: &[Tensor],
+    prefix: &Path,
+    patch_size: u32,
+    selection: &Selection,
+) -> error::Result<()> {
+    Face::ALL.iter().zip(faces).try_for_each(|(face, tensor)| {
+        let (patches, pieces) = tensor.split(patch_size);
+        patches.iter().enumerate().try_for_each(|(i, p)| {
+            if !selection.tile(*face, i as u32 / pieces, i as u32 % pieces) {
+                return Ok(());
+            }
+            let name = view_name(*face, Some((pieces, i as u32)));
+            p.save(&prefix.join(format!("{}.npy", name)))
+        })
+    })
+}
+
+/// Faces in `Face::ALL` order with `channels` per pixel, from the nearest pixel of `source`
+/// hit by each face pixel ray. `orientation` takes world rays to image rays and `convert` gets
+/// the face basis, the world ray and the sampled value. Rays outside of a partial panorama
+/// are left 0.
+pub(crate) fn equ_to_faces_nearest(
+    source: &Tensor,
+    equ: &Equirectangular,
+    orientation: Option<Matrix3<f32>>,
+    face_size: u32,
+    channels: usize,
+    selection: &Selection,
+    convert: impl Fn(&Basis, &Vector3<f32>, &[f32], &mut [f32]) + Sync,
+) -> Vec<Tensor> {
+    Face::ALL
+        .iter()
+        .map(|face| {
+            let cube_face = CubeFace {
+                face: *face,
+                size: face_size,
+            };
+            let basis = face.basis();
+            Tensor::par_from_fn(face_size, face_size, channels, |x, y, output| {
+                if !selection.pixel(*face, x, y) {
+                    return;
+                }
+                let ray = match cube_face.pixel_to_ray(&Vector2::new(x as f32, y as f32)) {
+                    Some(ray) => ray,
+                    None => return,
+                };
+                let p = match equ.ray_to_pixel(&orientation.map_or(ray, |o| o * ray)) {
+                    Some(p) => p,
+                    None => return,
+                };
+                let value = source.pixel(
+                    (p.x as u32).min(source.width - 1),
+                    (p.y as u32).min(source.height - 1),
+                );
+                convert(&basis, &ray, value, output);
+            })
+        })
+        .collect()
+}
+
+/// Full equirectangular tensor from the nearest pixel of 90 degree `faces`, `convert` gets the
+/// face basis, the ray of the sampled face pixel and its value
+pub(crate) fn faces_to_equ_nearest(
+    faces: &[Tensor],
+    channels: usize,
+    convert: impl Fn(&Basis, &Vector3<f32>, &[f32], &mut [f32]) + Sync,
+) -> Tensor {
+    let size = faces[0].width;
+    let dimensions = Vector2::new(size * 4, size * 2);
+    let equ = Equirectangular {
+        area: CroppedArea::full(dimensions),
+    };
+    let half = size as f32 / 2.0;
+    Tensor::par_from_fn(dimensions.x, dimensions.y, channels, |x, y, output| {
+        let ray = match equ.pixel_to_ray(&Vector2::new(x as f32, y as f32)) {
+            Some(ray) => ray,
+            None => return,
+        };
+        let (i, basis) = Face::ALL
+            .iter()
+            .map(|face| face.basis())
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.center.dot(ray).total_cmp(&b.center.dot(ray)))
+            .unwrap();
+        // rays on the face edge may fall a rounding error outside of it
+        let z = ray.dot(basis.center);
+        let coordinate =
+            |axis: Vector3<f32>| ((half + half * ray.dot(axis) / z).max(0.0) as u32).min(size - 1);
+        let (fx, fy) = (coordinate(basis.right), coordinate(basis.down));
+        let pixel_ray = basis.center
+            + basis.right * (fx as f32 / half - 1.0)
+            + basis.down * (fy as f32 / half - 1.0);
+        convert(
+            &basis,
+            &pixel_ray.normalize(),
+            faces[i].pixel(fx, fy),
+            output,
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant_faces(size: u32) -> Vec<Tensor> {
+        Face::ALL
+            .iter()
+            .enumerate()
+            .map(|(i, _)| {
+                let mut t = Tensor::new(size, size, 2);
+                for y in 0..size {
+                    for x in 0..size {
+                        t.pixel_mut(x, y).copy_from_slice(&[i as f32, 1.0]);
+                    }
+                }
+                t
+            })
+            .collect()
+    }
+
+    #[test]
+    fn front_face_lands_in_the_center() {
+        let equ = faces_to_equ(&constant_faces(8), 90.0, Vector2::new(32, 16)).unwrap();
+        assert_eq!(equ.pixel(16, 8), &[0.0, 1.0]);
+        // probability channel stays normalised everywhere
+        assert!(equ.data.chunks(2).all(|p| (p[1] - 1.0).abs() < 1e-5));
+    }
+
+    #[test]
+    fn overlapping_faces_are_averaged_by_edge_distance() {
+        // 120 degree faces overlap by 15 degrees on each side
+        let equ = faces_to_equ(&constant_faces(12), 120.0, Vector2::new(64, 32)).unwrap();
+        // on the front/left border both faces are equally far from their edges
+        let [value, probability] = [equ.pixel(24, 16)[0], equ.pixel(24, 16)[1]];
+        assert!((value - 1.0).abs() < 0.05, "{}", value);
+        assert!((probability - 1.0).abs() < 1e-5);
+        // closer to the front center the front face dominates
+        assert!(equ.pixel(27, 16)[0] < 0.5);
+    }
+
+    #[test]
+    fn face_fov_outside_0_to_180_is_refused() {
+        assert_eq!(face_size_within(12, 120.0).unwrap(), 7);
+        for fov in [0.0, -10.0, 180.0, 200.0] {
+            assert!(matches!(face_size_within(12, fov), Err(Error::Layout(_))));
+            assert!(matches!(
+                faces_to_equ(&constant_faces(8), fov, Vector2::new(32, 16)),
+                Err(Error::Layout(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn tiles_are_stitched_column_by_column() {
+        let patches = (0..4)
+            .map(|i| Tensor {
+                width: 1,
+                height: 1,
+                channels: 1,
+                data: vec![i as f32],
+            })
+            .collect::<Vec<_>>();
+        let full = Tensor::stitch(&patches, 2).unwrap();
+        assert_eq!(full.data, vec![0.0, 2.0, 1.0, 3.0]);
+        assert_eq!(full.split(1), (patches, 2));
+    }
+}
@@ -0,0 +1,207 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{self, Error};
+use crate::projection::Face;
+use crate::{npy, view_name, CubeView};
+
+/// Face or tile as found, without its pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ViewShape {
+    pub face: Face,
+    /// Tiles per face row and column and tile index, `None` for faces
+    pub tile: Option<(u32, u32)>,
+    pub dimensions: (u32, u32),
+}
+
+/// Value most of `values` have, the first one of equally common values
+fn most_common<T: Copy + PartialEq>(values: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts: Vec<(T, usize)> = vec![];
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    counts
+        .iter()
+        .rev()
+        .max_by_key(|(_, count)| *count)
+        .map(|(value, _)| *value)
+}
+
+/// Every problem keeping `views` from forming a complete cube: missing faces and tiles,
+/// tiles cut differently or indexed beyond their grid, duplicates and views that are not
+/// square or not of the size most of them are
+pub fn problems(views: &[ViewShape]) -> Vec<Error> {
+    // tiles cut into no pieces are reported below, they can't set the grid
+    let pieces = most_common(
+        views
+            .iter()
+            .map(|v| v.tile.map(|(p, _)| p))
+            .filter(|p| *p != Some(0)),
+    )
+    .flatten();
+    let size = most_common(
+        views
+            .iter()
+            .filter(|v| v.dimensions.0 == v.dimensions.1)
+            .map(|v| v.dimensions.0),
+    )
+    .or(views.first().map(|v| v.dimensions.0));
+    let grid = pieces.unwrap_or(1);
+    let mut problems = vec![];
+    let mut present = vec![vec![false; (grid * grid) as usize]; Face::ALL.len()];
+    for view in views {
+        let name = view_name(view.face, view.tile);
+        let (view_pieces, i) = view.tile.unwrap_or((1, 0));
+        if view.tile.map(|(p, _)| p) != pieces {
+            problems.push(Error::Layout(format!(
+                "{} is cut into {} tiles per row, expected {}",
+                name, view_pieces, grid
+            )));
+            continue;
+        }
+        if i >= grid * grid {
+            problems.push(Error::Layout(format!(
+                "{} has tile index beyond {}",
+                name,
+                grid * grid - 1
+            )));
+            continue;
+        }
+        let f = Face::ALL.iter().position(|f| *f == view.face).unwrap();
+        if present[f][i as usize] {
+            problems.push(Error::Layout(format!("{} is given twice", name)));
+        }
+        present[f][i as usize] = true;
+        if let Some(size) = size.filter(|s| view.dimensions != (*s, *s)) {
+            problems.push(Error::Dimensions {
+                name,
+                expected: (size, size),
+                found: view.dimensions,
+            });
+        }
+    }
+    for (face, tiles) in Face::ALL.iter().zip(&present) {
+        if !tiles.contains(&true) {
+            problems.push(Error::MissingView {
+                face: *face,
+                tile: None,
+            });
+            continue;
+        }
+        for (i, _) in tiles.iter().enumerate().filter(|(_, found)| !**found) {
+            problems.push(Error::MissingView {
+                face: *face,
+                tile: Some(i as u32),
+            });
+        }
+    }
+    problems
+}
+
+/// Faces `{face}.{extension}` or tiles `{face}_p{pieces}_{i}.{extension}` of `directory` in
+/// `Face::ALL` and tile order, dimensions are read from image headers (or whole .npy arrays)
+pub fn shapes_in(
+    directory: &Path,
+    extension: &str,
+    tiles: bool,
+) -> error::Result<Vec<(ViewShape, PathBuf)>> {
+    let mut found = vec![];
+    for entry in fs::read_dir(directory).map_err(Error::io(directory))? {
+        let path = entry.map_err(Error::io(directory))?.path();
+        if path
+            .extension()
+            .is_none_or(|e| e.to_string_lossy() != extension)
+        {
+            continue;
+        }
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        match CubeView::parse_name(&stem) {
+            Some((face, tile)) if tile.is_some() == tiles => {
+                let f = Face::ALL.iter().position(|f| *f == face).unwrap();
+                found.push((f, tile, face, path));
+            }
+            _ => {}
+        }
+    }
+    found.sort_by_key(|(f, tile, _, _)| (*f, *tile));
+    found
+        .into_iter()
+        .map(|(_, tile, face, path)| {
+            let dimensions = match extension {
+                "npy" => match npy::read(&path)?.shape[..] {
+                    [h, w, ..] => (w as u32, h as u32),
+                    _ => (0, 0),
+                },
+                _ => image::image_dimensions(&path).map_err(Error::image(&path))?,
+            };
+            Ok((
+                ViewShape {
+                    face,
+                    tile,
+                    dimensions,
+                },
+                path,
+            ))
+        })
+        .collect()
+}
+
+/// Problems of the faces or tiles in `directory`
+pub fn directory(directory: &Path, extension: &str, tiles: bool) -> error::Result<Vec<Error>> {
+    let shapes = shapes_in(directory, extension, tiles)?;
+    Ok(problems(
+        &shapes.iter().map(|(shape, _)| *shape).collect::<Vec<_>>(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(face: Face, i: u32, size: u32) -> ViewShape {
+        ViewShape {
+            face,
+            tile: Some((2, i)),
+            dimensions: (size, size),
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut views = Face::ALL
+            .iter()
+            .flat_map(|face| (0..4).map(|i| tile(*face, i, 16)))
+            .collect::<Vec<_>>();
+        assert!(problems(&views).is_empty());
+        views.retain(|v| v.face != Face::Up && (v.face, v.tile) != (Face::Left, Some((2, 1))));
+        views[0].dimensions = (16, 12);
+        views[5].tile = Some((3, 1));
+        views.push(tile(Face::Down, 9, 16));
+        views.push(ViewShape {
+            tile: Some((0, 0)),
+            ..tile(Face::Right, 0, 16)
+        });
+        let found = problems(&views)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                "front_p2_0 is 16x12, expected 16x16",
+                "back_p3_1 is cut into 3 tiles per row, expected 2",
+                "down_p2_9 has tile index beyond 3",
+                "right_p0_0 is cut into 0 tiles per row, expected 2",
+                "back tile 1 missing",
+                "left tile 1 missing",
+                "up face missing",
+            ]
+        );
+        // such files are no views at all when found in a directory
+        assert_eq!(CubeView::parse_name("right_p0_0"), None);
+    }
+}
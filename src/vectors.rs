@@ -0,0 +1,137 @@
+use crate::error;
+use cgmath::{InnerSpace, Matrix, Matrix3, Vector3};
+use std::path::Path;
+
+use crate::projection::{Basis, Equirectangular};
+use crate::selection::Selection;
+use crate::tensor::{equ_to_faces_nearest, faces_to_equ_nearest, load_cube, Tensor};
+
+/// Faces of a 3D vector field (surface normals, scene flow) in `Face::ALL` order. Vectors are
+/// in the camera frame of their face: x right, y down, z forward (the rows of the manifest
+/// `rotation_opencv`).
+#[non_exhaustive]
+pub struct VectorCube {
+    pub faces: Vec<Tensor>,
+}
+
+/// World vector in the camera frame of `basis`
+fn to_camera(basis: &Basis, v: Vector3<f32>) -> [f32; 3] {
+    [basis.right.dot(v), basis.down.dot(v), basis.center.dot(v)]
+}
+
+/// Camera frame vector of `basis` in the world frame
+fn to_world(basis: &Basis, v: &[f32]) -> Vector3<f32> {
+    basis.right * v[0] + basis.down * v[1] + basis.center * v[2]
+}
+
+impl VectorCube {
+    /// Faces from `{face}.npy` or tiles, H×W×3 arrays
+    pub fn load(directory: &Path, extension: &str, tiles: bool) -> error::Result<VectorCube> {
+        Ok(VectorCube {
+            faces: load_cube(directory, extension, tiles, 3)?,
+        })
+    }
+
+    /// Faces of an equirectangular H×W×3 field of world vectors (x east, y north, z up),
+    /// `orientation` takes world rays to image rays and rotates the vectors along. Vectors
+    /// are sampled from the nearest pixel and rotated, never interpolated.
+    pub fn from_equ(
+        field: &Tensor,
+        equ: &Equirectangular,
+        orientation: Option<Matrix3<f32>>,
+        face_size: u32,
+        selection: &Selection,
+    ) -> VectorCube {
+        let faces = equ_to_faces_nearest(
+            field,
+            equ,
+            orientation,
+            face_size,
+            3,
+            selection,
+            |basis, _, v, o| {
+                let v = Vector3::new(v[0], v[1], v[2]);
+                o.copy_from_slice(&to_camera(
+                    basis,
+                    orientation.map_or(v, |r| r.transpose() * v),
+                ))
+            },
+        );
+        VectorCube { faces }
+    }
+
+    /// Full equirectangular field of world vectors
+    pub fn to_equ(&self) -> Tensor {
+        faces_to_equ_nearest(&self.faces, 3, |basis, _, v, o| {
+            let v = to_world(basis, v);
+            o.copy_from_slice(&[v.x, v.y, v.z])
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projection::{CroppedArea, Face, Projection};
+    use cgmath::Vector2;
+
+    /// Normals of a sphere around the camera seen from inside, pointing at the camera
+    fn inward_normals(dimensions: Vector2<u32>) -> Tensor {
+        let equ = Equirectangular {
+            area: CroppedArea::full(dimensions),
+        };
+        let mut field = Tensor::new(dimensions.x, dimensions.y, 3);
+        for y in 0..dimensions.y {
+            for x in 0..dimensions.x {
+                let ray = equ
+                    .pixel_to_ray(&Vector2::new(x as f32 + 0.5, y as f32 + 0.5))
+                    .unwrap();
+                field
+                    .pixel_mut(x, y)
+                    .copy_from_slice(&[-ray.x, -ray.y, -ray.z]);
+            }
+        }
+        field
+    }
+
+    #[test]
+    fn normals_face_the_camera_on_every_face() {
+        let dimensions = Vector2::new(64, 32);
+        let equ = Equirectangular {
+            area: CroppedArea::full(dimensions),
+        };
+        let cube = VectorCube::from_equ(
+            &inward_normals(dimensions),
+            &equ,
+            None,
+            16,
+            &Selection::all(),
+        );
+        for (face, normals) in Face::ALL.iter().zip(&cube.faces) {
+            // face center looks straight at the wall, normal points back along -z
+            let n = normals.pixel(8, 8);
+            assert!(n[2] < -0.95, "{} {:?}", face.name(), n);
+        }
+        // on the right half of a face the normal tilts left
+        assert!(cube.faces[0].pixel(15, 8)[0] < -0.5);
+    }
+
+    #[test]
+    fn vectors_round_trip_to_world_frame() {
+        let constant = Vector3::new(0.2, -0.5, 0.8);
+        let mut field = Tensor::new(64, 32, 3);
+        for v in field.data.chunks_mut(3) {
+            v.copy_from_slice(&[constant.x, constant.y, constant.z]);
+        }
+        let equ = Equirectangular {
+            area: CroppedArea::full(Vector2::new(64, 32)),
+        };
+        let cube = VectorCube::from_equ(&field, &equ, None, 16, &Selection::all());
+        // the same world vector differs per face
+        assert_ne!(cube.faces[0].pixel(0, 0), cube.faces[1].pixel(0, 0));
+        let back = cube.to_equ();
+        for v in back.data.chunks(3) {
+            assert!((Vector3::new(v[0], v[1], v[2]) - constant).magnitude() < 1e-5);
+        }
+    }
+}
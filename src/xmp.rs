@@ -0,0 +1,201 @@
+use cgmath::{Matrix, Matrix3};
+
+use crate::projection::{rotation_from_angles, CroppedArea};
+
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Google Photo Sphere (GPano) properties we understand
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GPano {
+    pub cropped_area: Option<CroppedArea>,
+    /// Compass heading of the image center
+    pub pose_heading: Option<f32>,
+    pub pose_pitch: Option<f32>,
+    pub pose_roll: Option<f32>,
+}
+
+impl GPano {
+    /// Rotation taking world (north-facing, level) rays into the image frame,
+    /// `None` when the image has no pose
+    pub fn orientation(&self) -> Option<Matrix3<f32>> {
+        if self.pose_heading.is_none() && self.pose_pitch.is_none() && self.pose_roll.is_none() {
+            return None;
+        }
+        Some(
+            rotation_from_angles(
+                self.pose_heading.unwrap_or(0.0),
+                self.pose_pitch.unwrap_or(0.0),
+                self.pose_roll.unwrap_or(0.0),
+            )
+            .transpose(),
+        )
+    }
+}
+
+/// Finds XMP packet from any file (JPEG APP1, PNG iTXt, TIFF tag all keep it as plain text)
+pub(crate) fn find_xmp(bytes: &[u8]) -> Option<String> {
+    let start = find(bytes, b"<x:xmpmeta")?;
+    let end_tag = b"</x:xmpmeta>";
+    let end = start + find(&bytes[start..], end_tag)? + end_tag.len();
+    String::from_utf8(bytes[start..end].to_vec()).ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Value of XMP property written either as attribute (`GPano:X="1"`) or element (`<GPano:X>1</GPano:X>`)
+fn property(xmp: &str, name: &str) -> Option<String> {
+    let attribute = format!("{}=\"", name);
+    if let Some(start) = xmp.find(&attribute) {
+        let value = &xmp[start + attribute.len()..];
+        return Some(value[..value.find('"')?].trim().to_string());
+    }
+    let element = format!("<{}>", name);
+    let start = xmp.find(&element)? + element.len();
+    let value = &xmp[start..];
+    Some(value[..value.find('<')?].trim().to_string())
+}
+
+fn property_f32(xmp: &str, name: &str) -> Option<f32> {
+    property(xmp, name)?.parse::<f32>().ok()
+}
+
+fn property_u32(xmp: &str, name: &str) -> Option<u32> {
+    property(xmp, name)?
+        .parse::<f32>()
+        .ok()
+        .map(|v| v.round() as u32)
+}
+
+pub fn parse_gpano(xmp: &str) -> GPano {
+    let cropped_area = (|| {
+        Some(CroppedArea {
+            full_width: property_u32(xmp, "GPano:FullPanoWidthPixels")?,
+            full_height: property_u32(xmp, "GPano:FullPanoHeightPixels")?,
+            left: property_u32(xmp, "GPano:CroppedAreaLeftPixels").unwrap_or(0),
+            top: property_u32(xmp, "GPano:CroppedAreaTopPixels").unwrap_or(0),
+            width: property_u32(xmp, "GPano:CroppedAreaImageWidthPixels")?,
+            height: property_u32(xmp, "GPano:CroppedAreaImageHeightPixels")?,
+        })
+    })();
+    GPano {
+        cropped_area,
+        pose_heading: property_f32(xmp, "GPano:PoseHeadingDegrees"),
+        pose_pitch: property_f32(xmp, "GPano:PosePitchDegrees"),
+        pose_roll: property_f32(xmp, "GPano:PoseRollDegrees"),
+    }
+}
+
+pub fn read_gpano(bytes: &[u8]) -> Option<GPano> {
+    find_xmp(bytes).map(|xmp| parse_gpano(&xmp))
+}
+
+/// XMP packet describing equirectangular panorama
+pub fn gpano_xmp(gpano: &GPano) -> String {
+    let mut properties = vec![
+        ("ProjectionType", "equirectangular".to_string()),
+        ("UsePanoramaViewer", "True".to_string()),
+    ];
+    if let Some(area) = &gpano.cropped_area {
+        properties.extend([
+            ("CroppedAreaImageWidthPixels", area.width.to_string()),
+            ("CroppedAreaImageHeightPixels", area.height.to_string()),
+            ("FullPanoWidthPixels", area.full_width.to_string()),
+            ("FullPanoHeightPixels", area.full_height.to_string()),
+            ("CroppedAreaLeftPixels", area.left.to_string()),
+            ("CroppedAreaTopPixels", area.top.to_string()),
+        ]);
+    }
+    for (name, value) in [
+        ("PoseHeadingDegrees", gpano.pose_heading),
+        ("PosePitchDegrees", gpano.pose_pitch),
+        ("PoseRollDegrees", gpano.pose_roll),
+    ] {
+        if let Some(value) = value {
+            properties.push((name, format!("{:.1}", value)));
+        }
+    }
+    let attributes = properties
+        .iter()
+        .map(|(name, value)| format!("\n    GPano:{}=\"{}\"", name, value))
+        .collect::<String>();
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+         <rdf:Description rdf:about=\"\"\n    \
+         xmlns:GPano=\"http://ns.google.com/photos/1.0/panorama/\"{}/>\n \
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        attributes
+    )
+}
+
+/// XMP APP1 segment payload for JPEG
+pub fn app1(xmp: &str) -> Vec<u8> {
+    let mut payload = XMP_HEADER.to_vec();
+    payload.extend_from_slice(xmp.as_bytes());
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::RgbaImage;
+
+    use super::*;
+    use crate::metadata::insert_jpeg_app1;
+
+    #[test]
+    fn gpano_round_trips_through_jpeg() {
+        let area = CroppedArea {
+            full_width: 4000,
+            full_height: 2000,
+            left: 0,
+            top: 333,
+            width: 4000,
+            height: 1334,
+        };
+        let gpano = GPano {
+            cropped_area: Some(area),
+            pose_heading: Some(120.5),
+            pose_pitch: None,
+            pose_roll: Some(-2.0),
+        };
+        let mut jpeg = vec![];
+        JpegEncoder::new(&mut jpeg)
+            .encode_image(&RgbaImage::new(8, 8))
+            .unwrap();
+        let with_xmp = insert_jpeg_app1(&jpeg, &[app1(&gpano_xmp(&gpano))]).unwrap();
+        assert!(image::load_from_memory(&with_xmp).is_ok());
+        assert_eq!(read_gpano(&with_xmp), Some(gpano));
+    }
+
+    #[test]
+    fn element_style_properties_are_parsed() {
+        let xmp = "<x:xmpmeta><GPano:FullPanoWidthPixels>100</GPano:FullPanoWidthPixels>\
+            <GPano:FullPanoHeightPixels>50</GPano:FullPanoHeightPixels>\
+            <GPano:CroppedAreaImageWidthPixels>100</GPano:CroppedAreaImageWidthPixels>\
+            <GPano:CroppedAreaImageHeightPixels>20</GPano:CroppedAreaImageHeightPixels>\
+            <GPano:CroppedAreaTopPixels>15</GPano:CroppedAreaTopPixels></x:xmpmeta>";
+        let area = parse_gpano(xmp).cropped_area.unwrap();
+        assert_eq!((area.top, area.height, area.full_height), (15, 20, 50));
+    }
+
+    #[test]
+    fn pose_heading_turns_world_front_to_image_left() {
+        // camera center points east, so north (front) is on the left in the image
+        let gpano = GPano {
+            pose_heading: Some(90.0),
+            ..Default::default()
+        };
+        let ray = gpano.orientation().unwrap() * crate::FRONT;
+        assert!((ray - crate::LEFT).magnitude() < 1e-6);
+        assert_eq!(GPano::default().orientation(), None);
+    }
+}